@@ -0,0 +1,196 @@
+// CLH lock (Craig, Landin and Hagersten)
+//
+// Another queue lock, like mcs.rs, but the other way around: every thread
+// spins on the node of the thread *before* it, rather than on its own.
+// Locking is a single swap of the tail, and unlocking a single store to our
+// own node, with no need to find out who's next.
+//
+// The catch is that the thread behind us is still reading our node after
+// we've unlocked and left, so the node can't live on our stack. Instead, the
+// nodes move around: once a thread sees that the node before it is unlocked,
+// nobody else will ever look at that node again, so it takes it for the next
+// time it needs one, and leaves its own node behind for the thread after it.
+// Every thread keeps one spare node in a thread local, so locking and
+// unlocking doesn't allocate, apart from the first time.
+//
+// If we're still the tail when unlocking, we can set the tail back to null
+// and keep our node, since nobody else has seen it.
+
+use crate::Backoff;
+use std::cell::{Cell, UnsafeCell};
+use std::ptr;
+use std::sync::atomic::{
+    AtomicBool, AtomicPtr,
+    Ordering::{AcqRel, Acquire, Relaxed, Release},
+};
+
+/// On its own cache line(s), so that spinning on one node doesn't
+/// slow down threads spinning on a neighbouring one.
+#[repr(align(128))]
+struct Node {
+    /// Set to false by the owner when it unlocks.
+    locked: AtomicBool,
+}
+
+thread_local! {
+    static SPARE: Cell<Option<Box<Node>>> = const { Cell::new(None) };
+}
+
+fn take_node() -> *mut Node {
+    let node = SPARE
+        .try_with(Cell::take)
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| {
+            Box::new(Node {
+                locked: AtomicBool::new(true),
+            })
+        });
+    node.locked.store(true, Relaxed);
+    Box::into_raw(node)
+}
+
+/// # Safety
+///
+/// Nobody else may still be using the node.
+unsafe fn give_back_node(node: *mut Node) {
+    let node = Box::from_raw(node);
+    // If there's a spare already (or the thread local is gone), one of them gets freed.
+    let _ = SPARE.try_with(|spare| spare.set(Some(node)));
+}
+
+pub struct ClhLock<T> {
+    /// The node of the last thread in line, or null if unlocked.
+    tail: AtomicPtr<Node>,
+    backoff: Backoff,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for ClhLock<T> where T: Send {}
+
+impl<T> ClhLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self::with_backoff(value, Backoff::DEFAULT)
+    }
+
+    pub const fn with_backoff(value: T, backoff: Backoff) -> Self {
+        Self {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            backoff,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Runs `f` while holding the lock, and unlocks right after,
+    /// even if `f` panics.
+    pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let node = take_node();
+        // AcqRel: Acquire to see the data from when the last holder unlocked
+        // (if there's nobody before us), Release for our node's contents.
+        let prev = self.tail.swap(node, AcqRel);
+        if !prev.is_null() {
+            let mut step = 0;
+            // Safety: `prev` isn't freed until somebody sees it unlocked, which is us.
+            while unsafe { (*prev).locked.load(Acquire) } {
+                self.backoff.snooze(&mut step);
+            }
+            // Safety: Nobody else is going to look at it anymore. It's ours now.
+            unsafe { give_back_node(prev) };
+        }
+        let _unlock = Unlock { lock: self, node };
+        // Safety: We're holding the lock.
+        f(unsafe { &mut *self.value.get() })
+    }
+
+    /// Might be outdated by the time you look at it.
+    pub fn is_locked(&self) -> bool {
+        !self.tail.load(Relaxed).is_null()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: Default> Default for ClhLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Unlocks when dropped, including while unwinding.
+struct Unlock<'a, T> {
+    lock: &'a ClhLock<T>,
+    node: *mut Node,
+}
+
+impl<T> Drop for Unlock<'_, T> {
+    fn drop(&mut self) {
+        // Release: whoever locks it next sees our changes.
+        if self
+            .lock
+            .tail
+            .compare_exchange(self.node, ptr::null_mut(), Release, Relaxed)
+            .is_ok()
+        {
+            // Nobody saw our node, so we can keep it.
+            unsafe { give_back_node(self.node) };
+        } else {
+            // The next thread will take it from here.
+            unsafe { (*self.node).locked.store(false, Release) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use stress::{Config, Stamp};
+
+    #[test]
+    fn test_with_lock() {
+        let lock = ClhLock::new(Vec::new());
+        assert!(!lock.is_locked());
+        lock.with_lock(|v| v.push(1));
+        let len = lock.with_lock(|v| {
+            v.push(2);
+            v.len()
+        });
+        assert_eq!(len, 2);
+        assert!(!lock.is_locked());
+        let result = catch_unwind(AssertUnwindSafe(|| lock.with_lock(|_| panic!())));
+        assert!(result.is_err());
+        assert!(!lock.is_locked());
+        assert_eq!(lock.into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn test_nested() {
+        let a = ClhLock::new(1);
+        let b = ClhLock::new(2);
+        let sum = a.with_lock(|a| b.with_lock(|b| *a + *b));
+        assert_eq!(sum, 3);
+    }
+
+    #[test]
+    fn test_stress() {
+        let lock = ClhLock::new(Stamp::new(0));
+        let locks: usize = stress::run(&Config::from_env(), |w| {
+            for _ in 0..w.iterations {
+                lock.with_lock(|s| {
+                    assert!(s.is_intact());
+                    *s = s.next();
+                });
+            }
+            w.iterations
+        })
+        .into_iter()
+        .sum();
+        assert_eq!(lock.into_inner().value(), locks as u64);
+    }
+}
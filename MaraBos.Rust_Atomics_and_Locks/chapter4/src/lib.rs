@@ -0,0 +1,318 @@
+// Library versions of the spin locks from this chapter, for the other chapters to build on.
+//
+// RawSpinLock: from minimal_spinlock.rs, plus `try_lock`.
+// SpinLock: from lockguard.rs, plus `try_lock` and `with_lock`, on top of RawSpinLock.
+// McsLock, ClhLock: queue locks, where every waiting thread spins on its own
+//                   cache line, rather than all on the same one.
+//
+// Instead of hammering the lock with `swap` while waiting, `lock` only reads it
+// until it looks unlocked, and only then tries to take it (test-and-test-and-set).
+// A load keeps the cache line shared between all the waiting cores, while every
+// `swap` needs exclusive access to it, pulling it away from the others and from
+// the thread that's about to unlock. (See caching*.rs in chapter 7.)
+//
+// Between those reads, waiting threads back off: spinning twice as long every
+// time, up to a limit, after which they yield to the operating system.
+// That way, a thread that got preempted while holding the lock gets a chance
+// to run again, rather than having all the others burn their time slices.
+//
+// Without the (default) "std" feature, this is a #![no_std] library,
+// and waiting threads never yield, since there's nothing to yield to.
+// ClhLock needs std for its thread local spare nodes.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{
+    AtomicBool,
+    Ordering::{Acquire, Relaxed, Release},
+};
+
+#[cfg(feature = "std")]
+pub mod clh;
+pub mod mcs;
+
+// Backoff //
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    /// Spin 1, 2, 4, … times between attempts, up to 2^spin_limit times.
+    pub spin_limit: u32,
+    /// Once we've reached the spin limit, yield to the operating system
+    /// between attempts, rather than continuing to spin.
+    /// Ignored without the "std" feature.
+    pub yield_now: bool,
+}
+
+impl Backoff {
+    pub const DEFAULT: Self = Self {
+        spin_limit: 6,
+        yield_now: true,
+    };
+
+    /// Never yield. Only for when every thread has its own core,
+    /// and the lock is only ever held for a very short time.
+    pub const SPIN_ONLY: Self = Self {
+        spin_limit: 6,
+        yield_now: false,
+    };
+
+    /// Wait a bit longer than the last time.
+    ///
+    /// `step` starts at zero, and is kept by the caller for as long as it's waiting.
+    pub fn snooze(&self, step: &mut u32) {
+        if *step < self.spin_limit {
+            for _ in 0..1 << *step {
+                spin_loop();
+            }
+            *step += 1;
+            return;
+        }
+        // Tests always have std, and take forever on a single core without yielding.
+        #[cfg(any(feature = "std", test))]
+        if self.yield_now {
+            std::thread::yield_now();
+            return;
+        }
+        for _ in 0..1 << self.spin_limit {
+            spin_loop();
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+// RawSpinLock //
+
+pub struct RawSpinLock {
+    locked: AtomicBool,
+    backoff: Backoff,
+}
+
+impl RawSpinLock {
+    pub const fn new() -> Self {
+        Self::with_backoff(Backoff::DEFAULT)
+    }
+
+    pub const fn with_backoff(backoff: Backoff) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            backoff,
+        }
+    }
+
+    pub fn lock(&self) {
+        let mut step = 0;
+        while !self.try_lock() {
+            // Only read while it's locked, to keep the cache line shared.
+            while self.locked.load(Relaxed) {
+                self.backoff.snooze(&mut step);
+            }
+        }
+    }
+
+    /// Doesn't wait, and returns false if it's already locked.
+    pub fn try_lock(&self) -> bool {
+        // A plain load first, so a failed attempt doesn't need exclusive access.
+        !self.locked.load(Relaxed)
+            && self
+                .locked
+                .compare_exchange(false, true, Acquire, Relaxed)
+                .is_ok()
+    }
+
+    /// # Safety
+    ///
+    /// Only call this after `lock` or a successful `try_lock`, once.
+    pub unsafe fn unlock(&self) {
+        self.locked.store(false, Release);
+    }
+
+    /// Might be outdated by the time you look at it.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Relaxed)
+    }
+}
+
+impl Default for RawSpinLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// SpinLock //
+
+pub struct SpinLock<T> {
+    raw: RawSpinLock,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for SpinLock<T> where T: Send {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self::with_backoff(value, Backoff::DEFAULT)
+    }
+
+    pub const fn with_backoff(value: T, backoff: Backoff) -> Self {
+        Self {
+            raw: RawSpinLock::with_backoff(backoff),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> Guard<'_, T> {
+        self.raw.lock();
+        Guard { lock: self }
+    }
+
+    /// Doesn't wait, and returns `None` if it's already locked.
+    pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+        self.raw.try_lock().then(|| Guard { lock: self })
+    }
+
+    /// Runs `f` while holding the lock, and unlocks right after,
+    /// even if `f` panics.
+    ///
+    /// Handy to make sure the lock is held for as short as possible,
+    /// and not accidentally across anything else.
+    pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    /// Might be outdated by the time you look at it.
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+// Guard //
+
+pub struct Guard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+unsafe impl<T> Sync for Guard<'_, T> where T: Sync {}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: The very existence of this Guard
+        // guarantees we've exclusively locked the lock.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: The very existence of this Guard
+        // guarantees we've exclusively locked the lock.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        // Safety: The very existence of this Guard
+        // guarantees we've locked the lock.
+        unsafe { self.lock.raw.unlock() };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use stress::{Config, Stamp};
+
+    #[test]
+    fn test_lock_try_lock() {
+        let lock = SpinLock::new(Vec::new());
+        let mut guard = lock.lock();
+        guard.push(1);
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_none());
+        assert!(lock.is_locked(), "a failed try_lock unlocked it");
+        drop(guard);
+        assert!(!lock.is_locked());
+        lock.try_lock().unwrap().push(2);
+        assert_eq!(lock.with_lock(|v| v.len()), 2);
+        assert_eq!(lock.into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn test_with_lock_unlocks_on_panic() {
+        let lock = SpinLock::new(0);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            lock.with_lock(|v| {
+                *v += 1;
+                panic!();
+            })
+        }));
+        assert!(result.is_err());
+        assert!(!lock.is_locked());
+        assert_eq!(*lock.lock(), 1);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            spin_limit: 3,
+            yield_now: true,
+        };
+        let mut step = 0;
+        for _ in 0..10 {
+            backoff.snooze(&mut step);
+        }
+        assert_eq!(step, 3);
+    }
+
+    /// Every thread increments the stamp, locking either way.
+    #[test]
+    fn test_stress() {
+        let lock = SpinLock::new(Stamp::new(0));
+        let locks: u64 = stress::run(&Config::from_env(), |w| {
+            let mut locks = 0;
+            for _ in 0..w.iterations {
+                match w.rng.below(3) {
+                    0 => lock.with_lock(|s| *s = s.next()),
+                    1 => match lock.try_lock() {
+                        Some(mut guard) => *guard = guard.next(),
+                        None => continue,
+                    },
+                    _ => {
+                        let mut guard = lock.lock();
+                        assert!(guard.is_intact());
+                        *guard = guard.next();
+                    }
+                }
+                locks += 1;
+            }
+            locks
+        })
+        .into_iter()
+        .sum();
+        assert_eq!(lock.lock().value(), locks);
+    }
+}
@@ -3,6 +3,8 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::ops::{Deref, DerefMut};
+#[cfg(test)]
+use stress::{Config, Stamp};
 
 #[derive(Debug)]
 pub struct SpinLock<T> {
@@ -20,7 +22,7 @@ impl<T> SpinLock<T> {
         }
     }
 
-    pub fn lock(&self) -> Guard<T> {
+    pub fn lock(&self) -> Guard<'_, T> {
         while self.locked.swap(true, Ordering::Acquire) {
             std::hint::spin_loop();
         }
@@ -56,6 +58,21 @@ impl<T> Drop for Guard<'_, T> {
     }
 }
 
+#[test]
+fn test_stress() {
+    let lock = SpinLock::new(Stamp::new(0));
+    let locks: usize = stress::run(&Config::from_env(), |w| {
+        for _ in 0..w.iterations {
+            let mut guard = lock.lock();
+            *guard = guard.next();
+        }
+        w.iterations
+    })
+    .into_iter()
+    .sum();
+    assert_eq!(lock.lock().value(), locks as u64);
+}
+
 fn main() {
     let x = SpinLock::new(Vec::new());
 
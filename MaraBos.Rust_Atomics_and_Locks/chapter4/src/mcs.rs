@@ -0,0 +1,193 @@
+// MCS lock (Mellor-Crummey and Scott)
+//
+// With the SpinLock in lib.rs, all waiting threads spin on the same flag.
+// Every unlock invalidates that cache line on every waiting core, and all of
+// them then race to take it, so it gets slower the more threads are waiting.
+//
+// Here, waiting threads form a queue instead. Every thread brings its own
+// node, appends it at the tail (with a single swap), links it behind the
+// previous tail, and then spins on the flag in its *own* node. Unlocking only
+// touches the node of the next thread in line, to hand the lock to it. So no
+// matter how many threads are waiting, every unlock only involves two cores,
+// and threads get the lock in the order they arrived.
+//
+// The node only needs to live for as long as the thread is waiting for or
+// holding the lock, so it lives on that thread's stack. Since other threads
+// write to it through a pointer, it must not move or be dropped while it's
+// in the queue. That's why there's only a closure-based `with_lock`, rather
+// than a guard: a guard could be leaked with mem::forget, leaving a pointer
+// to a node that's long gone.
+
+use crate::Backoff;
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{
+    AtomicBool, AtomicPtr,
+    Ordering::{AcqRel, Acquire, Relaxed, Release},
+};
+
+/// On its own cache line(s), so that spinning on one node doesn't
+/// slow down threads spinning on a neighbouring one.
+#[repr(align(128))]
+struct Node {
+    /// Set back to false by the previous thread, to hand over the lock.
+    locked: AtomicBool,
+    /// The next thread in line. Set by that thread, once it's enqueued.
+    next: AtomicPtr<Node>,
+}
+
+pub struct McsLock<T> {
+    /// The last node in the queue, or null if unlocked.
+    tail: AtomicPtr<Node>,
+    backoff: Backoff,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for McsLock<T> where T: Send {}
+
+impl<T> McsLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self::with_backoff(value, Backoff::DEFAULT)
+    }
+
+    pub const fn with_backoff(value: T, backoff: Backoff) -> Self {
+        Self {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            backoff,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Runs `f` while holding the lock, and unlocks right after,
+    /// even if `f` panics.
+    pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let node = Node {
+            locked: AtomicBool::new(true),
+            next: AtomicPtr::new(ptr::null_mut()),
+        };
+        let node_ptr = &node as *const Node as *mut Node;
+        // AcqRel: Acquire to see the data from when the last holder unlocked
+        // (if there's nobody before us), Release for our node's contents.
+        let prev = self.tail.swap(node_ptr, AcqRel);
+        if !prev.is_null() {
+            // Safety: `prev` can't leave before it has handed the lock to us,
+            // which it can't do before we've linked ourselves behind it.
+            unsafe { (*prev).next.store(node_ptr, Release) };
+            let mut step = 0;
+            while node.locked.load(Acquire) {
+                self.backoff.snooze(&mut step);
+            }
+        }
+        let _unlock = Unlock {
+            lock: self,
+            node: &node,
+        };
+        // Safety: We're holding the lock.
+        f(unsafe { &mut *self.value.get() })
+    }
+
+    /// Might be outdated by the time you look at it.
+    pub fn is_locked(&self) -> bool {
+        !self.tail.load(Relaxed).is_null()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: Default> Default for McsLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Unlocks when dropped, including while unwinding.
+struct Unlock<'a, T> {
+    lock: &'a McsLock<T>,
+    node: &'a Node,
+}
+
+impl<T> Drop for Unlock<'_, T> {
+    fn drop(&mut self) {
+        let node_ptr = self.node as *const Node as *mut Node;
+        let mut next = self.node.next.load(Acquire);
+        if next.is_null() {
+            // Nobody behind us yet. If we're still the tail, nobody is coming either.
+            // Release: whoever locks it next sees our changes.
+            if self
+                .lock
+                .tail
+                .compare_exchange(node_ptr, ptr::null_mut(), Release, Relaxed)
+                .is_ok()
+            {
+                return;
+            }
+            // Somebody swapped in behind us, but hasn't linked itself yet.
+            let mut step = 0;
+            loop {
+                next = self.node.next.load(Acquire);
+                if !next.is_null() {
+                    break;
+                }
+                self.lock.backoff.snooze(&mut step);
+            }
+        }
+        // Safety: `next` is waiting for us, so it's still there.
+        // After this store, it might be gone, so we don't touch it anymore.
+        unsafe { (*next).locked.store(false, Release) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use stress::{Config, Stamp};
+
+    #[test]
+    fn test_with_lock() {
+        let lock = McsLock::new(Vec::new());
+        assert!(!lock.is_locked());
+        lock.with_lock(|v| v.push(1));
+        let len = lock.with_lock(|v| {
+            v.push(2);
+            v.len()
+        });
+        assert_eq!(len, 2);
+        assert!(!lock.is_locked());
+        let result = catch_unwind(AssertUnwindSafe(|| lock.with_lock(|_| panic!())));
+        assert!(result.is_err());
+        assert!(!lock.is_locked());
+        assert_eq!(lock.into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn test_nested() {
+        let a = McsLock::new(1);
+        let b = McsLock::new(2);
+        let sum = a.with_lock(|a| b.with_lock(|b| *a + *b));
+        assert_eq!(sum, 3);
+    }
+
+    #[test]
+    fn test_stress() {
+        let lock = McsLock::new(Stamp::new(0));
+        let locks: usize = stress::run(&Config::from_env(), |w| {
+            for _ in 0..w.iterations {
+                lock.with_lock(|s| {
+                    assert!(s.is_intact());
+                    *s = s.next();
+                });
+            }
+            w.iterations
+        })
+        .into_iter()
+        .sum();
+        assert_eq!(lock.into_inner().value(), locks as u64);
+    }
+}
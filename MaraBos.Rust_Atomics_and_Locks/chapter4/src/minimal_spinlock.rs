@@ -0,0 +1,89 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering::{Acquire, Relaxed, Release},
+};
+use stress::{Config, Stamp};
+
+pub struct SpinLock {
+    locked: AtomicBool,
+}
+
+impl SpinLock {
+    pub const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    pub fn lock(&self) {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Acquire, Relaxed)
+            .is_err()
+        {
+            std::hint::spin_loop();
+        }
+        // while self.locked.swap(true, Acquire) {
+        //     std::hint::spin_loop();
+        // }
+    }
+
+    pub fn unlock(&self) {
+        self.locked.store(false, Release);
+    }
+}
+
+impl Default for SpinLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// stress //
+
+/// The data protected by the (data-less) `SpinLock`.
+struct Protected(UnsafeCell<Stamp>);
+
+// Safety: Only accessed while holding the lock.
+unsafe impl Sync for Protected {}
+
+impl Protected {
+    fn get(&self) -> *mut Stamp {
+        self.0.get()
+    }
+}
+
+fn stress_spinlock(config: &Config) -> u64 {
+    let lock = SpinLock::new();
+    let data = Protected(UnsafeCell::new(Stamp::new(0)));
+
+    let locks: usize = stress::run(config, |w| {
+        for _ in 0..w.iterations {
+            lock.lock();
+            // Safety: We're holding the lock.
+            unsafe {
+                let stamp = &mut *data.get();
+                *stamp = stamp.next();
+            }
+            lock.unlock();
+        }
+        w.iterations
+    })
+    .into_iter()
+    .sum();
+
+    let sum = data.0.into_inner().value();
+    assert_eq!(sum, locks as u64);
+    sum
+}
+
+#[test]
+fn test_stress() {
+    stress_spinlock(&Config::from_env());
+}
+
+fn main() {
+    let sum = stress_spinlock(&Config::from_env());
+    println!("The resulting SUM is: {sum}");
+}
@@ -25,6 +25,9 @@ impl<T> UnsafeSpinLock<T> {
         unsafe { &mut *self.value.get() }
     }
 
+    /// # Safety
+    ///
+    /// Only call this after `lock`, once the `&mut T` is no longer used.
     pub unsafe fn unlock(&self) {
         self.locked.store(false, Ordering::Release);
     }
@@ -0,0 +1,286 @@
+// Async one-shot channel
+//
+// Based on single_atomic_channel_state.rs: a message slot and a single
+// atomic state. But the state is a set of flags, to keep track of both ends,
+// and instead of spinning or parking, the receiver is a `Future` that
+// registers its waker in an `AtomicWaker` (atomic_waker.rs).
+//
+// The sender can wait for the receiver to go away with `closed().await`,
+// e.g. to stop working on a result nobody is waiting for anymore.
+
+use crate::atomic_waker::AtomicWaker;
+use crate::mpmc::SendError;
+use crate::oneshot::Canceled;
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::sync::atomic::{
+    AtomicU8,
+    Ordering::{Acquire, Relaxed, Release},
+};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// The message has been written.
+const SENT: u8 = 1;
+/// The sender is gone, with or without sending.
+const SENDER_GONE: u8 = 2;
+/// The receiver is gone.
+const RECEIVER_GONE: u8 = 4;
+/// The receiver took the message out.
+const RECEIVED: u8 = 8;
+
+struct Channel<T> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    state: AtomicU8,
+    /// Woken when the sender is gone (after sending or not).
+    receiver_waker: AtomicWaker,
+    /// Woken when the receiver is gone.
+    sender_waker: AtomicWaker,
+}
+
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel {
+        message: UnsafeCell::new(MaybeUninit::uninit()),
+        state: AtomicU8::new(0),
+        receiver_waker: AtomicWaker::new(),
+        sender_waker: AtomicWaker::new(),
+    });
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() & (SENT | RECEIVED) == SENT {
+            unsafe { self.message.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+// Sender //
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Fails if the receiver is already gone, giving the message back.
+    ///
+    /// Wakes the receiver when the sender is dropped, right after this.
+    pub fn send(self, message: T) -> Result<(), SendError<T>> {
+        let state = &self.channel.state;
+        if state.load(Relaxed) & RECEIVER_GONE != 0 {
+            return Err(SendError(message));
+        }
+        unsafe { (*self.channel.message.get()).write(message) };
+        // Only mark it as sent if the receiver didn't go away in the meantime,
+        // since we need to take the message back out in that case.
+        let mut s = state.load(Relaxed);
+        loop {
+            if s & RECEIVER_GONE != 0 {
+                let message = unsafe { (*self.channel.message.get()).assume_init_read() };
+                return Err(SendError(message));
+            }
+            match state.compare_exchange_weak(s, s | SENT, Release, Relaxed) {
+                Ok(_) => return Ok(()),
+                Err(e) => s = e,
+            }
+        }
+    }
+
+    /// True if the receiver is gone, so there's no point in sending anymore.
+    pub fn is_closed(&self) -> bool {
+        self.channel.state.load(Relaxed) & RECEIVER_GONE != 0
+    }
+
+    /// Completes once the receiver is gone.
+    pub fn closed(&mut self) -> Closed<'_, T> {
+        Closed { sender: self }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.state.fetch_or(SENDER_GONE, Release);
+        self.channel.receiver_waker.wake();
+    }
+}
+
+/// The future returned by `Sender::closed`.
+pub struct Closed<'a, T> {
+    sender: &'a Sender<T>,
+}
+
+impl<T> Future for Closed<'_, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.sender.is_closed() {
+            return Poll::Ready(());
+        }
+        self.sender.channel.sender_waker.register(cx.waker());
+        // Check again, in case it happened before the waker was registered.
+        if self.sender.is_closed() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+// Receiver //
+
+/// A future resolving to the message,
+/// or to `Err(Canceled)` if the sender is dropped without sending.
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Doesn't wait. Returns None if nothing was sent (yet).
+    ///
+    /// Panics if the message was already received.
+    pub fn try_recv(&mut self) -> Option<Result<T, Canceled>> {
+        let state = self.channel.state.load(Acquire);
+        assert!(state & RECEIVED == 0, "message already received");
+        if state & SENT != 0 {
+            self.channel.state.fetch_or(RECEIVED, Relaxed);
+            Some(Ok(unsafe {
+                (*self.channel.message.get()).assume_init_read()
+            }))
+        } else if state & SENDER_GONE != 0 {
+            Some(Err(Canceled))
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, Canceled>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(result) = self.try_recv() {
+            return Poll::Ready(result);
+        }
+        self.channel.receiver_waker.register(cx.waker());
+        // Check again, in case it was sent before the waker was registered.
+        match self.try_recv() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.state.fetch_or(RECEIVER_GONE, Relaxed);
+        self.channel.sender_waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use stress::{block_on, Config, DropCounter, Stamp};
+
+    #[test]
+    fn test_send_and_await() {
+        let (sender, receiver) = channel();
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                sender.send("hello").unwrap();
+            });
+            assert_eq!(block_on(receiver), Ok("hello"));
+        });
+    }
+
+    #[test]
+    fn test_canceled() {
+        let (sender, receiver) = channel::<i32>();
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                drop(sender);
+            });
+            assert_eq!(block_on(receiver), Err(Canceled));
+        });
+    }
+
+    #[test]
+    fn test_closed() {
+        let (mut sender, receiver) = channel::<i32>();
+        assert!(!sender.is_closed());
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                drop(receiver);
+            });
+            block_on(sender.closed());
+        });
+        assert!(sender.is_closed());
+        assert_eq!(sender.send(1), Err(SendError(1)));
+    }
+
+    #[test]
+    fn test_try_recv() {
+        let (sender, mut receiver) = channel();
+        assert_eq!(receiver.try_recv(), None);
+        sender.send(5).unwrap();
+        assert_eq!(receiver.try_recv(), Some(Ok(5)));
+    }
+
+    #[test]
+    fn test_drops() {
+        let counter = DropCounter::new();
+        let (sender, receiver) = channel();
+        sender.send(counter.track()).unwrap();
+        drop(receiver);
+        assert_eq!(counter.alive(), 0);
+
+        let (sender, receiver) = channel();
+        drop(receiver);
+        assert!(sender.send(counter.track()).is_err());
+        assert_eq!(counter.alive(), 0);
+    }
+
+    /// A worker thread per request sends back a result, cancels, or finds the
+    /// receiver gone, racing with the receiving side awaiting or dropping.
+    #[test]
+    fn test_stress() {
+        let counter = &DropCounter::new();
+
+        stress::run(&Config::from_env(), |w| {
+            for _ in 0..w.iterations {
+                let value = w.rng.next_u64();
+                let action = w.rng.below(3);
+                let (sender, receiver) = channel();
+                thread::scope(|s| {
+                    s.spawn(move || {
+                        if action != 0 {
+                            let _ = sender.send((Stamp::new(value), counter.track()));
+                        }
+                    });
+                    match action {
+                        0 => assert_eq!(block_on(receiver).err(), Some(Canceled)),
+                        1 => assert_eq!(block_on(receiver).unwrap().0.value(), value),
+                        _ => drop(receiver),
+                    }
+                });
+            }
+        });
+
+        assert_eq!(counter.alive(), 0);
+    }
+}
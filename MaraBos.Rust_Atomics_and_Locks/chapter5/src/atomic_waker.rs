@@ -0,0 +1,174 @@
+// AtomicWaker
+//
+// A slot for one `Waker`, for a future to register itself in before it
+// returns `Poll::Pending`, and for another thread to take it out of to wake it.
+// Just like the `thread::park`/`unpark` pair, but for async tasks.
+//
+// Only one thread may `register` at a time (the one polling the future),
+// but any number of threads can call `wake` concurrently.
+// The state makes sure the waker is never touched by two threads at once,
+// and that a `wake` racing with a `register` is never lost:
+// if `wake` finds a `register` in progress, it leaves a WAKING flag behind,
+// and `register` wakes the new waker itself when it sees that.
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{
+    AtomicU8,
+    Ordering::{AcqRel, Acquire, Release},
+};
+use std::task::Waker;
+
+const WAITING: u8 = 0;
+const REGISTERING: u8 = 1;
+const WAKING: u8 = 2;
+
+pub struct AtomicWaker {
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+unsafe impl Sync for AtomicWaker {}
+
+impl AtomicWaker {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(WAITING),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Stores `waker`, replacing the previous one.
+    ///
+    /// The caller has to check its condition again afterwards,
+    /// in case it changed right before the new waker was stored.
+    pub fn register(&self, waker: &Waker) {
+        match self
+            .state
+            .compare_exchange(WAITING, REGISTERING, Acquire, Acquire)
+        {
+            Ok(_) => {
+                // We have exclusive access to the slot now.
+                let slot = unsafe { &mut *self.waker.get() };
+                if !slot.as_ref().is_some_and(|w| w.will_wake(waker)) {
+                    *slot = Some(waker.clone());
+                }
+                if self
+                    .state
+                    .compare_exchange(REGISTERING, WAITING, AcqRel, Acquire)
+                    .is_err()
+                {
+                    // A `wake` came in while we were busy. It left the waker
+                    // to us, since it couldn't take it out itself.
+                    let waker = slot.take().unwrap();
+                    self.state.swap(WAITING, AcqRel);
+                    waker.wake();
+                }
+            }
+            // Being woken right now, so the waker will be gone.
+            // Make sure we get polled again.
+            Err(WAKING) => waker.wake_by_ref(),
+            // Another thread is registering at the same time,
+            // which isn't allowed. Nothing sensible to do.
+            Err(_) => {}
+        }
+    }
+
+    /// Wakes the registered waker, if any, and removes it.
+    pub fn wake(&self) {
+        if let Some(waker) = self.take() {
+            waker.wake();
+        }
+    }
+
+    /// Removes the registered waker, if any.
+    pub fn take(&self) -> Option<Waker> {
+        match self.state.fetch_or(WAKING, AcqRel) {
+            WAITING => {
+                // We have exclusive access to the slot now.
+                let waker = unsafe { (*self.waker.get()).take() };
+                self.state.fetch_and(!WAKING, Release);
+                waker
+            }
+            // Either `register` is running and will handle it when it sees the
+            // WAKING flag, or another `wake` is running and will take the waker.
+            _ => None,
+        }
+    }
+}
+
+impl Default for AtomicWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+    use std::sync::Arc;
+    use std::task::Wake;
+    use std::thread;
+    use stress::Config;
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_register_and_wake() {
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let atomic_waker = AtomicWaker::new();
+        atomic_waker.wake();
+        atomic_waker.register(&waker);
+        atomic_waker.register(&waker);
+        atomic_waker.wake();
+        assert_eq!(counter.0.load(Relaxed), 1);
+        // It's gone now.
+        atomic_waker.wake();
+        assert_eq!(counter.0.load(Relaxed), 1);
+    }
+
+    /// One thread keeps registering while the others keep waking.
+    /// A wake that happens after a registration must never be lost,
+    /// or this test hangs.
+    #[test]
+    fn test_stress() {
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let atomic_waker = AtomicWaker::new();
+        let registered = AtomicUsize::new(0);
+        let config = Config::from_env();
+        assert!(config.threads >= 2, "need at least one waking thread");
+        stress::run(&config, |w| {
+            if w.index == 0 {
+                for i in 1..=w.iterations {
+                    let wakes = counter.0.load(Acquire);
+                    atomic_waker.register(&waker);
+                    registered.store(i, Release);
+                    // Wait until one of the others woke us.
+                    while counter.0.load(Acquire) == wakes {
+                        thread::yield_now();
+                    }
+                }
+            } else {
+                let mut seen = 0;
+                while seen < w.iterations {
+                    let r = registered.load(Acquire);
+                    if r > seen {
+                        atomic_waker.wake();
+                        seen = r;
+                    } else {
+                        thread::yield_now();
+                    }
+                }
+            }
+        });
+        assert!(counter.0.load(Relaxed) >= config.iterations);
+    }
+}
@@ -7,6 +7,8 @@ use std::sync::atomic::{
 };
 use std::thread::{self, Thread};
 use std::time::Duration;
+#[cfg(test)]
+use stress::{Config, DropCounter, Stamp};
 
 pub struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
@@ -39,6 +41,12 @@ impl<T> Channel<T> {
     }
 }
 
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct Sender<'a, T> {
     channel: &'a Channel<T>,
     receiving_thread: Thread,
@@ -65,7 +73,6 @@ impl<T> Receiver<'_, T> {
 
     pub fn receive(self) -> T {
         while !self.channel.ready.swap(false, Acquire) {
-            println!("parking receiver...");
             thread::park();
         }
         unsafe { (*self.channel.message.get()).assume_init_read() }
@@ -80,6 +87,26 @@ impl<T> Drop for Channel<T> {
     }
 }
 
+#[test]
+fn test_stress() {
+    let counter = &DropCounter::new();
+
+    stress::run(&Config::from_env(), |w| {
+        let mut channel = Channel::new();
+        for _ in 0..w.iterations {
+            let value = w.rng.next_u64();
+            thread::scope(|s| {
+                let (sender, receiver) = channel.split();
+                s.spawn(move || sender.send((Stamp::new(value), counter.track())));
+                let (stamp, _tracked) = receiver.receive();
+                assert_eq!(stamp.value(), value);
+            });
+        }
+    });
+
+    assert_eq!(counter.alive(), 0);
+}
+
 fn main() {
     let mut channel = Channel::new();
     thread::scope(|s| {
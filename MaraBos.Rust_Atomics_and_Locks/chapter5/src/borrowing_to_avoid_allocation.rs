@@ -24,12 +24,18 @@ impl<T> Channel<T> {
     }
 
     // pub fn split<'a>(&'a mut self) -> (Sender<'a, T>, Receiver<'a, T>) {
-    pub fn split(&mut self) -> (Sender<T>, Receiver<T>) {
+    pub fn split(&mut self) -> (Sender<'_, T>, Receiver<'_, T>) {
         *self = Self::new();
         (Sender { channel: self }, Receiver { channel: self })
     }
 }
 
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct Sender<'a, T> {
     channel: &'a Channel<T>,
 }
@@ -0,0 +1,479 @@
+// Broadcast channel
+//
+// One sender, any number of receivers, and every receiver sees every message.
+//
+// The messages go into a ring buffer, where every slot remembers the
+// sequence number of the message it holds. Each receiver keeps its own
+// cursor: the sequence number of the next message it wants. The sender
+// never waits for receivers. If a receiver falls more than `capacity`
+// messages behind, the messages it missed are simply overwritten, and the
+// receiver gets a `Lagged(n)` error telling it how many it missed, after
+// which it continues from the oldest message that's still there.
+//
+// Every slot has its own Mutex (from chapter9), which is only held for
+// as long as it takes to write or clone a single message.
+//
+// Receivers can block on a futex (`recv`), be awaited (`recv_async`),
+// or be used with `Select` and `select!` (select.rs).
+
+use crate::mpmc::SendError;
+use crate::select::{SelectRecv, Selectable, Signal, Signals};
+use atomic_wait::{wait, wake_all};
+use chapter9::Mutex;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{
+    AtomicBool, AtomicU32, AtomicU64, AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release, SeqCst},
+};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+struct Channel<T> {
+    slots: Box<[Mutex<Slot<T>>]>,
+    /// Sequence number of the next message to be sent.
+    tail: AtomicU64,
+    /// Set when the sender is dropped.
+    closed: AtomicBool,
+    receivers: AtomicUsize,
+    /// Incremented on every send (and on close), for blocking receivers to wait on.
+    counter: AtomicU32,
+    /// Number of receivers blocked on `counter`, to skip the syscall if there are none.
+    num_waiters: AtomicUsize,
+    /// Wakers of receivers awaiting a message.
+    wakers: Mutex<Vec<Waker>>,
+    /// Threads selecting on a receiver.
+    selectors: Signals,
+}
+
+struct Slot<T> {
+    /// The sequence number of `message`.
+    seq: u64,
+    message: Option<T>,
+}
+
+/// Panics if `capacity` is zero.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity must be at least 1");
+    let channel = Arc::new(Channel {
+        slots: (0..capacity)
+            .map(|_| {
+                Mutex::new(Slot {
+                    seq: 0,
+                    message: None,
+                })
+            })
+            .collect(),
+        tail: AtomicU64::new(0),
+        closed: AtomicBool::new(false),
+        receivers: AtomicUsize::new(1),
+        counter: AtomicU32::new(0),
+        num_waiters: AtomicUsize::new(0),
+        wakers: Mutex::new(Vec::new()),
+        selectors: Signals::new(),
+    });
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver {
+            channel,
+            next: AtomicU64::new(0),
+        },
+    )
+}
+
+impl<T> Channel<T> {
+    fn capacity(&self) -> u64 {
+        self.slots.len() as u64
+    }
+
+    /// Wakes up all blocked and awaiting receivers.
+    fn notify(&self) {
+        // SeqCst, together with the SeqCst in `recv`, to make sure that either
+        // we see the waiter, or the waiter sees the new counter value.
+        self.counter.fetch_add(1, SeqCst);
+        if self.num_waiters.load(SeqCst) > 0 {
+            wake_all(&self.counter);
+        }
+        let wakers = std::mem::take(&mut *self.wakers.lock());
+        for waker in wakers {
+            waker.wake();
+        }
+        self.selectors.notify();
+    }
+
+    fn subscribe(self: &Arc<Self>) -> Receiver<T> {
+        self.receivers.fetch_add(1, Relaxed);
+        Receiver {
+            channel: self.clone(),
+            next: AtomicU64::new(self.tail.load(Acquire)),
+        }
+    }
+}
+
+// Sender //
+
+/// Not `Clone`: there's only one.
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Never blocks. Overwrites the oldest message if the buffer is full.
+    ///
+    /// Fails if there are no receivers, giving the message back.
+    /// (A receiver can still be subscribed later, to receive new messages.)
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        if self.channel.receivers.load(Relaxed) == 0 {
+            return Err(SendError(message));
+        }
+        let channel = &*self.channel;
+        // Only we change `tail`.
+        let seq = channel.tail.load(Relaxed);
+        let old = {
+            let mut slot = channel.slots[(seq % channel.capacity()) as usize].lock();
+            slot.seq = seq;
+            slot.message.replace(message)
+        };
+        channel.tail.store(seq + 1, Release);
+        channel.notify();
+        // Drop the overwritten message outside of the lock.
+        drop(old);
+        Ok(())
+    }
+
+    /// A new receiver, which will only see messages sent after this.
+    pub fn subscribe(&self) -> Receiver<T> {
+        self.channel.subscribe()
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.channel.receivers.load(Relaxed)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.closed.store(true, Release);
+        self.channel.notify();
+    }
+}
+
+// Receiver //
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+    /// Sequence number of the next message to receive.
+    ///
+    /// Only atomic so that `select!` can receive through a shared reference,
+    /// without making the receiver !Sync. Only one thread at a time
+    /// receives: everything else takes `&mut self`.
+    next: AtomicU64,
+}
+
+impl<T: Clone> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.try_recv_shared()
+    }
+
+    /// `try_recv`, for `try_select`.
+    fn try_recv_shared(&self) -> Result<T, TryRecvError> {
+        let channel = &*self.channel;
+        let next = self.next.load(Relaxed);
+        // Load `closed` before `tail`: if it's closed, we see every message.
+        let closed = channel.closed.load(Acquire);
+        let tail = channel.tail.load(Acquire);
+        if next == tail {
+            return Err(match closed {
+                true => TryRecvError::Closed,
+                false => TryRecvError::Empty,
+            });
+        }
+        if tail - next > channel.capacity() {
+            let oldest = tail - channel.capacity();
+            return Err(TryRecvError::Lagged(self.lag_to(oldest)));
+        }
+        let slot = channel.slots[(next % channel.capacity()) as usize].lock();
+        if slot.seq != next {
+            // Overwritten since we looked at `tail`.
+            // The oldest message is the one right after this one.
+            let oldest = slot.seq + 1 - channel.capacity();
+            drop(slot);
+            return Err(TryRecvError::Lagged(self.lag_to(oldest)));
+        }
+        let message = slot.message.clone().unwrap();
+        drop(slot);
+        self.next.store(next + 1, Relaxed);
+        Ok(message)
+    }
+
+    /// Blocks until there's a message.
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            let counter = self.channel.counter.load(SeqCst);
+            match self.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Lagged(n)) => return Err(RecvError::Lagged(n)),
+                Err(TryRecvError::Closed) => return Err(RecvError::Closed),
+                Err(TryRecvError::Empty) => {}
+            }
+            self.channel.num_waiters.fetch_add(1, SeqCst);
+            wait(&self.channel.counter, counter);
+            self.channel.num_waiters.fetch_sub(1, Relaxed);
+        }
+    }
+
+    /// Like `recv`, but as a future, instead of blocking.
+    pub fn recv_async(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    /// A new receiver, which will only see messages sent after this.
+    pub fn resubscribe(&self) -> Self {
+        self.channel.subscribe()
+    }
+
+    /// Skips ahead to `oldest`, and returns the number of skipped messages.
+    fn lag_to(&self, oldest: u64) -> u64 {
+        oldest - self.next.swap(oldest, Relaxed)
+    }
+}
+
+/// Clones start at the same position.
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receivers.fetch_add(1, Relaxed);
+        Self {
+            channel: self.channel.clone(),
+            next: AtomicU64::new(self.next.load(Relaxed)),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receivers.fetch_sub(1, Relaxed);
+    }
+}
+
+/// The future returned by `Receiver::recv_async`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T: Clone> Future for Recv<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut registered = false;
+        loop {
+            match self.receiver.try_recv() {
+                Ok(message) => return Poll::Ready(Ok(message)),
+                Err(TryRecvError::Lagged(n)) => return Poll::Ready(Err(RecvError::Lagged(n))),
+                Err(TryRecvError::Closed) => return Poll::Ready(Err(RecvError::Closed)),
+                Err(TryRecvError::Empty) if registered => return Poll::Pending,
+                Err(TryRecvError::Empty) => {}
+            }
+            let mut wakers = self.receiver.channel.wakers.lock();
+            if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+            drop(wakers);
+            // Check again, in case a message came in before we registered.
+            registered = true;
+        }
+    }
+}
+
+impl<T> Selectable for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        let channel = &*self.channel;
+        channel.closed.load(Acquire) || channel.tail.load(Acquire) != self.next.load(Relaxed)
+    }
+
+    fn register(&self, signal: &Arc<Signal>) {
+        self.channel.selectors.register(signal);
+    }
+
+    fn unregister(&self, signal: &Arc<Signal>) {
+        self.channel.selectors.unregister(signal);
+    }
+}
+
+impl<T: Clone> SelectRecv for Receiver<T> {
+    type Message = T;
+    type Error = RecvError;
+
+    fn try_select(&self) -> Option<Result<T, RecvError>> {
+        match self.try_recv_shared() {
+            Ok(message) => Some(Ok(message)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Closed) => Some(Err(RecvError::Closed)),
+            Err(TryRecvError::Lagged(n)) => Some(Err(RecvError::Lagged(n))),
+        }
+    }
+}
+
+// Errors //
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// The sender is gone, and all its messages have been received.
+    Closed,
+    /// This receiver fell behind, and the given number of messages
+    /// were overwritten before it got to them.
+    /// Receiving again continues at the oldest message still there.
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvError::Closed => f.write_str("channel closed"),
+            RecvError::Lagged(n) => write!(f, "receiver lagged behind by {n} messages"),
+        }
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("channel empty"),
+            TryRecvError::Closed => f.write_str("channel closed"),
+            TryRecvError::Lagged(n) => write!(f, "receiver lagged behind by {n} messages"),
+        }
+    }
+}
+
+impl std::error::Error for RecvError {}
+impl std::error::Error for TryRecvError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use stress::{block_on, Config};
+
+    #[test]
+    fn test_every_receiver_gets_everything() {
+        let (tx, mut rx1) = channel(4);
+        let mut rx2 = tx.subscribe();
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        assert_eq!(rx1.try_recv(), Ok(1));
+        assert_eq!(rx1.try_recv(), Ok(2));
+        assert_eq!(rx1.try_recv(), Err(TryRecvError::Empty));
+        // Clones start where they are, new subscribers at the tail.
+        let mut rx3 = rx2.clone();
+        let mut rx4 = rx2.resubscribe();
+        tx.send(3).unwrap();
+        for rx in [&mut rx2, &mut rx3] {
+            assert_eq!(rx.recv(), Ok(1));
+            assert_eq!(rx.recv(), Ok(2));
+            assert_eq!(rx.recv(), Ok(3));
+        }
+        assert_eq!(rx4.recv(), Ok(3));
+        assert_eq!(rx4.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn test_lagged() {
+        let (tx, mut rx) = channel(3);
+        for i in 0..10 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Lagged(7)));
+        assert_eq!(rx.recv(), Ok(7));
+        assert_eq!(rx.recv(), Ok(8));
+        tx.send(10).unwrap();
+        tx.send(11).unwrap();
+        tx.send(12).unwrap();
+        tx.send(13).unwrap();
+        assert_eq!(rx.recv(), Err(RecvError::Lagged(2)));
+        assert_eq!(rx.recv(), Ok(11));
+    }
+
+    #[test]
+    fn test_closed() {
+        let (tx, mut rx) = channel(2);
+        tx.send("a").unwrap();
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                tx.send("b").unwrap();
+                thread::sleep(Duration::from_millis(10));
+                drop(tx);
+            });
+            assert_eq!(rx.recv(), Ok("a"));
+            assert_eq!(rx.recv(), Ok("b"));
+            assert_eq!(rx.recv(), Err(RecvError::Closed));
+        });
+
+        let (tx, rx) = channel(2);
+        drop(rx);
+        assert_eq!(tx.send(1), Err(SendError(1)));
+    }
+
+    #[test]
+    fn test_async() {
+        let (tx, mut rx) = channel(2);
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                tx.send(1).unwrap();
+            });
+            assert_eq!(block_on(rx.recv_async()), Ok(1));
+            assert_eq!(block_on(rx.recv_async()), Err(RecvError::Closed));
+        });
+    }
+
+    /// One sender, the other threads receiving, some blocking and some async.
+    /// Every receiver sees the messages in order, and accounts for every
+    /// message either by receiving it or by being told it lagged behind.
+    #[test]
+    fn test_stress() {
+        let config = Config::from_env();
+        let (tx, rx) = channel(8);
+        let tx = std::sync::Mutex::new(Some(tx));
+        let messages = config.iterations as u64;
+        stress::run(&config, |w| {
+            if w.index == 0 {
+                let tx = tx.lock().unwrap().take().unwrap();
+                for i in 0..messages {
+                    tx.send(i).unwrap();
+                    if w.rng.below(4) == 0 {
+                        thread::yield_now();
+                    }
+                }
+                return;
+            }
+            let mut rx = rx.clone();
+            let mut next = 0;
+            loop {
+                let result = match w.index % 2 {
+                    0 => rx.recv(),
+                    _ => block_on(rx.recv_async()),
+                };
+                match result {
+                    Ok(i) => {
+                        assert_eq!(i, next);
+                        next += 1;
+                    }
+                    Err(RecvError::Lagged(n)) => next += n,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+            assert_eq!(next, messages);
+        });
+    }
+}
@@ -0,0 +1,10 @@
+// Library versions of the channels, for use outside of this chapter.
+
+pub mod async_oneshot;
+pub mod atomic_waker;
+pub mod broadcast;
+pub mod mpmc;
+pub mod oneshot;
+pub mod select;
+pub mod spsc;
+pub mod watch;
@@ -0,0 +1,480 @@
+// Bounded multi-producer multi-consumer channel
+//
+// Like simple_mutex_based_channel.rs, but built on the Mutex and Condvar
+// from chapter9, with a capacity, cloneable ends and disconnection:
+// receiving fails once all senders are gone (and the queue is empty),
+// sending fails once all receivers are gone.
+//
+// Receivers can be used with `Select` and `select!` (select.rs):
+// selecting threads register a signal here, which is notified
+// together with `item_ready`.
+
+use crate::select::{SelectRecv, Selectable, Signal};
+use chapter9::{Condvar, Mutex, MutexGuard};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+struct Channel<T> {
+    state: Mutex<State<T>>,
+    /// Notified when a message is sent, or the last sender is dropped.
+    item_ready: Condvar,
+    /// Notified when a message is received, or the last receiver is dropped.
+    space_ready: Condvar,
+    capacity: usize,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receivers: usize,
+    /// Threads selecting on this channel.
+    selectors: Vec<Arc<Signal>>,
+}
+
+impl<T> State<T> {
+    fn notify_selectors(&self) {
+        for signal in &self.selectors {
+            signal.notify();
+        }
+    }
+}
+
+/// Panics if `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity must be at least 1");
+    let channel = Arc::new(Channel {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            senders: 1,
+            receivers: 1,
+            selectors: Vec::new(),
+        }),
+        item_ready: Condvar::new(),
+        space_ready: Condvar::new(),
+        capacity,
+    });
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+// Sender //
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Blocks while the channel is full.
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        let mut state = self.channel.state.lock();
+        loop {
+            if state.receivers == 0 {
+                return Err(SendError(message));
+            }
+            if state.queue.len() < self.channel.capacity {
+                self.push(state, message);
+                return Ok(());
+            }
+            state = self.channel.space_ready.wait(state);
+        }
+    }
+
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        let state = self.channel.state.lock();
+        if state.receivers == 0 {
+            Err(TrySendError::Disconnected(message))
+        } else if state.queue.len() == self.channel.capacity {
+            Err(TrySendError::Full(message))
+        } else {
+            self.push(state, message);
+            Ok(())
+        }
+    }
+
+    fn push(&self, mut state: MutexGuard<State<T>>, message: T) {
+        state.queue.push_back(message);
+        state.notify_selectors();
+        drop(state);
+        self.channel.item_ready.notify_one();
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.state.lock().senders += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.channel.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.notify_selectors();
+            drop(state);
+            // Wake up all receivers, to let them know.
+            self.channel.item_ready.notify_all();
+        }
+    }
+}
+
+// Receiver //
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Blocks while the channel is empty.
+    /// Only fails if the channel is empty and all senders are gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut state = self.channel.state.lock();
+        loop {
+            if let Some(message) = state.queue.pop_front() {
+                return Ok(self.popped(state, message));
+            }
+            if state.senders == 0 {
+                return Err(RecvError);
+            }
+            state = self.channel.item_ready.wait(state);
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.channel.state.lock();
+        match state.queue.pop_front() {
+            Some(message) => Ok(self.popped(state, message)),
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.channel.state.lock();
+        loop {
+            if let Some(message) = state.queue.pop_front() {
+                return Ok(self.popped(state, message));
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            // Spurious wake ups and timeouts are handled by the checks above.
+            (state, _) = self.channel.item_ready.wait_timeout(state, deadline - now);
+        }
+    }
+
+    fn popped(&self, state: MutexGuard<State<T>>, message: T) -> T {
+        drop(state);
+        self.channel.space_ready.notify_one();
+        message
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.state.lock().receivers += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.channel.state.lock();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            drop(state);
+            // Wake up all blocked senders, to let them know.
+            self.channel.space_ready.notify_all();
+        }
+    }
+}
+
+impl<T> Selectable for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        let state = self.channel.state.lock();
+        !state.queue.is_empty() || state.senders == 0
+    }
+
+    fn register(&self, signal: &Arc<Signal>) {
+        self.channel.state.lock().selectors.push(signal.clone());
+    }
+
+    fn unregister(&self, signal: &Arc<Signal>) {
+        let mut state = self.channel.state.lock();
+        if let Some(i) = state.selectors.iter().position(|s| Arc::ptr_eq(s, signal)) {
+            state.selectors.swap_remove(i);
+        }
+    }
+}
+
+impl<T> SelectRecv for Receiver<T> {
+    type Message = T;
+    type Error = RecvError;
+
+    fn try_select(&self) -> Option<Result<T, RecvError>> {
+        match self.try_recv() {
+            Ok(message) => Some(Ok(message)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(RecvError)),
+        }
+    }
+}
+
+// Errors //
+
+/// All receivers are gone. Contains the message that couldn't be sent.
+#[derive(PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Disconnected(T),
+}
+
+/// All senders are gone, and there are no messages left.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    Timeout,
+    Disconnected,
+}
+
+// Like std, don't require T: Debug, the message isn't very interesting.
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SendError { .. }")
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("sending on a disconnected channel")
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("sending on a full channel"),
+            TrySendError::Disconnected(_) => f.write_str("sending on a disconnected channel"),
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("receiving on an empty and disconnected channel")
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Disconnected => {
+                f.write_str("receiving on an empty and disconnected channel")
+            }
+        }
+    }
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => f.write_str("timed out waiting on channel"),
+            RecvTimeoutError::Disconnected => {
+                f.write_str("receiving on an empty and disconnected channel")
+            }
+        }
+    }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+impl<T> std::error::Error for TrySendError<T> {}
+impl std::error::Error for RecvError {}
+impl std::error::Error for TryRecvError {}
+impl std::error::Error for RecvTimeoutError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use stress::{Config, DropCounter, Stamp};
+
+    #[test]
+    fn test_bounded() {
+        let (tx, rx) = channel(2);
+        tx.send(1).unwrap();
+        tx.try_send(2).unwrap();
+        assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+        assert_eq!(rx.try_recv(), Ok(1));
+        tx.try_send(3).unwrap();
+        assert_eq!(rx.recv(), Ok(2));
+        assert_eq!(rx.recv(), Ok(3));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn test_send_blocks_when_full() {
+        let (tx, rx) = channel(1);
+        tx.send(1).unwrap();
+        thread::scope(|s| {
+            let t = s.spawn(|| tx.send(2));
+            thread::sleep(Duration::from_millis(50));
+            assert!(!t.is_finished());
+            assert_eq!(rx.recv(), Ok(1));
+            t.join().unwrap().unwrap();
+        });
+        assert_eq!(rx.recv(), Ok(2));
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        let (tx, rx) = channel::<i32>(1);
+        let start = Instant::now();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(50)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                tx.send(7).unwrap();
+            });
+            assert_eq!(rx.recv_timeout(Duration::from_secs(10)), Ok(7));
+        });
+    }
+
+    #[test]
+    fn test_disconnect_senders() {
+        let (tx, rx) = channel(4);
+        let tx2 = tx.clone();
+        tx.send(1).unwrap();
+        drop(tx);
+        tx2.send(2).unwrap();
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                drop(tx2);
+            });
+            // Messages sent before the disconnect are still received.
+            assert_eq!(rx.recv(), Ok(1));
+            assert_eq!(rx.recv(), Ok(2));
+            // Blocks until the last sender is dropped.
+            assert_eq!(rx.recv(), Err(RecvError));
+        });
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn test_disconnect_receivers() {
+        let (tx, rx) = channel(1);
+        let rx2 = rx.clone();
+        tx.send(1).unwrap();
+        drop(rx);
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                drop(rx2);
+            });
+            // Blocks because it's full, until the last receiver is dropped.
+            assert_eq!(tx.send(2), Err(SendError(2)));
+        });
+        assert_eq!(tx.try_send(3), Err(TrySendError::Disconnected(3)));
+    }
+
+    #[test]
+    fn test_drops_unreceived_messages() {
+        let counter = DropCounter::new();
+        let (tx, rx) = channel(3);
+        tx.send(counter.track()).unwrap();
+        tx.send(counter.track()).unwrap();
+        drop(rx.recv().unwrap());
+        drop(tx);
+        drop(rx);
+        assert_eq!(counter.alive(), 0);
+    }
+
+    /// Even threads produce, odd threads consume, over a tiny buffer.
+    /// Every message arrives exactly once, in order per producer.
+    #[test]
+    fn test_stress() {
+        let config = Config::from_env();
+        assert!(
+            config.threads >= 2,
+            "need at least one producer and one consumer"
+        );
+        let counter = &DropCounter::new();
+        let (tx, rx) = channel(2);
+        // Every thread gets its own ends (the last one gets the originals),
+        // so the consumers see the disconnect once all producers are done.
+        let ends = std::sync::Mutex::new(vec![(tx, rx); config.threads]);
+
+        let received: usize = stress::run(&config, |w| {
+            let (tx, rx) = ends.lock().unwrap().pop().unwrap();
+            if w.index % 2 == 0 {
+                drop(rx);
+                for seq in 0..w.iterations as u64 {
+                    tx.send((w.index, Stamp::new(seq), counter.track()))
+                        .unwrap();
+                }
+                0
+            } else {
+                drop(tx);
+                let mut next_seq = vec![0; config.threads];
+                let mut n = 0;
+                while let Ok((producer, stamp, _tracked)) = rx.recv() {
+                    assert!(stamp.value() >= next_seq[producer], "out of order");
+                    next_seq[producer] = stamp.value() + 1;
+                    n += 1;
+                }
+                n
+            }
+        })
+        .into_iter()
+        .sum();
+
+        assert_eq!(received, config.threads.div_ceil(2) * config.iterations);
+        assert_eq!(counter.alive(), 0);
+    }
+}
@@ -0,0 +1,432 @@
+// Reusable one-shot channel
+//
+// Like blocking.rs and borrowing_to_avoid_allocation.rs, the channel is
+// borrowed by its Sender and Receiver, so it doesn't need an Arc.
+// But `split` only takes a shared reference: a single long-lived channel
+// (e.g. a field of a struct or a static) can be split again once both ends
+// of the previous round are gone. The state tracks which ends are still
+// alive, so the receiver finds out when the sender is dropped without
+// sending, instead of waiting forever.
+//
+// The receiver blocks on the state itself, using a futex,
+// which allows for a timeout, and allows the receiver to be sent to another thread.
+//
+// The receiver can also be used with `Select` and `select!` (select.rs).
+// Since that only borrows it, receiving through `select!` leaves it
+// RECEIVED: done, but not yet dropped.
+
+use crate::mpmc::SendError;
+use crate::select::{SelectRecv, Selectable, Signal, Signals};
+use atomic_wait::{wait, wake_one};
+use chapter9::futex;
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem::{self, MaybeUninit};
+use std::sync::atomic::{
+    AtomicU32,
+    Ordering::{Acquire, Relaxed, Release},
+};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Not split. The message slot is empty.
+const IDLE: u32 = 0;
+/// Both ends alive, nothing sent yet.
+const WAITING: u32 = 1;
+/// Sent. Only the receiver is left.
+const READY: u32 = 2;
+/// The sender was dropped without sending. Only the receiver is left.
+const CANCELED: u32 = 3;
+/// The receiver was dropped. Only the sender is left.
+const CLOSED: u32 = 4;
+/// Received through `select!`. Only the receiver is left. The message slot is empty.
+const RECEIVED: u32 = 5;
+
+pub struct OneshotChannel<T> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    state: AtomicU32,
+    /// Threads selecting on the receiver.
+    selectors: Signals,
+}
+
+unsafe impl<T> Sync for OneshotChannel<T> where T: Send {}
+
+impl<T> OneshotChannel<T> {
+    pub const fn new() -> Self {
+        Self {
+            message: UnsafeCell::new(MaybeUninit::uninit()),
+            state: AtomicU32::new(IDLE),
+            selectors: Signals::new(),
+        }
+    }
+
+    /// Returns None if the ends from the previous `split` aren't both gone yet.
+    pub fn split(&self) -> Option<(Sender<'_, T>, Receiver<'_, T>)> {
+        // Acquire: the previous round must be completely done with the message slot.
+        self.state
+            .compare_exchange(IDLE, WAITING, Acquire, Relaxed)
+            .ok()?;
+        Some((Sender { channel: self }, Receiver { channel: self }))
+    }
+
+    /// True if the channel can be split again.
+    pub fn is_idle(&self) -> bool {
+        self.state.load(Relaxed) == IDLE
+    }
+
+    /// Makes the channel splittable again,
+    /// even if the ends of the previous round were leaked (with `mem::forget`).
+    /// Drops the message if there was one that never got received.
+    pub fn reset(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.message.get_mut().assume_init_drop() }
+        }
+        *self.state.get_mut() = IDLE;
+    }
+}
+
+impl<T> Default for OneshotChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OneshotChannel<T> {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+// Sender //
+
+pub struct Sender<'a, T> {
+    channel: &'a OneshotChannel<T>,
+}
+
+impl<T> Sender<'_, T> {
+    /// Fails if the receiver is already gone, giving the message back.
+    pub fn send(self, message: T) -> Result<(), SendError<T>> {
+        let channel = self.channel;
+        mem::forget(self);
+        unsafe { (*channel.message.get()).write(message) };
+        match channel
+            .state
+            .compare_exchange(WAITING, READY, Release, Acquire)
+        {
+            Ok(_) => {
+                wake_one(&channel.state);
+                channel.selectors.notify();
+                Ok(())
+            }
+            Err(_closed) => {
+                let message = unsafe { (*channel.message.get()).assume_init_read() };
+                channel.state.store(IDLE, Release);
+                Err(SendError(message))
+            }
+        }
+    }
+
+    /// True if the receiver is gone, so there's no point in sending anymore.
+    pub fn is_closed(&self) -> bool {
+        self.channel.state.load(Relaxed) == CLOSED
+    }
+}
+
+impl<T> Drop for Sender<'_, T> {
+    fn drop(&mut self) {
+        let state = &self.channel.state;
+        match state.compare_exchange(WAITING, CANCELED, Release, Relaxed) {
+            Ok(_) => {
+                wake_one(state);
+                self.channel.selectors.notify();
+            }
+            // The receiver is gone too, so this round is over.
+            Err(_closed) => state.store(IDLE, Release),
+        }
+    }
+}
+
+// Receiver //
+
+pub struct Receiver<'a, T> {
+    channel: &'a OneshotChannel<T>,
+}
+
+impl<'a, T> Receiver<'a, T> {
+    /// True if `receive` won't block.
+    pub fn is_ready(&self) -> bool {
+        self.channel.state.load(Relaxed) != WAITING
+    }
+
+    /// Blocks until the message arrives,
+    /// or fails if the sender is dropped without sending.
+    ///
+    /// Also fails if the message was already received through `select!`.
+    pub fn receive(self) -> Result<T, Canceled> {
+        loop {
+            match self.channel.state.load(Acquire) {
+                WAITING => wait(&self.channel.state, WAITING),
+                _ => return self.take(),
+            }
+        }
+    }
+
+    /// Like `receive`, but gives up after `timeout`,
+    /// giving back the receiver to try again later.
+    pub fn receive_timeout(self, timeout: Duration) -> Result<T, ReceiveTimeoutError<'a, T>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.channel.state.load(Acquire) != WAITING {
+                return self
+                    .take()
+                    .map_err(|Canceled| ReceiveTimeoutError::Canceled);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ReceiveTimeoutError::Timeout(self));
+            }
+            // Spurious wake ups and timeouts are handled by the checks above.
+            futex::wait_timeout(&self.channel.state, WAITING, deadline - now);
+        }
+    }
+
+    /// Ends this round. The state must be READY, CANCELED or RECEIVED.
+    fn take(self) -> Result<T, Canceled> {
+        let channel = self.channel;
+        mem::forget(self);
+        let result = match channel.state.load(Relaxed) {
+            READY => Ok(unsafe { (*channel.message.get()).assume_init_read() }),
+            _ => Err(Canceled),
+        };
+        // Release: we're done with the message slot, for the next `split`.
+        channel.state.store(IDLE, Release);
+        result
+    }
+}
+
+impl<T> Drop for Receiver<'_, T> {
+    fn drop(&mut self) {
+        let state = &self.channel.state;
+        if state
+            .compare_exchange(WAITING, CLOSED, Relaxed, Acquire)
+            .is_err()
+        {
+            // The sender is already gone, maybe after sending
+            // (and maybe we've already received it through `select!`).
+            if state.load(Relaxed) == READY {
+                unsafe { (*self.channel.message.get()).assume_init_drop() }
+            }
+            state.store(IDLE, Release);
+        }
+    }
+}
+
+impl<T> Selectable for Receiver<'_, T> {
+    fn is_ready(&self) -> bool {
+        Receiver::is_ready(self)
+    }
+
+    fn register(&self, signal: &Arc<Signal>) {
+        self.channel.selectors.register(signal);
+    }
+
+    fn unregister(&self, signal: &Arc<Signal>) {
+        self.channel.selectors.unregister(signal);
+    }
+}
+
+impl<T> SelectRecv for Receiver<'_, T> {
+    type Message = T;
+    type Error = Canceled;
+
+    fn try_select(&self) -> Option<Result<T, Canceled>> {
+        // A compare-and-exchange, since the receiver is Sync:
+        // only one thread gets to take the message.
+        match self
+            .channel
+            .state
+            .compare_exchange(READY, RECEIVED, Acquire, Relaxed)
+        {
+            Ok(_) => Some(Ok(unsafe {
+                (*self.channel.message.get()).assume_init_read()
+            })),
+            Err(WAITING) => None,
+            Err(_) => Some(Err(Canceled)),
+        }
+    }
+}
+
+// Errors //
+
+/// The sender was dropped without sending anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canceled;
+
+pub enum ReceiveTimeoutError<'a, T> {
+    /// Nothing was sent yet. Contains the receiver, to try again.
+    Timeout(Receiver<'a, T>),
+    Canceled,
+}
+
+impl fmt::Display for Canceled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("sender dropped without sending")
+    }
+}
+
+impl<T> fmt::Debug for ReceiveTimeoutError<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReceiveTimeoutError::Timeout(_) => f.write_str("Timeout(..)"),
+            ReceiveTimeoutError::Canceled => f.write_str("Canceled"),
+        }
+    }
+}
+
+impl<T> fmt::Display for ReceiveTimeoutError<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReceiveTimeoutError::Timeout(_) => f.write_str("timed out waiting on channel"),
+            ReceiveTimeoutError::Canceled => Canceled.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Canceled {}
+impl<T> std::error::Error for ReceiveTimeoutError<'_, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use stress::{Config, DropCounter, Stamp};
+
+    #[test]
+    fn test_round_trips() {
+        let channel = OneshotChannel::new();
+        for i in 0..3 {
+            let (sender, receiver) = channel.split().unwrap();
+            // Can't split again while this round isn't done.
+            assert!(channel.split().is_none());
+            thread::scope(|s| {
+                s.spawn(move || sender.send(i).unwrap());
+                assert_eq!(receiver.receive(), Ok(i));
+            });
+            assert!(channel.is_idle());
+        }
+    }
+
+    #[test]
+    fn test_canceled() {
+        let channel = OneshotChannel::<i32>::new();
+        let (sender, receiver) = channel.split().unwrap();
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                drop(sender);
+            });
+            assert_eq!(receiver.receive(), Err(Canceled));
+        });
+        assert!(channel.is_idle());
+    }
+
+    #[test]
+    fn test_receiver_dropped() {
+        let channel = OneshotChannel::new();
+        let (sender, receiver) = channel.split().unwrap();
+        assert!(!sender.is_closed());
+        drop(receiver);
+        assert!(sender.is_closed());
+        assert!(channel.split().is_none());
+        assert_eq!(sender.send(5), Err(SendError(5)));
+        assert!(channel.is_idle());
+    }
+
+    #[test]
+    fn test_receive_timeout() {
+        let channel = OneshotChannel::new();
+        let (sender, receiver) = channel.split().unwrap();
+        let start = Instant::now();
+        let receiver = match receiver.receive_timeout(Duration::from_millis(50)) {
+            Err(ReceiveTimeoutError::Timeout(r)) => r,
+            r => panic!("unexpected {r:?}"),
+        };
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                sender.send("hi").unwrap();
+            });
+            assert_eq!(
+                receiver.receive_timeout(Duration::from_secs(10)).unwrap(),
+                "hi"
+            );
+        });
+
+        let (sender, receiver) = channel.split().unwrap();
+        drop(sender);
+        assert!(matches!(
+            receiver.receive_timeout(Duration::from_secs(10)),
+            Err(ReceiveTimeoutError::Canceled)
+        ));
+    }
+
+    #[test]
+    fn test_drops() {
+        let counter = DropCounter::new();
+        let mut channel = OneshotChannel::new();
+
+        // Sent, but never received.
+        let (sender, receiver) = channel.split().unwrap();
+        sender.send(counter.track()).unwrap();
+        drop(receiver);
+        assert_eq!(counter.alive(), 0);
+
+        // Leaked ends, cleaned up by `reset`.
+        let (sender, receiver) = channel.split().unwrap();
+        sender.send(counter.track()).unwrap();
+        mem::forget(receiver);
+        assert!(channel.split().is_none());
+        channel.reset();
+        assert_eq!(counter.alive(), 0);
+
+        // Leaked ends, cleaned up when the channel is dropped.
+        let (sender, receiver) = channel.split().unwrap();
+        sender.send(counter.track()).unwrap();
+        mem::forget(receiver);
+        drop(channel);
+        assert_eq!(counter.alive(), 0);
+    }
+
+    /// Every thread reuses one channel for all its round trips, randomly
+    /// sending, or dropping either end first.
+    #[test]
+    fn test_stress() {
+        let counter = &DropCounter::new();
+
+        stress::run(&Config::from_env(), |w| {
+            let channel = OneshotChannel::new();
+            for _ in 0..w.iterations {
+                let value = w.rng.next_u64();
+                let action = w.rng.below(3);
+                let (sender, receiver) = channel.split().unwrap();
+                thread::scope(|s| {
+                    s.spawn(move || match action {
+                        0 => drop(sender),
+                        _ => {
+                            let _ = sender.send((Stamp::new(value), counter.track()));
+                        }
+                    });
+                    match action {
+                        0 => assert_eq!(receiver.receive().err(), Some(Canceled)),
+                        1 => assert_eq!(receiver.receive().unwrap().0.value(), value),
+                        _ => drop(receiver),
+                    }
+                });
+                assert!(channel.is_idle());
+            }
+        });
+
+        assert_eq!(counter.alive(), 0);
+    }
+}
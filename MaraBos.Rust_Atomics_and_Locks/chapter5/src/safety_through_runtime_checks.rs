@@ -43,7 +43,9 @@ impl<T> Channel<T> {
     ///
     /// Tip: Use `is_ready` to check first.
     ///
-    /// Safety: Only call this once!
+    /// # Safety
+    ///
+    /// Only call this once!
     /// (but it possible to call it twice, even thou it's UB)
     pub unsafe fn receive_bad(&self) -> T {
         if !self.ready.load(Acquire) {
@@ -67,6 +69,12 @@ impl<T> Channel<T> {
     }
 }
 
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Drop for Channel<T> {
     fn drop(&mut self) {
         if *self.ready.get_mut() {
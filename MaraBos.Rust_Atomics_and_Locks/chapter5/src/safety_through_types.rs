@@ -8,6 +8,8 @@ use std::sync::atomic::{
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
+#[cfg(test)]
+use stress::{Config, DropCounter, Stamp};
 
 struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
@@ -19,7 +21,10 @@ unsafe impl<T: Send> Sync for Channel<T> {}
 
 impl<T> Drop for Channel<T> {
     fn drop(&mut self) {
-        unsafe { self.message.get_mut().assume_init_drop() }
+        // Only drop the message if it was sent, but never received.
+        if *self.ready.get_mut() {
+            unsafe { self.message.get_mut().assume_init_drop() }
+        }
     }
 }
 
@@ -60,6 +65,29 @@ impl<T> Receiver<T> {
     }
 }
 
+#[test]
+fn test_stress() {
+    let counter = &DropCounter::new();
+
+    stress::run(&Config::from_env(), |w| {
+        for i in 0..w.iterations {
+            let value = w.rng.next_u64();
+            let (sender, receiver) = channel();
+            thread::scope(|s| {
+                s.spawn(move || sender.send((Stamp::new(value), counter.track())));
+            });
+            // Leave every other message in the channel,
+            // it must be dropped together with the channel.
+            if i % 2 == 0 {
+                let (stamp, _tracked) = receiver.receive();
+                assert_eq!(stamp.value(), value);
+            }
+        }
+    });
+
+    assert_eq!(counter.alive(), 0);
+}
+
 fn main() {
     thread::scope(|s| {
         let (sender, receiver) = channel();
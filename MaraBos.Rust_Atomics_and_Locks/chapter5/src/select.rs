@@ -0,0 +1,428 @@
+// Select
+//
+// Waiting for whichever of several receivers becomes ready first.
+//
+// A `Select` registers one shared `Signal` in each of the channels, which
+// the channels notify whenever they might have become ready (a message was
+// sent, or the last sender went away). The selecting thread waits on the
+// signal using a futex, and checks all receivers again every time it's
+// notified. The signal's state makes sure a notification that comes in
+// between checking the receivers and going to sleep isn't lost.
+//
+// Channels take part by implementing `Selectable` (and `SelectRecv`, to
+// be usable in the `select!` macro). The receivers of mpmc, spsc, oneshot,
+// broadcast and watch all do, so one `select!` can mix them.
+// mpmc keeps the signals in its own State; the others use `Signals`.
+
+use atomic_wait::{wait, wake_one};
+use chapter9::{futex, Mutex};
+use std::fmt;
+use std::sync::atomic::{
+    AtomicU32,
+    Ordering::{Acquire, Release},
+};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A channel end that a `Select` can wait for.
+pub trait Selectable {
+    /// True if receiving wouldn't block right now.
+    fn is_ready(&self) -> bool;
+
+    /// From now on, notify `signal` whenever this might have become ready.
+    fn register(&self, signal: &Arc<Signal>);
+
+    fn unregister(&self, signal: &Arc<Signal>);
+}
+
+/// A receiver that can be used in `select!`.
+pub trait SelectRecv: Selectable {
+    type Message;
+    type Error;
+
+    /// Like `try_recv`, but returns None if it's empty.
+    fn try_select(&self) -> Option<Result<Self::Message, Self::Error>>;
+}
+
+// Signal //
+
+pub struct Signal {
+    /// 0: nothing happened since the selecting thread last looked.
+    /// 1: notified.
+    state: AtomicU32,
+}
+
+impl Signal {
+    fn new() -> Self {
+        Self {
+            state: AtomicU32::new(0),
+        }
+    }
+
+    /// Called by a channel after something changed.
+    pub fn notify(&self) {
+        if self.state.swap(1, Release) == 0 {
+            wake_one(&self.state);
+        }
+    }
+}
+
+// Signals //
+
+/// The signals of the threads selecting on a channel,
+/// for channels without a Mutex of their own to keep them under.
+pub struct Signals {
+    signals: Mutex<Vec<Arc<Signal>>>,
+}
+
+impl Signals {
+    pub const fn new() -> Self {
+        Self {
+            signals: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Call this after the change, so that a selecting thread
+    /// either gets notified, or sees the change after registering.
+    pub fn notify(&self) {
+        for signal in self.signals.lock().iter() {
+            signal.notify();
+        }
+    }
+
+    pub fn register(&self, signal: &Arc<Signal>) {
+        self.signals.lock().push(signal.clone());
+    }
+
+    pub fn unregister(&self, signal: &Arc<Signal>) {
+        let mut signals = self.signals.lock();
+        if let Some(i) = signals.iter().position(|s| Arc::ptr_eq(s, signal)) {
+            signals.swap_remove(i);
+        }
+    }
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Select //
+
+#[derive(Default)]
+pub struct Select<'a> {
+    receivers: Vec<&'a dyn Selectable>,
+}
+
+impl<'a> Select<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a receiver, and returns its index.
+    pub fn recv(&mut self, receiver: &'a dyn Selectable) -> usize {
+        self.receivers.push(receiver);
+        self.receivers.len() - 1
+    }
+
+    /// Blocks until any of the receivers is ready, and returns its index.
+    ///
+    /// If several are ready, the first one is picked.
+    /// With multiple consumers, another one might still take the message
+    /// first, in which case `try_recv` fails and you'll have to select again.
+    pub fn ready(&self) -> usize {
+        self.wait(None).unwrap()
+    }
+
+    pub fn ready_timeout(&self, timeout: Duration) -> Result<usize, SelectTimeoutError> {
+        self.ready_deadline(Instant::now() + timeout)
+    }
+
+    pub fn ready_deadline(&self, deadline: Instant) -> Result<usize, SelectTimeoutError> {
+        self.wait(Some(deadline)).ok_or(SelectTimeoutError)
+    }
+
+    fn first_ready(&self) -> Option<usize> {
+        self.receivers.iter().position(|r| r.is_ready())
+    }
+
+    fn wait(&self, deadline: Option<Instant>) -> Option<usize> {
+        assert!(!self.receivers.is_empty(), "nothing to select");
+
+        // Don't bother registering if something is ready already.
+        if let Some(i) = self.first_ready() {
+            return Some(i);
+        }
+
+        let signal = Arc::new(Signal::new());
+        for r in &self.receivers {
+            r.register(&signal);
+        }
+        let result = loop {
+            // Reset the signal before looking,
+            // so anything that happens after this wakes us up.
+            signal.state.swap(0, Acquire);
+            if let Some(i) = self.first_ready() {
+                break Some(i);
+            }
+            match deadline {
+                None => wait(&signal.state, 0),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break None;
+                    }
+                    futex::wait_timeout(&signal.state, 0, deadline - now);
+                }
+            }
+        };
+        for r in &self.receivers {
+            r.unregister(&signal);
+        }
+        result
+    }
+}
+
+/// None of the receivers became ready in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectTimeoutError;
+
+impl fmt::Display for SelectTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("timed out waiting on select")
+    }
+}
+
+impl std::error::Error for SelectTimeoutError {}
+
+// select! //
+
+/// Receives from whichever of the receivers is ready first.
+///
+/// ```
+/// # use chapter5::{mpmc, select};
+/// # use std::time::Duration;
+/// let (tx1, rx1) = mpmc::channel::<i32>(1);
+/// let (tx2, rx2) = mpmc::channel::<&str>(1);
+/// tx2.send("hi").unwrap();
+/// select! {
+///     recv(rx1) -> msg => println!("number: {msg:?}"),
+///     recv(rx2) -> msg => assert_eq!(msg, Ok("hi")),
+///     timeout(Duration::from_secs(1)) => panic!("timed out"),
+/// }
+/// # drop((tx1, tx2));
+/// ```
+///
+/// `msg` is an `Err` if that receiver's senders are all gone (with the
+/// receiver's own error type, e.g. `mpmc::RecvError`).
+/// The `timeout` arm is optional.
+#[macro_export]
+macro_rules! select {
+    (
+        $(recv($rx:expr) -> $msg:pat => $body:expr,)+
+        timeout($timeout:expr) => $timeout_body:expr $(,)?
+    ) => {
+        $crate::select!(@bind [$($rx, $msg, $body;)+] []
+            (Some(::std::time::Instant::now() + $timeout)) $timeout_body)
+    };
+    ($(recv($rx:expr) -> $msg:pat => $body:expr),+ $(,)?) => {
+        $crate::select!(@bind [$($rx, $msg, $body;)+] [] (None) unreachable!())
+    };
+
+    // Evaluates every receiver expression once, and makes a slot
+    // for its message. Every expansion gets its own `rx` and `slot`.
+    (@bind [$rx:expr, $msg:pat, $body:expr; $($rest:tt)*] [$($bound:tt)*] $($t:tt)*) => {{
+        let rx = &$rx;
+        let mut slot = None;
+        $crate::select!(@bind [$($rest)*] [$($bound)* (rx, slot, $msg, $body)] $($t)*)
+    }};
+    (@bind [] [$(($rx:ident, $slot:ident, $msg:pat, $body:expr))+]
+        ($deadline:expr) $timeout_body:expr) => {{
+        let deadline: Option<::std::time::Instant> = $deadline;
+        let mut select = $crate::select::Select::new();
+        $( select.recv($rx); )+
+        loop {
+            $(
+                if let Some(message) = $crate::select::SelectRecv::try_select($rx) {
+                    $slot = Some(message);
+                    break;
+                }
+            )+
+            let ready = match deadline {
+                Some(deadline) => select.ready_deadline(deadline).is_ok(),
+                None => { select.ready(); true }
+            };
+            if !ready {
+                break;
+            }
+        }
+        // Outside of the loop, so `break` and `continue` in the bodies work as expected.
+        $(
+            if let Some(message) = $slot {
+                let $msg = message;
+                $body
+            } else
+        )+ {
+            $timeout_body
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpmc::{self, RecvError};
+    use crate::oneshot::OneshotChannel;
+    use crate::{broadcast, spsc, watch};
+    use std::thread;
+
+    #[test]
+    fn test_ready() {
+        let (tx1, rx1) = mpmc::channel::<i32>(1);
+        let (tx2, rx2) = mpmc::channel::<i32>(1);
+        let mut select = Select::new();
+        assert_eq!(select.recv(&rx1), 0);
+        assert_eq!(select.recv(&rx2), 1);
+
+        assert_eq!(
+            select.ready_timeout(Duration::from_millis(10)),
+            Err(SelectTimeoutError)
+        );
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                tx2.send(2).unwrap();
+            });
+            assert_eq!(select.ready(), 1);
+        });
+        assert_eq!(rx2.try_recv(), Ok(2));
+
+        // A disconnected channel is always ready.
+        drop(tx1);
+        assert_eq!(select.ready(), 0);
+    }
+
+    #[test]
+    fn test_select_macro() {
+        let (tx1, rx1) = mpmc::channel(1);
+        let (tx2, rx2) = mpmc::channel::<()>(1);
+
+        let start = Instant::now();
+        let r = select! {
+            recv(rx1) -> _ => 1,
+            recv(rx2) -> _ => 2,
+            timeout(Duration::from_millis(20)) => 0,
+        };
+        assert_eq!(r, 0);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                tx1.send("one").unwrap();
+            });
+            select! {
+                recv(rx1) -> msg => assert_eq!(msg, Ok("one")),
+                recv(rx2) -> msg => panic!("{msg:?}"),
+            }
+        });
+
+        drop(tx2);
+        select! {
+            recv(rx1) -> msg => panic!("{msg:?}"),
+            recv(rx2) -> msg => assert_eq!(msg, Err(RecvError)),
+        }
+    }
+
+    /// One select! over every kind of receiver, woken up by each of them.
+    #[test]
+    fn test_mixed_channels() {
+        let (mpmc_tx, mpmc_rx) = mpmc::channel(1);
+        let (spsc_tx, spsc_rx) = spsc::channel(1);
+        let oneshot = OneshotChannel::new();
+        let (oneshot_tx, oneshot_rx) = oneshot.split().unwrap();
+        let (broadcast_tx, broadcast_rx) = broadcast::channel(1);
+        let (watch_tx, watch_rx) = watch::channel(0);
+
+        let next = |timeout| {
+            select! {
+                recv(mpmc_rx) -> msg => format!("mpmc {msg:?}"),
+                recv(spsc_rx) -> msg => format!("spsc {msg:?}"),
+                recv(broadcast_rx) -> msg => format!("broadcast {msg:?}"),
+                recv(watch_rx) -> msg => format!("watch {msg:?} {}", *watch_rx.borrow()),
+                recv(oneshot_rx) -> msg => format!("oneshot {msg:?}"),
+                timeout(timeout) => "timeout".to_string(),
+            }
+        };
+        let ms = Duration::from_millis;
+        let later = |f: &(dyn Fn() + Sync)| {
+            thread::sleep(ms(10));
+            f();
+        };
+
+        assert_eq!(next(ms(10)), "timeout");
+        thread::scope(|s| {
+            s.spawn(|| later(&|| mpmc_tx.send(1).unwrap()));
+            assert_eq!(next(ms(1000)), "mpmc Ok(1)");
+            // The spsc sender isn't Sync, so it goes there and back.
+            let t = s.spawn(move || {
+                thread::sleep(ms(10));
+                spsc_tx.send(2).unwrap();
+                spsc_tx
+            });
+            assert_eq!(next(ms(1000)), "spsc Ok(2)");
+            let spsc_tx = t.join().unwrap();
+            s.spawn(|| later(&|| broadcast_tx.send(3).unwrap()));
+            assert_eq!(next(ms(1000)), "broadcast Ok(3)");
+            s.spawn(|| later(&|| watch_tx.send(4)));
+            assert_eq!(next(ms(1000)), "watch Ok(()) 4");
+            s.spawn(move || {
+                thread::sleep(ms(10));
+                oneshot_tx.send(5).unwrap();
+            });
+            assert_eq!(next(ms(1000)), "oneshot Ok(5)");
+            // Once received, it stays ready, with an error.
+            assert_eq!(next(ms(0)), "oneshot Err(Canceled)");
+            // A disconnected receiver is always ready too.
+            drop(spsc_tx);
+            assert_eq!(next(ms(0)), "spsc Err(RecvError)");
+        });
+    }
+
+    /// Two producers per channel, one consumer selecting over all channels.
+    /// Nothing is lost, and the selecting thread never sleeps through a message.
+    #[test]
+    fn test_stress() {
+        let config = stress::Config::from_env();
+        let (tx_a, rx_a) = mpmc::channel(2);
+        let (tx_b, rx_b) = mpmc::channel(2);
+        let (tx_c, rx_c) = mpmc::channel(2);
+        let senders = [tx_a, tx_b, tx_c];
+        let mut received = [0; 3];
+        thread::scope(|s| {
+            for i in 0..6 {
+                let tx = &senders[i % 3];
+                let iterations = config.iterations;
+                s.spawn(move || {
+                    for _ in 0..iterations {
+                        tx.send(i).unwrap();
+                    }
+                });
+            }
+            // Not waiting for disconnection: a disconnected receiver
+            // is always ready, and would starve the others.
+            for _ in 0..6 * config.iterations {
+                let (channel, i) = select! {
+                    recv(rx_a) -> msg => (0, msg.unwrap()),
+                    recv(rx_b) -> msg => (1, msg.unwrap()),
+                    recv(rx_c) -> msg => (2, msg.unwrap()),
+                };
+                assert_eq!(i % 3, channel);
+                received[channel] += 1;
+            }
+        });
+        assert_eq!(received, [2 * config.iterations; 3]);
+    }
+}
@@ -1,6 +1,8 @@
 use std::sync::{Arc, Mutex, Condvar};
 use std::thread;
 use std::collections::VecDeque;
+#[cfg(test)]
+use stress::{Config, Stamp};
 
 pub struct Channel<T> {
     queue: Mutex<VecDeque<T>>,
@@ -31,6 +33,54 @@ impl<T> Channel<T> {
     }
 }
 
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Even threads produce, odd threads consume. Every consumer must see
+/// the messages of every single producer in order, and nothing gets lost.
+#[test]
+fn test_stress() {
+    let config = Config::from_env();
+    assert!(
+        config.threads >= 2,
+        "need at least one producer and one consumer"
+    );
+    let producers = config.threads.div_ceil(2);
+    let consumers = config.threads / 2;
+    let messages = producers * config.iterations;
+
+    let chan = Channel::new();
+    let received: usize = stress::run(&config, |w| {
+        if w.index % 2 == 0 {
+            for seq in 0..w.iterations as u64 {
+                chan.send((w.index, Stamp::new(seq)));
+            }
+            0
+        } else {
+            let consumer = w.index / 2;
+            let mut n = messages / consumers;
+            if consumer == 0 {
+                n += messages % consumers;
+            }
+            let mut next_seq = vec![0; config.threads];
+            for _ in 0..n {
+                let (producer, stamp) = chan.receive();
+                assert!(stamp.value() >= next_seq[producer], "out of order");
+                next_seq[producer] = stamp.value() + 1;
+            }
+            n
+        }
+    })
+    .into_iter()
+    .sum();
+
+    assert_eq!(received, messages);
+    assert!(chan.queue.lock().unwrap().is_empty());
+}
+
 fn main() {
     let chan = Arc::new(Channel::new());
 
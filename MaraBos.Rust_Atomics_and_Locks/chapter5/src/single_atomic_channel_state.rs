@@ -7,6 +7,8 @@ use std::sync::atomic::{
 };
 use std::thread;
 use std::time::Duration;
+#[cfg(test)]
+use stress::{Config, DropCounter, Stamp};
 
 const EMPTY: u8 = 0;
 const WRITING: u8 = 1;
@@ -57,6 +59,12 @@ impl<T> Channel<T> {
     }
 }
 
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Drop for Channel<T> {
     fn drop(&mut self) {
         if *self.state.get_mut() == READY {
@@ -65,6 +73,28 @@ impl<T> Drop for Channel<T> {
     }
 }
 
+#[test]
+fn test_stress() {
+    let counter = DropCounter::new();
+
+    stress::run(&Config::from_env(), |w| {
+        for _ in 0..w.iterations {
+            let value = w.rng.next_u64();
+            let chan = Channel::new();
+            thread::scope(|s| {
+                s.spawn(|| chan.send((Stamp::new(value), counter.track())));
+                while !chan.is_ready() {
+                    thread::yield_now();
+                }
+                let (stamp, _tracked) = chan.receive();
+                assert_eq!(stamp.value(), value);
+            });
+        }
+    });
+
+    assert_eq!(counter.alive(), 0);
+}
+
 fn main() {
     let chan = Channel::new();
     thread::scope(|s| {
@@ -0,0 +1,562 @@
+// Lock-free single-producer single-consumer ring buffer
+//
+// Unlike the other channels in this chapter, nothing here is ever locked:
+// only the sender writes `tail`, only the receiver writes `head`, and both
+// only read the other's index to find out how much room (or data) there is.
+// `try_send`, `try_recv`, `push_slice` and `pop_slice` never wait for the
+// other thread, which makes them wait-free.
+//
+// The two indices live on separate cache lines (see chapter7/caching7.rs),
+// so the two threads don't keep taking the same cache line away from each
+// other. On top of that, each side keeps its own copy of the other's index,
+// and only loads the real one again when the buffer looks full (or empty).
+//
+// `send` and `recv` block on a futex while the buffer is full (or empty).
+//
+// The receiver can also be used with `Select` and `select!` (select.rs).
+// While it's registered, `receiver_waiting` is SELECTING, so the sender
+// only takes the lock on the signals while someone is actually selecting.
+
+use crate::mpmc::{RecvError, SendError, TryRecvError, TrySendError};
+use crate::select::{SelectRecv, Selectable, Signal, Signals};
+use atomic_wait::{wait, wake_one};
+use chapter9::CachePadded;
+use std::cell::{Cell, UnsafeCell};
+use std::mem::MaybeUninit;
+use std::sync::atomic::{
+    fence, AtomicBool, AtomicU32, AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release, SeqCst},
+};
+use std::sync::Arc;
+
+struct Channel<T> {
+    /// Position of the next message to receive. Only written by the receiver.
+    head: CachePadded<AtomicUsize>,
+    /// Position of the next message to send. Only written by the sender.
+    tail: CachePadded<AtomicUsize>,
+    /// Positions keep counting up (wrapping around at usize::MAX),
+    /// so the buffer length must be a power of two.
+    buffer: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    capacity: usize,
+    /// 1 while the receiver is about to block on an empty buffer.
+    /// SELECTING while it's registered with a `Select`.
+    receiver_waiting: AtomicU32,
+    /// 1 while the sender is about to block on a full buffer.
+    sender_waiting: AtomicU32,
+    /// Set when either end is dropped.
+    disconnected: AtomicBool,
+    /// At most one, since the receiver isn't Sync.
+    selectors: Signals,
+}
+
+const SELECTING: u32 = 2;
+
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+/// Panics if `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity must be at least 1");
+    let channel = Arc::new(Channel {
+        head: CachePadded(AtomicUsize::new(0)),
+        tail: CachePadded(AtomicUsize::new(0)),
+        buffer: (0..capacity.next_power_of_two())
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        capacity,
+        receiver_waiting: AtomicU32::new(0),
+        sender_waiting: AtomicU32::new(0),
+        disconnected: AtomicBool::new(false),
+        selectors: Signals::new(),
+    });
+    (
+        Sender {
+            channel: channel.clone(),
+            tail: Cell::new(0),
+            head: Cell::new(0),
+        },
+        Receiver {
+            channel,
+            head: Cell::new(0),
+            tail: Cell::new(0),
+        },
+    )
+}
+
+impl<T> Channel<T> {
+    fn slot(&self, position: usize) -> *mut T {
+        let i = position & (self.buffer.len() - 1);
+        UnsafeCell::raw_get(self.buffer[i..].as_ptr()).cast()
+    }
+
+    /// Copies `n` messages from `src` into the buffer, starting at `position`.
+    ///
+    /// Safety: those slots must be free, and `src` must have `n` messages.
+    unsafe fn copy_in(&self, position: usize, src: *const T, n: usize) {
+        let first = n.min(self.buffer.len() - (position & (self.buffer.len() - 1)));
+        std::ptr::copy_nonoverlapping(src, self.slot(position), first);
+        std::ptr::copy_nonoverlapping(src.add(first), self.slot(0), n - first);
+    }
+
+    /// Copies `n` messages out of the buffer into `dst`, starting at `position`.
+    ///
+    /// Safety: those slots must hold messages, and `dst` must have room for `n`.
+    unsafe fn copy_out(&self, position: usize, dst: *mut T, n: usize) {
+        let first = n.min(self.buffer.len() - (position & (self.buffer.len() - 1)));
+        std::ptr::copy_nonoverlapping(self.slot(position), dst, first);
+        std::ptr::copy_nonoverlapping(self.slot(0), dst.add(first), n - first);
+    }
+
+    /// Blocks on `waiting` as long as `blocked()` says we have to.
+    ///
+    /// Together with the fence in `wake`, this makes sure that either we see
+    /// the other side's progress in `blocked()`, or it sees our `waiting` flag.
+    fn sleep(&self, waiting: &AtomicU32, blocked: impl Fn() -> bool) {
+        waiting.store(1, Relaxed);
+        fence(SeqCst);
+        if blocked() && !self.disconnected.load(Relaxed) {
+            wait(waiting, 1);
+        }
+        waiting.store(0, Relaxed);
+    }
+
+    /// Wakes up the other side, if it's (about to be) blocked,
+    /// or notifies the selecting thread.
+    /// Without a syscall (or a lock) if neither.
+    fn wake(&self, waiting: &AtomicU32) {
+        fence(SeqCst);
+        match waiting.load(Relaxed) {
+            0 => {}
+            SELECTING => self.selectors.notify(),
+            _ => {
+                // Not a plain store: the other side might have stopped
+                // waiting since we loaded it, and be selecting by now.
+                if waiting.compare_exchange(1, 0, Relaxed, Relaxed).is_ok() {
+                    wake_one(waiting);
+                }
+            }
+        }
+    }
+
+    fn disconnect(&self, other_side_waiting: &AtomicU32) {
+        self.disconnected.store(true, Release);
+        self.wake(other_side_waiting);
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        let mut position = *self.head.0.get_mut();
+        let tail = *self.tail.0.get_mut();
+        while position != tail {
+            unsafe { self.slot(position).drop_in_place() };
+            position = position.wrapping_add(1);
+        }
+    }
+}
+
+// Sender //
+
+/// Not `Sync`, and not `Clone`: there can only be one.
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+    /// Our own copy of `channel.tail`, which only we change.
+    tail: Cell<usize>,
+    /// The last `channel.head` we've seen. The real one can only be further.
+    head: Cell<usize>,
+}
+
+impl<T> Sender<T> {
+    /// Blocks while the buffer is full.
+    pub fn send(&self, mut message: T) -> Result<(), SendError<T>> {
+        loop {
+            match self.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(m)) => return Err(SendError(m)),
+                Err(TrySendError::Full(m)) => message = m,
+            }
+            let channel = &*self.channel;
+            channel.sleep(&channel.sender_waiting, || {
+                self.tail.get().wrapping_sub(channel.head.0.load(Acquire)) == channel.capacity
+            });
+        }
+    }
+
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        if self.is_disconnected() {
+            return Err(TrySendError::Disconnected(message));
+        }
+        if self.free(1) == 0 {
+            return Err(TrySendError::Full(message));
+        }
+        let tail = self.tail.get();
+        unsafe { self.channel.slot(tail).write(message) };
+        self.advance(tail.wrapping_add(1));
+        Ok(())
+    }
+
+    /// Sends as many messages from the start of `messages` as fit,
+    /// and returns how many that were. Never blocks.
+    ///
+    /// Returns 0 if the buffer is full, or if the receiver is gone.
+    pub fn push_slice(&self, messages: &[T]) -> usize
+    where
+        T: Copy,
+    {
+        if self.is_disconnected() {
+            return 0;
+        }
+        let n = self.free(messages.len());
+        if n > 0 {
+            let tail = self.tail.get();
+            unsafe { self.channel.copy_in(tail, messages.as_ptr(), n) };
+            self.advance(tail.wrapping_add(n));
+        }
+        n
+    }
+
+    /// True if the receiver has been dropped.
+    pub fn is_disconnected(&self) -> bool {
+        self.channel.disconnected.load(Relaxed)
+    }
+
+    /// The number of free slots, up to `wanted`.
+    /// Only looks at the receiver's progress if we don't know about enough of them.
+    fn free(&self, wanted: usize) -> usize {
+        let mut free = self.channel.capacity - self.tail.get().wrapping_sub(self.head.get());
+        if free < wanted {
+            // Acquire: the receiver must be done reading a slot before we overwrite it.
+            self.head.set(self.channel.head.0.load(Acquire));
+            free = self.channel.capacity - self.tail.get().wrapping_sub(self.head.get());
+        }
+        free.min(wanted)
+    }
+
+    fn advance(&self, tail: usize) {
+        self.channel.tail.0.store(tail, Release);
+        self.tail.set(tail);
+        self.channel.wake(&self.channel.receiver_waiting);
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.disconnect(&self.channel.receiver_waiting);
+    }
+}
+
+// Receiver //
+
+/// Not `Sync`, and not `Clone`: there can only be one.
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+    /// Our own copy of `channel.head`, which only we change.
+    head: Cell<usize>,
+    /// The last `channel.tail` we've seen. The real one can only be further.
+    tail: Cell<usize>,
+}
+
+impl<T> Receiver<T> {
+    /// Blocks while the buffer is empty.
+    /// Only fails if the buffer is empty and the sender is gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
+            }
+            let channel = &*self.channel;
+            channel.sleep(&channel.receiver_waiting, || {
+                channel.tail.0.load(Acquire) == self.head.get()
+            });
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        if self.available(1) == 0 {
+            return Err(self.empty_error());
+        }
+        let head = self.head.get();
+        let message = unsafe { self.channel.slot(head).read() };
+        self.advance(head.wrapping_add(1));
+        Ok(message)
+    }
+
+    /// Receives as many messages as are available, up to the length of `buf`,
+    /// and returns how many that were. Never blocks.
+    ///
+    /// Returns 0 if the buffer is empty (or if `buf` is).
+    pub fn pop_slice(&self, buf: &mut [T]) -> usize
+    where
+        T: Copy,
+    {
+        let n = self.available(buf.len());
+        if n > 0 {
+            let head = self.head.get();
+            unsafe { self.channel.copy_out(head, buf.as_mut_ptr(), n) };
+            self.advance(head.wrapping_add(n));
+        }
+        n
+    }
+
+    /// True if the sender has been dropped.
+    /// There might still be messages left to receive.
+    pub fn is_disconnected(&self) -> bool {
+        self.channel.disconnected.load(Relaxed)
+    }
+
+    /// The number of messages ready to be received, up to `wanted`.
+    /// Only looks at the sender's progress if we don't know about enough of them.
+    fn available(&self, wanted: usize) -> usize {
+        let mut n = self.tail.get().wrapping_sub(self.head.get());
+        if n < wanted {
+            // Acquire: to see the messages the sender wrote.
+            self.tail.set(self.channel.tail.0.load(Acquire));
+            n = self.tail.get().wrapping_sub(self.head.get());
+        }
+        n.min(wanted)
+    }
+
+    /// Called when the buffer looked empty.
+    fn empty_error(&self) -> TryRecvError {
+        if !self.channel.disconnected.load(Acquire) {
+            return TryRecvError::Empty;
+        }
+        // The sender might have sent more right before it was dropped.
+        if self.available(1) == 0 {
+            TryRecvError::Disconnected
+        } else {
+            TryRecvError::Empty
+        }
+    }
+
+    fn advance(&self, head: usize) {
+        self.channel.head.0.store(head, Release);
+        self.head.set(head);
+        self.channel.wake(&self.channel.sender_waiting);
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.disconnect(&self.channel.sender_waiting);
+    }
+}
+
+impl<T> Selectable for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        self.available(1) > 0 || self.is_disconnected()
+    }
+
+    fn register(&self, signal: &Arc<Signal>) {
+        let channel = &*self.channel;
+        channel.selectors.register(signal);
+        channel.receiver_waiting.store(SELECTING, Relaxed);
+        // Together with the fence in `wake`: either the sender sees
+        // SELECTING, or the `is_ready` that comes after this sees its message.
+        fence(SeqCst);
+    }
+
+    fn unregister(&self, signal: &Arc<Signal>) {
+        self.channel.receiver_waiting.store(0, Relaxed);
+        self.channel.selectors.unregister(signal);
+    }
+}
+
+impl<T> SelectRecv for Receiver<T> {
+    type Message = T;
+    type Error = RecvError;
+
+    fn try_select(&self) -> Option<Result<T, RecvError>> {
+        match self.try_recv() {
+            Ok(message) => Some(Ok(message)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(RecvError)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use stress::{Config, DropCounter, Stamp};
+
+    #[test]
+    fn test_bounded() {
+        // Not a power of two, so the buffer has an unused slot.
+        let (tx, rx) = channel(3);
+        for round in 0..10 {
+            tx.send(round).unwrap();
+            tx.try_send(round + 1).unwrap();
+            tx.try_send(round + 2).unwrap();
+            assert_eq!(tx.try_send(99), Err(TrySendError::Full(99)));
+            assert_eq!(rx.try_recv(), Ok(round));
+            assert_eq!(rx.recv(), Ok(round + 1));
+            assert_eq!(rx.recv(), Ok(round + 2));
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        }
+    }
+
+    #[test]
+    fn test_slices() {
+        let (tx, rx) = channel(8);
+        let mut buf = [0; 8];
+        // Start somewhere in the middle, so the copies have to wrap around.
+        assert_eq!(tx.push_slice(&[1, 2, 3, 4, 5]), 5);
+        assert_eq!(rx.pop_slice(&mut buf[..5]), 5);
+        assert_eq!(tx.push_slice(&[6, 7, 8, 9, 10, 11, 12, 13, 14, 15]), 8);
+        assert_eq!(tx.push_slice(&[0]), 0);
+        assert_eq!(rx.pop_slice(&mut buf[..3]), 3);
+        assert_eq!(buf[..3], [6, 7, 8]);
+        assert_eq!(tx.push_slice(&[14, 15, 16, 17]), 3);
+        assert_eq!(rx.pop_slice(&mut buf), 8);
+        assert_eq!(buf, [9, 10, 11, 12, 13, 14, 15, 16]);
+        assert_eq!(rx.pop_slice(&mut buf), 0);
+    }
+
+    #[test]
+    fn test_send_blocks_when_full() {
+        let (tx, rx) = channel(1);
+        tx.send(1).unwrap();
+        thread::scope(|s| {
+            let t = s.spawn(move || tx.send(2));
+            thread::sleep(Duration::from_millis(50));
+            assert!(!t.is_finished());
+            assert_eq!(rx.recv(), Ok(1));
+            t.join().unwrap().unwrap();
+        });
+        assert_eq!(rx.recv(), Ok(2));
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let (tx, rx) = channel(4);
+        tx.send(1).unwrap();
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                tx.send(2).unwrap();
+            });
+            // Messages sent before the disconnect are still received.
+            assert_eq!(rx.recv(), Ok(1));
+            assert_eq!(rx.recv(), Ok(2));
+            // Blocks until the sender is dropped.
+            assert_eq!(rx.recv(), Err(RecvError));
+        });
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+
+        let (tx, rx) = channel(1);
+        tx.send(1).unwrap();
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                drop(rx);
+            });
+            // Blocks because it's full, until the receiver is dropped.
+            assert_eq!(tx.send(2), Err(SendError(2)));
+        });
+        assert_eq!(tx.push_slice(&[3]), 0);
+    }
+
+    #[test]
+    fn test_drops_unreceived_messages() {
+        let counter = DropCounter::new();
+        let (tx, rx) = channel(2);
+        for _ in 0..5 {
+            tx.send(counter.track()).unwrap();
+            drop(rx.recv().unwrap());
+        }
+        tx.send(counter.track()).unwrap();
+        tx.send(counter.track()).unwrap();
+        drop(tx);
+        drop(rx);
+        assert_eq!(counter.alive(), 0);
+        assert_eq!(counter.created(), 7);
+    }
+
+    /// The receiver goes from a blocking `recv` straight into a `select!`,
+    /// while the sender might still be waking up that `recv`. That must not
+    /// undo the registration of the select, or it'd never wake up.
+    #[test]
+    fn test_wake_races_select() {
+        let rounds = Config::from_env().iterations as u64 / 10;
+        let (tx, rx) = channel(4);
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..rounds {
+                    tx.send(2 * i).unwrap();
+                    tx.send(2 * i + 1).unwrap();
+                    if i % 2 == 0 {
+                        thread::yield_now();
+                    }
+                }
+            });
+            for i in 0..rounds {
+                assert_eq!(rx.recv(), Ok(2 * i));
+                crate::select! {
+                    recv(rx) -> msg => assert_eq!(msg, Ok(2 * i + 1)),
+                }
+            }
+        });
+    }
+
+    /// One producer and one consumer over a tiny buffer, randomly mixing
+    /// single and batched operations. Every message arrives exactly once, in order.
+    #[test]
+    fn test_stress() {
+        let config = Config::from_env().threads(2);
+        let (tx, rx) = channel(5);
+        let (tx, rx) = (
+            &std::sync::Mutex::new(Some(tx)),
+            &std::sync::Mutex::new(Some(rx)),
+        );
+        let messages = config.iterations as u64;
+        stress::run(&config, |w| {
+            let mut buf = [Stamp::new(0); 4];
+            if w.index == 0 {
+                let tx = tx.lock().unwrap().take().unwrap();
+                let mut next = 0;
+                while next < messages {
+                    let n = (w.rng.below(buf.len()) + 1).min((messages - next) as usize);
+                    if n == 1 {
+                        tx.send(Stamp::new(next)).unwrap();
+                        next += 1;
+                        continue;
+                    }
+                    for (i, stamp) in buf[..n].iter_mut().enumerate() {
+                        *stamp = Stamp::new(next + i as u64);
+                    }
+                    let sent = tx.push_slice(&buf[..n]);
+                    next += sent as u64;
+                    if sent == 0 {
+                        thread::yield_now();
+                    }
+                }
+            } else {
+                let rx = rx.lock().unwrap().take().unwrap();
+                let mut next = 0;
+                loop {
+                    if w.rng.below(2) == 0 {
+                        match rx.recv() {
+                            Ok(stamp) => assert_eq!(stamp.value(), next),
+                            Err(RecvError) => break,
+                        }
+                        next += 1;
+                    } else {
+                        let n = rx.pop_slice(&mut buf);
+                        for stamp in &buf[..n] {
+                            assert_eq!(stamp.value(), next);
+                            next += 1;
+                        }
+                        if n == 0 {
+                            thread::yield_now();
+                        }
+                    }
+                }
+                assert_eq!(next, messages);
+            }
+        });
+    }
+}
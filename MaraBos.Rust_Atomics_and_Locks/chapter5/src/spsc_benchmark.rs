@@ -0,0 +1,108 @@
+// Throughput of the lock-free SPSC ring buffer (spsc.rs),
+// compared to the Mutex based channel from simple_mutex_based_channel.rs.
+//
+// Run with --release, and on a machine with at least two cores
+// for the numbers to mean anything.
+
+use chapter5::spsc;
+use simple_mutex_based_channel::Channel;
+use std::hint::black_box;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The very same Channel, rather than a copy. (Its main() goes unused here.)
+#[allow(dead_code)]
+#[path = "simple_mutex_based_channel.rs"]
+mod simple_mutex_based_channel;
+
+// main //
+
+const MESSAGES: u64 = 10_000_000;
+const CAPACITY: usize = 1024;
+const BATCH: usize = 256;
+
+fn report(name: &str, duration: Duration) {
+    let rate = MESSAGES as f64 / duration.as_secs_f64() / 1e6;
+    println!("{name:>24}: {duration:>12.3?} ({rate:.1} M messages/s)");
+}
+
+fn mutex_channel() -> Duration {
+    let chan = Channel::new();
+    let start = Instant::now();
+    thread::scope(|s| {
+        s.spawn(|| {
+            for i in 0..MESSAGES {
+                chan.send(i);
+            }
+        });
+        let mut sum = 0;
+        for _ in 0..MESSAGES {
+            sum += chan.receive();
+        }
+        black_box(sum);
+    });
+    start.elapsed()
+}
+
+fn spsc_blocking() -> Duration {
+    let (tx, rx) = spsc::channel(CAPACITY);
+    let start = Instant::now();
+    thread::scope(|s| {
+        s.spawn(move || {
+            for i in 0..MESSAGES {
+                tx.send(i).unwrap();
+            }
+        });
+        let mut sum = 0;
+        while let Ok(i) = rx.recv() {
+            sum += i;
+        }
+        black_box(sum);
+    });
+    start.elapsed()
+}
+
+fn spsc_slices() -> Duration {
+    let (tx, rx) = spsc::channel(CAPACITY);
+    let start = Instant::now();
+    thread::scope(|s| {
+        s.spawn(move || {
+            let mut buf = [0; BATCH];
+            let mut next = 0;
+            while next < MESSAGES {
+                for (i, x) in buf.iter_mut().enumerate() {
+                    *x = next + i as u64;
+                }
+                let n = (BATCH as u64).min(MESSAGES - next) as usize;
+                let mut sent = 0;
+                while sent < n {
+                    match tx.push_slice(&buf[sent..n]) {
+                        0 => thread::yield_now(),
+                        k => sent += k,
+                    }
+                }
+                next += n as u64;
+            }
+        });
+        let mut buf = [0; BATCH];
+        let mut received = 0;
+        let mut sum = 0;
+        while received < MESSAGES {
+            match rx.pop_slice(&mut buf) {
+                0 => thread::yield_now(),
+                n => {
+                    sum += buf[..n].iter().sum::<u64>();
+                    received += n as u64;
+                }
+            }
+        }
+        black_box(sum);
+    });
+    start.elapsed()
+}
+
+fn main() {
+    report("Mutex<VecDeque> channel", mutex_channel());
+    report("spsc send/recv", spsc_blocking());
+    report("spsc push/pop_slice", spsc_slices());
+}
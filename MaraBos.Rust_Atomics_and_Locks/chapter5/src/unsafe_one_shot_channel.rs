@@ -19,7 +19,9 @@ impl<T> Channel<T> {
         }
     }
 
-    /// Safety: Only call this once!
+    /// # Safety
+    ///
+    /// Only call this once!
     pub unsafe fn send(&self, message: T) {
         (*self.message.get()).write(message);
         self.ready.store(true, Ordering::Release);
@@ -29,13 +31,21 @@ impl<T> Channel<T> {
         self.ready.load(Ordering::Acquire)
     }
 
-    /// Safety: Only call this once,
+    /// # Safety
+    ///
+    /// Only call this once,
     /// and only after is_ready() returns true!
     pub unsafe fn receive(&self) -> T {
         (*self.message.get()).assume_init_read()
     }
 }
 
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 fn main() {
     let chan = Arc::new(Channel::new());
     thread::scope(|scope| {
@@ -0,0 +1,356 @@
+// Watch channel
+//
+// Not a queue, but a single value that the sender keeps replacing,
+// and that receivers can look at whenever they want: e.g. configuration.
+//
+// The value lives in the RWLock from chapter9, so receivers never see a
+// half-written value. Next to it is a version counter, incremented by
+// every update while the value is still write-locked, so that a reader
+// holding a read lock always sees the version that belongs to the value.
+// Every receiver remembers the last version it has seen, so `changed`
+// can tell if there was at least one update since (but not how many).
+//
+// The version is a u64, so it doesn't wrap around: with a u32, a receiver
+// that missed exactly 2^31 updates would think nothing had changed.
+// A futex needs a u32, so `changed` blocks on a separate `futex` counter
+// instead, which is incremented after every update (and on close).
+// That one can wrap around: it only needs to be different from what a
+// waiting receiver loaded right before it went to sleep.
+// Receivers can also be used with `Select` and `select!` (select.rs),
+// where they're ready once `changed` wouldn't block.
+
+use crate::select::{SelectRecv, Selectable, Signal, Signals};
+use atomic_wait::{wait, wake_all};
+use chapter9::{RWLock, ReadGuard};
+use std::fmt;
+use std::sync::atomic::{
+    AtomicU32, AtomicU64, AtomicUsize,
+    Ordering::{Acquire, Relaxed, SeqCst},
+};
+use std::sync::Arc;
+
+/// Set in `version` once the sender is gone. Updates add 2.
+const CLOSED: u64 = 1;
+
+struct Shared<T> {
+    value: RWLock<T>,
+    /// Twice the number of updates, plus CLOSED.
+    version: AtomicU64,
+    /// Incremented (wrapping around) after every change to `version`,
+    /// for receivers to wait on.
+    futex: AtomicU32,
+    /// Number of receivers blocked in `changed`.
+    num_waiters: AtomicUsize,
+    /// Threads selecting on a receiver.
+    selectors: Signals,
+}
+
+pub fn channel<T>(initial: T) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        value: RWLock::new(initial),
+        version: AtomicU64::new(0),
+        futex: AtomicU32::new(0),
+        num_waiters: AtomicUsize::new(0),
+        selectors: Signals::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        // The initial value counts as seen.
+        Receiver {
+            shared,
+            seen: AtomicU64::new(0),
+        },
+    )
+}
+
+impl<T> Shared<T> {
+    /// Call this after changing `version`.
+    fn wake_receivers(&self) {
+        // SeqCst, together with the SeqCst in `changed`, to make sure that
+        // either we see the waiter, or the waiter sees the new futex value.
+        self.futex.fetch_add(1, SeqCst);
+        if self.num_waiters.load(SeqCst) > 0 {
+            wake_all(&self.futex);
+        }
+        self.selectors.notify();
+    }
+}
+
+// Sender //
+
+/// Not `Clone`: there's only one.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Replaces the value, and notifies all receivers.
+    /// The old value is dropped.
+    pub fn send(&self, value: T) {
+        self.send_modify(|v| *v = value);
+    }
+
+    /// Modifies the value in place, and notifies all receivers.
+    pub fn send_modify(&self, f: impl FnOnce(&mut T)) {
+        let mut guard = self.shared.value.write();
+        f(&mut guard);
+        // While still locked, so nobody sees the new value with the old version.
+        self.shared.version.fetch_add(2, SeqCst);
+        drop(guard);
+        self.shared.wake_receivers();
+    }
+
+    pub fn borrow(&self) -> ReadGuard<'_, T> {
+        self.shared.value.read()
+    }
+
+    /// A new receiver, which has seen the current value.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let guard = self.shared.value.read();
+        let seen = self.shared.version.load(Relaxed) & !CLOSED;
+        drop(guard);
+        Receiver {
+            shared: self.shared.clone(),
+            seen: AtomicU64::new(seen),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.version.fetch_or(CLOSED, SeqCst);
+        self.shared.wake_receivers();
+    }
+}
+
+// Receiver //
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    /// The last version this receiver has seen, without the CLOSED bit.
+    ///
+    /// Only atomic so that `select!` can mark a version as seen through a
+    /// shared reference, without making the receiver !Sync. Only one thread
+    /// at a time does that: everything else takes `&mut self`.
+    seen: AtomicU64,
+}
+
+impl<T> Receiver<T> {
+    /// The latest value, without marking it as seen.
+    ///
+    /// Keep the guard short-lived: the sender can't update while it exists.
+    pub fn borrow(&self) -> ReadGuard<'_, T> {
+        self.shared.value.read()
+    }
+
+    /// The latest value, and marks it as seen.
+    pub fn borrow_and_update(&mut self) -> ReadGuard<'_, T> {
+        let guard = self.shared.value.read();
+        // The version can't change while we hold the read lock.
+        let version = self.shared.version.load(Acquire) & !CLOSED;
+        self.seen.store(version, Relaxed);
+        guard
+    }
+
+    /// True if there was an update that this receiver hasn't seen yet.
+    pub fn has_changed(&self) -> bool {
+        self.shared.version.load(Acquire) & !CLOSED != self.seen.load(Relaxed)
+    }
+
+    /// Blocks until there's a version this receiver hasn't seen yet,
+    /// and marks it as seen. Use `borrow` to look at it afterwards.
+    ///
+    /// Fails if the sender is gone, and there was no unseen update.
+    pub fn changed(&mut self) -> Result<(), RecvError> {
+        loop {
+            // Before looking at the version: if it changes after that,
+            // so does the futex, and `wait` returns right away.
+            let futex = self.shared.futex.load(SeqCst);
+            let version = self.shared.version.load(SeqCst);
+            if let Some(result) = self.check(version) {
+                return result;
+            }
+            self.shared.num_waiters.fetch_add(1, SeqCst);
+            wait(&self.shared.futex, futex);
+            self.shared.num_waiters.fetch_sub(1, Relaxed);
+        }
+    }
+
+    /// None if `changed` would have to wait. Otherwise, marks `version` as seen.
+    fn check(&self, version: u64) -> Option<Result<(), RecvError>> {
+        if version & !CLOSED != self.seen.load(Relaxed) {
+            self.seen.store(version & !CLOSED, Relaxed);
+            Some(Ok(()))
+        } else if version & CLOSED != 0 {
+            Some(Err(RecvError))
+        } else {
+            None
+        }
+    }
+}
+
+/// Clones have seen the same versions.
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            seen: AtomicU64::new(self.seen.load(Relaxed)),
+        }
+    }
+}
+
+impl<T> Selectable for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        let version = self.shared.version.load(Acquire);
+        version & !CLOSED != self.seen.load(Relaxed) || version & CLOSED != 0
+    }
+
+    fn register(&self, signal: &Arc<Signal>) {
+        self.shared.selectors.register(signal);
+    }
+
+    fn unregister(&self, signal: &Arc<Signal>) {
+        self.shared.selectors.unregister(signal);
+    }
+}
+
+/// The message is just that there was a change. Use `borrow` to see it.
+impl<T> SelectRecv for Receiver<T> {
+    type Message = ();
+    type Error = RecvError;
+
+    fn try_select(&self) -> Option<Result<(), RecvError>> {
+        self.check(self.shared.version.load(Acquire))
+    }
+}
+
+// Errors //
+
+/// The sender is gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("watch sender dropped")
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use stress::{Config, Stamp};
+
+    #[test]
+    fn test_borrow_and_changed() {
+        let (tx, mut rx) = channel("a");
+        assert_eq!(*rx.borrow(), "a");
+        assert!(!rx.has_changed());
+        tx.send("b");
+        tx.send("c");
+        assert!(rx.has_changed());
+        // Two updates, but only one change to see.
+        rx.changed().unwrap();
+        assert!(!rx.has_changed());
+        assert_eq!(*rx.borrow(), "c");
+
+        let mut rx2 = tx.subscribe();
+        assert!(!rx2.has_changed());
+        tx.send_modify(|v| *v = "d");
+        assert_eq!(*rx2.borrow_and_update(), "d");
+        assert!(!rx2.has_changed());
+        assert!(rx.has_changed());
+    }
+
+    #[test]
+    fn test_changed_blocks() {
+        let (tx, mut rx) = channel(0);
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                tx.send(1);
+                thread::sleep(Duration::from_millis(10));
+                drop(tx);
+            });
+            rx.changed().unwrap();
+            assert_eq!(*rx.borrow(), 1);
+            assert_eq!(rx.changed(), Err(RecvError));
+        });
+    }
+
+    #[test]
+    fn test_unseen_update_before_close() {
+        let (tx, mut rx) = channel(0);
+        tx.send(1);
+        drop(tx);
+        assert_eq!(rx.changed(), Ok(()));
+        assert_eq!(*rx.borrow(), 1);
+        assert_eq!(rx.changed(), Err(RecvError));
+    }
+
+    /// A u32 version would be back where it started after 2^31 updates,
+    /// and the futex counter does wrap around.
+    #[test]
+    fn test_wrap_around() {
+        let (tx, mut rx) = channel(0);
+        // As if 2^31 updates had happened, without the value changing.
+        tx.shared.version.fetch_add(2 << 31, Relaxed);
+        assert!(rx.has_changed());
+        rx.changed().unwrap();
+        assert!(!rx.has_changed());
+
+        tx.shared.futex.store(u32::MAX, Relaxed);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                tx.send(1);
+            });
+            rx.changed().unwrap();
+        });
+        assert_eq!(tx.shared.futex.load(Relaxed), 0);
+        assert_eq!(*rx.borrow(), 1);
+    }
+
+    /// One thread keeps updating, the others keep watching.
+    /// Nobody sees a torn value, values only go up, and every receiver
+    /// ends up seeing the final value.
+    #[test]
+    fn test_stress() {
+        let config = Config::from_env();
+        let (tx, rx) = channel(Stamp::new(0));
+        let tx = std::sync::Mutex::new(Some(tx));
+        let updates = config.iterations as u64;
+        stress::run(&config, |w| {
+            if w.index == 0 {
+                let tx = tx.lock().unwrap().take().unwrap();
+                for _ in 0..updates {
+                    tx.send_modify(|v| *v = v.next());
+                    if w.rng.below(8) == 0 {
+                        thread::yield_now();
+                    }
+                }
+                return;
+            }
+            let mut rx = rx.clone();
+            let mut last = 0;
+            loop {
+                let value = match w.rng.below(2) {
+                    0 => rx.borrow().value(),
+                    _ => rx.borrow_and_update().value(),
+                };
+                assert!(value >= last, "went back in time");
+                last = value;
+                if rx.changed().is_err() {
+                    break;
+                }
+            }
+            assert_eq!(rx.borrow().value(), updates);
+        });
+    }
+}
@@ -0,0 +1,467 @@
+// AtomicArc
+//
+// A cell holding an `Arc<T>`, e.g. configuration that many threads read all
+// the time, and that's replaced as a whole once in a while.
+//
+// The cell owns one strong reference to whatever it points to. Loading can't
+// simply increment the strong count of the pointer it loaded: by the time it
+// gets to do that, a writer might have swapped it out and dropped the last
+// reference. So a reader first publishes the pointer in a "debt" slot,
+// and then checks that the cell still points to it. A writer, after swapping
+// the pointer out (and before letting go of its reference), looks through all
+// debt slots, and pays every debt on the old pointer: it increments the
+// strong count on behalf of the reader, and clears the slot. A reader that
+// finds its slot cleared knows it now owns a reference, and drops it when done.
+//
+// This way, a `Guard` doesn't have to touch the strong count at all, unless
+// a writer came along in the meantime. `load` is a `Guard` plus a `clone`.
+//
+// Every thread owns a few debt slots. Only the owner puts pointers in them,
+// so a slot cleared by a writer is never filled by another reader in between,
+// which would make this reader's "is my debt still there?" check lie.
+// The slots live in a global linked list of nodes that's never freed.
+// Nodes of threads that exit are reused by new threads.
+
+use crate::Arc;
+use std::cell::RefCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::ptr;
+use std::sync::atomic::{
+    AtomicBool, AtomicPtr, AtomicU8, AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release, SeqCst},
+};
+
+// Debts //
+
+const SLOTS_PER_NODE: usize = 8;
+
+struct DebtNode {
+    /// The owning thread keeps the node until it exits.
+    in_use: AtomicBool,
+    /// Bit i is set while slot i belongs to a `Guard`,
+    /// even if the debt was paid in the meantime.
+    reserved: AtomicU8,
+    /// Addresses of data that a `Guard` uses without owning a strong count,
+    /// or 0.
+    slots: [AtomicUsize; SLOTS_PER_NODE],
+    next: *const DebtNode,
+}
+
+// Safety: `next` is never changed once the node is in the list.
+unsafe impl Sync for DebtNode {}
+
+/// The list of all nodes ever created. Only grows.
+static NODES: AtomicPtr<DebtNode> = AtomicPtr::new(ptr::null_mut());
+
+impl DebtNode {
+    fn all() -> impl Iterator<Item = &'static DebtNode> {
+        let mut p = NODES.load(Acquire) as *const DebtNode;
+        std::iter::from_fn(move || {
+            // Safety: nodes are never freed.
+            let node = unsafe { p.as_ref()? };
+            p = node.next;
+            Some(node)
+        })
+    }
+
+    /// Reuses a node of a thread that exited, or adds a new one to the list.
+    fn claim() -> &'static DebtNode {
+        for node in Self::all() {
+            if !node.in_use.load(Relaxed)
+                && node
+                    .in_use
+                    .compare_exchange(false, true, Acquire, Relaxed)
+                    .is_ok()
+            {
+                return node;
+            }
+        }
+        let node = Box::leak(Box::new(DebtNode {
+            in_use: AtomicBool::new(true),
+            reserved: AtomicU8::new(0),
+            slots: Default::default(),
+            next: ptr::null(),
+        }));
+        let mut head = NODES.load(Relaxed);
+        loop {
+            node.next = head;
+            match NODES.compare_exchange_weak(head, node, Release, Relaxed) {
+                Ok(_) => return node,
+                Err(e) => head = e,
+            }
+        }
+    }
+
+    /// Reserves a free slot, if this node has one.
+    /// Only called by the owning thread.
+    fn reserve(&self) -> Option<usize> {
+        let reserved = self.reserved.load(Relaxed);
+        let i = reserved.trailing_ones() as usize;
+        if i == SLOTS_PER_NODE {
+            return None;
+        }
+        // Only the owner sets bits, so nobody else can take it in the meantime.
+        self.reserved.fetch_or(1 << i, Relaxed);
+        Some(i)
+    }
+}
+
+/// The debt nodes of the current thread.
+struct LocalNodes(RefCell<Vec<&'static DebtNode>>);
+
+impl Drop for LocalNodes {
+    fn drop(&mut self) {
+        // Slots reserved by guards that are still around stay reserved,
+        // and the next owner will skip them.
+        for node in self.0.get_mut() {
+            node.in_use.store(false, Release);
+        }
+    }
+}
+
+thread_local! {
+    static LOCAL_NODES: LocalNodes = const { LocalNodes(RefCell::new(Vec::new())) };
+}
+
+/// Reserves a slot in one of the current thread's nodes.
+fn reserve_slot() -> (&'static DebtNode, usize) {
+    LOCAL_NODES.with(|local| {
+        let mut nodes = local.0.borrow_mut();
+        for &node in nodes.iter() {
+            if let Some(i) = node.reserve() {
+                return (node, i);
+            }
+        }
+        // Only happens with more than `SLOTS_PER_NODE` guards alive at once.
+        let node = DebtNode::claim();
+        nodes.push(node);
+        (node, node.reserve().expect("claimed node has no free slot"))
+    })
+}
+
+/// Gives every reader with a debt on `data` its own strong reference.
+///
+/// The caller must own a strong reference to `data`,
+/// and must have already made sure no new debts on it can come in.
+fn pay_debts<T>(data: *const T) {
+    for node in DebtNode::all() {
+        for slot in &node.slots {
+            if slot.load(SeqCst) != data as usize {
+                continue;
+            }
+            // Safety: the caller keeps it alive.
+            let arc = ManuallyDrop::new(unsafe { Arc::from_raw(data) });
+            let paid = ManuallyDrop::new(Arc::clone(&arc));
+            if slot
+                .compare_exchange(data as usize, 0, SeqCst, Relaxed)
+                .is_err()
+            {
+                // The reader was done already.
+                drop(ManuallyDrop::into_inner(paid));
+            }
+        }
+    }
+}
+
+// AtomicArc //
+
+pub struct AtomicArc<T> {
+    /// From `Arc::into_raw`. Never null.
+    ptr: AtomicPtr<T>,
+}
+
+unsafe impl<T: Send + Sync> Send for AtomicArc<T> {}
+unsafe impl<T: Send + Sync> Sync for AtomicArc<T> {}
+
+impl<T> AtomicArc<T> {
+    pub fn new(arc: Arc<T>) -> Self {
+        Self {
+            ptr: AtomicPtr::new(Arc::into_raw(arc) as *mut T),
+        }
+    }
+
+    /// A temporary reference to the current value,
+    /// without touching the reference counter (most of the time).
+    ///
+    /// Meant to be short-lived: every guard occupies one of the thread's
+    /// debt slots, and every writer has to look at all of them.
+    pub fn load_guard(&self) -> Guard<'_, T> {
+        let (node, i) = reserve_slot();
+        let slot = &node.slots[i];
+        loop {
+            let p = self.ptr.load(Acquire);
+            slot.store(p as usize, SeqCst);
+            // SeqCst, together with the SeqCst swap and slot loads of the
+            // writer: if we still see `p` here, the writer will see our debt.
+            if self.ptr.load(SeqCst) == p {
+                return Guard {
+                    arc: ManuallyDrop::new(unsafe { Arc::from_raw(p) }),
+                    node,
+                    slot: i,
+                    _cell: PhantomData,
+                };
+            }
+            // Swapped out in the meantime. Take the debt back,
+            // unless it was paid, in which case we own a reference now.
+            if slot
+                .compare_exchange(p as usize, 0, SeqCst, Relaxed)
+                .is_err()
+            {
+                drop(unsafe { Arc::from_raw(p) });
+            }
+        }
+    }
+
+    /// The current value.
+    pub fn load(&self) -> Arc<T> {
+        Arc::clone(&self.load_guard())
+    }
+
+    pub fn store(&self, new: Arc<T>) {
+        drop(self.swap(new));
+    }
+
+    /// Replaces the value, and returns the old one.
+    pub fn swap(&self, new: Arc<T>) -> Arc<T> {
+        let old = self.ptr.swap(Arc::into_raw(new) as *mut T, SeqCst);
+        pay_debts(old);
+        // Safety: the reference that was owned by the cell.
+        unsafe { Arc::from_raw(old) }
+    }
+
+    /// Replaces the value with `new` if it's still `current` (the same
+    /// allocation, not just an equal value), and returns the old one.
+    /// Otherwise, gives `new` back.
+    ///
+    /// `current` can be a `&Guard`, as returned by `load_guard`.
+    pub fn compare_and_swap(&self, current: &Arc<T>, new: Arc<T>) -> Result<Arc<T>, Arc<T>> {
+        let current = &**current as *const T as *mut T;
+        let new = Arc::into_raw(new) as *mut T;
+        match self.ptr.compare_exchange(current, new, SeqCst, Relaxed) {
+            Ok(old) => {
+                pay_debts(old);
+                Ok(unsafe { Arc::from_raw(old) })
+            }
+            Err(_) => Err(unsafe { Arc::from_raw(new) }),
+        }
+    }
+
+    /// Replaces the value with `f(current value)`, retrying if another thread
+    /// replaced it in the meantime. Returns the old value.
+    pub fn rcu(&self, mut f: impl FnMut(&T) -> T) -> Arc<T> {
+        let mut current = self.load();
+        loop {
+            match self.compare_and_swap(&current, Arc::new(f(&current))) {
+                Ok(old) => return old,
+                Err(_) => current = self.load(),
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> Arc<T> {
+        let this = ManuallyDrop::new(self);
+        // No guards left, since they borrow the cell.
+        unsafe { Arc::from_raw(this.ptr.load(Relaxed)) }
+    }
+}
+
+impl<T> Drop for AtomicArc<T> {
+    fn drop(&mut self) {
+        // No guards left, since they borrow the cell.
+        drop(unsafe { Arc::from_raw(*self.ptr.get_mut()) });
+    }
+}
+
+impl<T> From<Arc<T>> for AtomicArc<T> {
+    fn from(arc: Arc<T>) -> Self {
+        Self::new(arc)
+    }
+}
+
+impl<T: Default> Default for AtomicArc<T> {
+    fn default() -> Self {
+        Self::new(Arc::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for AtomicArc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("AtomicArc")
+            .field(&**self.load_guard())
+            .finish()
+    }
+}
+
+// Guard //
+
+/// A reference to the value an `AtomicArc` had when it was loaded,
+/// which stays valid even if the cell is changed in the meantime.
+///
+/// Derefs to `Arc<T>`, so it can be cloned into an owned `Arc`.
+pub struct Guard<'a, T> {
+    /// Doesn't own a strong count, unless our debt was paid.
+    arc: ManuallyDrop<Arc<T>>,
+    node: &'static DebtNode,
+    slot: usize,
+    _cell: PhantomData<&'a AtomicArc<T>>,
+}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = Arc<T>;
+
+    fn deref(&self) -> &Arc<T> {
+        &self.arc
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        let data = &**self.arc as *const T as usize;
+        if self.node.slots[self.slot]
+            .compare_exchange(data, 0, SeqCst, Relaxed)
+            .is_err()
+        {
+            // A writer paid our debt, so we own a reference.
+            unsafe { ManuallyDrop::drop(&mut self.arc) };
+        }
+        self.node.reserved.fetch_and(!(1 << self.slot), Relaxed);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Guard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use stress::{Config, DropCounter, Mix, Stamp, Tracked};
+
+    #[test]
+    fn test_load_store_swap() {
+        let cell = AtomicArc::new(Arc::new(1));
+        assert_eq!(*cell.load(), 1);
+        cell.store(Arc::new(2));
+        let old = cell.swap(Arc::new(3));
+        assert_eq!(*old, 2);
+        assert_eq!(Arc::strong_count(&old), 1);
+
+        let current = cell.load();
+        // Equal value, but not the same allocation.
+        let rejected = cell.compare_and_swap(&Arc::new(3), Arc::new(4));
+        assert_eq!(*rejected.unwrap_err(), 4);
+        let prev = cell.compare_and_swap(&current, Arc::new(4)).unwrap();
+        assert!(Arc::ptr_eq(&prev, &current));
+        assert_eq!(*cell.rcu(|v| v + 1), 4);
+        assert_eq!(*cell.into_inner(), 5);
+    }
+
+    #[test]
+    fn test_guard_outlives_value() {
+        let counter = DropCounter::new();
+        let cell = AtomicArc::new(Arc::new(counter.track()));
+        let guard = cell.load_guard();
+        // The guard didn't touch the reference counter.
+        assert_eq!(Arc::strong_count(&guard), 1);
+        cell.store(Arc::new(counter.track()));
+        // But the store paid its debt.
+        assert_eq!(Arc::strong_count(&guard), 1);
+        assert_eq!(counter.alive(), 2);
+        drop(guard);
+        assert_eq!(counter.alive(), 1);
+
+        // Guards can be used with compare_and_swap.
+        let guard = cell.load_guard();
+        assert!(cell
+            .compare_and_swap(&guard, Arc::new(counter.track()))
+            .is_ok());
+        drop(guard);
+        drop(cell);
+        assert_eq!(counter.alive(), 0);
+    }
+
+    #[test]
+    fn test_many_guards() {
+        let cells: Vec<_> = (0..3 * SLOTS_PER_NODE)
+            .map(|i| AtomicArc::new(Arc::new(i)))
+            .collect();
+        // More guards than fit in one node.
+        let guards: Vec<_> = cells.iter().map(|c| c.load_guard()).collect();
+        for (i, cell) in cells.iter().enumerate() {
+            cell.store(Arc::new(i + 100));
+        }
+        for (i, guard) in guards.iter().enumerate() {
+            assert_eq!(***guard, i);
+        }
+        drop(guards);
+        for (i, cell) in cells.into_iter().enumerate() {
+            assert_eq!(Arc::strong_count(&cell.load()), 2);
+            assert_eq!(*cell.into_inner(), i + 100);
+        }
+    }
+
+    /// Readers keep loading while writers keep replacing the value.
+    /// Nobody sees a freed or torn value, values only go up,
+    /// and nothing leaks.
+    #[test]
+    fn test_stress() {
+        #[derive(Clone, Copy)]
+        enum Op {
+            Guard,
+            Load,
+            Store,
+            Cas,
+        }
+
+        let counter = DropCounter::new();
+        let cell: AtomicArc<(Stamp, Tracked)> =
+            AtomicArc::new(Arc::new((Stamp::new(0), counter.track())));
+        let mix = Mix::new(&[(Op::Guard, 4), (Op::Load, 2), (Op::Store, 1), (Op::Cas, 1)]);
+
+        stress::run(&Config::from_env(), |w| {
+            let mut held = Vec::new();
+            let mut last = 0;
+            for _ in 0..w.iterations {
+                match mix.pick(&mut w.rng) {
+                    Op::Guard => {
+                        let guard = cell.load_guard();
+                        let value = guard.0.value();
+                        assert!(value >= last, "went back in time");
+                        last = value;
+                        if w.rng.below(4) == 0 {
+                            thread::yield_now();
+                        }
+                        assert_eq!(guard.0.value(), value);
+                    }
+                    Op::Load => {
+                        held.push(cell.load());
+                        if held.len() > 4 {
+                            held.remove(0);
+                        }
+                    }
+                    Op::Store => {
+                        // Only ever goes up, through rcu.
+                        cell.rcu(|v| (v.0.next(), counter.track()));
+                    }
+                    Op::Cas => {
+                        let guard = cell.load_guard();
+                        let new = Arc::new((guard.0.next(), counter.track()));
+                        let _ = cell.compare_and_swap(&guard, new);
+                    }
+                }
+            }
+            for arc in held {
+                assert!(arc.0.is_intact());
+            }
+        });
+
+        drop(cell);
+        assert_eq!(counter.alive(), 0);
+    }
+}
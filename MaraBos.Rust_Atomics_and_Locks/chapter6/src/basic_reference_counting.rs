@@ -4,6 +4,8 @@ use std::sync::atomic::{
     fence, AtomicUsize,
     Ordering::{Acquire, Relaxed, Release},
 };
+#[cfg(test)]
+use stress::{Config, DropCounter, Mix, Stamp};
 
 struct ArcData<T> {
     ref_count: AtomicUsize,
@@ -94,6 +96,39 @@ fn test() {
     assert_eq!(NUM_DROPS.load(Relaxed), 1);
 }
 
+#[test]
+fn test_stress() {
+    #[derive(Clone, Copy)]
+    enum Op {
+        Clone,
+        Drop,
+        Read,
+    }
+
+    let counter = DropCounter::new();
+    let arc = Arc::new((Stamp::new(42), counter.track()));
+    let mix = Mix::new(&[(Op::Clone, 3), (Op::Drop, 3), (Op::Read, 2)]);
+
+    stress::run(&Config::from_env(), |w| {
+        let mut arcs = Vec::new();
+        for _ in 0..w.iterations {
+            match mix.pick(&mut w.rng) {
+                Op::Clone => arcs.push(arc.clone()),
+                Op::Drop => drop(arcs.pop()),
+                Op::Read => {
+                    if let Some(a) = arcs.last() {
+                        assert_eq!(a.0.value(), 42);
+                    }
+                }
+            }
+        }
+    });
+
+    assert_eq!(counter.alive(), 1);
+    drop(arc);
+    assert_eq!(counter.alive(), 0);
+}
+
 fn main() {
     static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
 
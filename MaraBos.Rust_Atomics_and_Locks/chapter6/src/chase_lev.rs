@@ -0,0 +1,386 @@
+// Chase–Lev work-stealing deque
+//
+// A double-ended queue with one owner and any number of stealers. The owner
+// pushes and pops at the back (so it's a stack to the owner, which is best
+// for caches), while other threads steal from the front, taking the oldest
+// items, which in a thread pool tend to be the biggest pieces of work.
+//
+// It's a growable ring buffer with two counters: `front` (only ever
+// incremented, by whoever takes an item from the front) and `back` (only
+// ever written by the owner). The owner and stealers only ever fight over
+// the very last item, which they settle with a compare-and-exchange on
+// `front`, just like the stealers do amongst themselves.
+//
+// When the buffer is full, the owner copies everything into one twice as
+// big. Stealers might still be reading the old one, so it's freed through
+// epoch.rs.
+//
+// Based on "Correct and Efficient Work-Stealing for Weak Memory Models"
+// by Lê, Pop, Cohen and Zappa Nardelli (2013), which has the orderings.
+
+use crate::epoch::{self, Atomic, Owned};
+use crate::Arc;
+use std::cell::{Cell, UnsafeCell};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{
+    fence, AtomicIsize,
+    Ordering::{Acquire, Relaxed, Release, SeqCst},
+};
+
+const MIN_CAPACITY: usize = 16;
+
+struct Buffer<T> {
+    /// The length is a power of two. Dropping this doesn't drop the items.
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+}
+
+impl<T> Buffer<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity)
+                .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+                .collect(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn slot(&self, index: isize) -> *mut MaybeUninit<T> {
+        self.slots[index as usize & (self.capacity() - 1)].get()
+    }
+
+    unsafe fn write(&self, index: isize, value: MaybeUninit<T>) {
+        ptr::write(self.slot(index), value);
+    }
+
+    /// A stealer might read a slot while the owner is overwriting it, if the
+    /// item was taken by someone else in the meantime and the slot reused.
+    /// It'll find out when its compare-and-exchange fails, and never look at
+    /// what it read. The volatile read keeps the compiler from doing
+    /// anything clever with it until then.
+    unsafe fn read(&self, index: isize) -> MaybeUninit<T> {
+        ptr::read_volatile(self.slot(index))
+    }
+}
+
+struct Inner<T> {
+    /// Where stealers take items from.
+    front: AtomicIsize,
+    /// Where the owner pushes and pops. Only written by the owner.
+    back: AtomicIsize,
+    buffer: Atomic<Buffer<T>>,
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        // Safety: The owner and all stealers are gone.
+        unsafe {
+            let guard = epoch::unprotected();
+            let buffer = self.buffer.load(Relaxed, guard);
+            let front = self.front.load(Relaxed);
+            let back = self.back.load(Relaxed);
+            for i in front..back {
+                buffer.deref().read(i).assume_init_drop();
+            }
+            drop(buffer.into_owned());
+        }
+    }
+}
+
+/// The result of `Stealer::steal`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Steal<T> {
+    Empty,
+    Success(T),
+    /// Lost a race with another thread. Might not be empty.
+    Retry,
+}
+
+impl<T> Steal<T> {
+    pub fn success(self) -> Option<T> {
+        match self {
+            Steal::Success(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+// Worker //
+
+/// The owner's side of the deque. Can be sent to another thread, but not shared.
+pub struct Worker<T> {
+    inner: Arc<Inner<T>>,
+    /// The same as `inner.buffer`, which only we change.
+    buffer: Cell<*const Buffer<T>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+unsafe impl<T: Send> Send for Worker<T> {}
+
+impl<T> Worker<T> {
+    pub fn new() -> Self {
+        let buffer = Owned::new(Buffer::new(MIN_CAPACITY));
+        let buffer_ptr = &*buffer as *const Buffer<T>;
+        Self {
+            inner: Arc::new(Inner {
+                front: AtomicIsize::new(0),
+                back: AtomicIsize::new(0),
+                buffer: Atomic::from(buffer),
+            }),
+            buffer: Cell::new(buffer_ptr),
+            _not_sync: PhantomData,
+        }
+    }
+
+    pub fn stealer(&self) -> Stealer<T> {
+        Stealer {
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        let back = self.inner.back.load(Relaxed);
+        let front = self.inner.front.load(Acquire);
+        let mut buffer = self.buffer.get();
+        // Safety: Only we replace the buffer.
+        let capacity = unsafe { (*buffer).capacity() };
+        if back - front >= capacity as isize {
+            buffer = self.grow(front, back, capacity * 2);
+        }
+        unsafe { (*buffer).write(back, MaybeUninit::new(value)) };
+        // Release: whoever takes it sees the value.
+        self.inner.back.store(back + 1, Release);
+    }
+
+    #[cold]
+    fn grow(&self, front: isize, back: isize, capacity: usize) -> *const Buffer<T> {
+        let old = self.buffer.get();
+        let new = Buffer::new(capacity);
+        for i in front..back {
+            // Safety: Stealers can only take these, not change them.
+            unsafe { new.write(i, (*old).read(i)) };
+        }
+        let guard = &epoch::pin();
+        let new = Owned::new(new).into_shared(guard);
+        // Release: stealers that see the new buffer see its contents.
+        let old = self.inner.buffer.swap(new, Release, guard);
+        self.buffer.set(new.as_raw());
+        // Safety: Nobody can load the old one anymore, and the items
+        // in it are also in the new one, so only free the buffer itself.
+        unsafe { guard.defer_destroy(old) };
+        new.as_raw()
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let back = self.inner.back.load(Relaxed) - 1;
+        // Claim the last item, before checking if a stealer got there first.
+        self.inner.back.store(back, Relaxed);
+        fence(SeqCst);
+        let front = self.inner.front.load(Relaxed);
+        if front > back {
+            // It was empty.
+            self.inner.back.store(back + 1, Relaxed);
+            return None;
+        }
+        let value = unsafe { (*self.buffer.get()).read(back) };
+        if front == back {
+            // The last item. Stealers might be after it too,
+            // so take it the way they do.
+            let won = self
+                .inner
+                .front
+                .compare_exchange(front, front + 1, SeqCst, Relaxed)
+                .is_ok();
+            self.inner.back.store(back + 1, Relaxed);
+            if !won {
+                return None;
+            }
+        }
+        // Safety: It's ours now.
+        Some(unsafe { value.assume_init() })
+    }
+
+    pub fn len(&self) -> usize {
+        let back = self.inner.back.load(Relaxed);
+        let front = self.inner.front.load(Relaxed);
+        (back - front).max(0) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Default for Worker<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for Worker<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Worker").finish_non_exhaustive()
+    }
+}
+
+// Stealer //
+
+pub struct Stealer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+unsafe impl<T: Send> Send for Stealer<T> {}
+unsafe impl<T: Send> Sync for Stealer<T> {}
+
+impl<T> Stealer<T> {
+    pub fn steal(&self) -> Steal<T> {
+        let front = self.inner.front.load(Acquire);
+        // Pinning includes a SeqCst fence, which pairs with the one in `pop`:
+        // either the owner sees our `front`, or we see its `back`.
+        let guard = &epoch::pin();
+        fence(SeqCst);
+        let back = self.inner.back.load(Acquire);
+        if back - front <= 0 {
+            return Steal::Empty;
+        }
+        let buffer = self.inner.buffer.load(Acquire, guard);
+        // Safety: We're pinned, so even an old buffer is still there.
+        let value = unsafe { buffer.deref().read(front) };
+        if self
+            .inner
+            .front
+            .compare_exchange(front, front + 1, SeqCst, Relaxed)
+            .is_err()
+        {
+            return Steal::Retry;
+        }
+        Steal::Success(unsafe { value.assume_init() })
+    }
+
+    /// Might be outdated by the time you look at it.
+    pub fn is_empty(&self) -> bool {
+        let front = self.inner.front.load(Acquire);
+        let back = self.inner.back.load(Acquire);
+        back <= front
+    }
+}
+
+impl<T> Clone for Stealer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Stealer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stealer").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use stress::{Config, DropCounter};
+
+    #[test]
+    fn test_push_pop_steal() {
+        let worker = Worker::new();
+        let stealer = worker.stealer();
+        assert_eq!(stealer.steal(), Steal::Empty);
+        for i in 0..100 {
+            worker.push(i);
+        }
+        assert_eq!(worker.len(), 100);
+        // The owner takes the newest, stealers the oldest.
+        assert_eq!(worker.pop(), Some(99));
+        assert_eq!(stealer.steal(), Steal::Success(0));
+        assert_eq!(stealer.clone().steal(), Steal::Success(1));
+        for i in (2..99).rev() {
+            assert_eq!(worker.pop(), Some(i));
+        }
+        assert_eq!(worker.pop(), None);
+        assert_eq!(stealer.steal(), Steal::Empty);
+        assert!(worker.is_empty() && stealer.is_empty());
+    }
+
+    #[test]
+    fn test_drop() {
+        let counter = DropCounter::new();
+        let worker = Worker::new();
+        let stealer = worker.stealer();
+        for _ in 0..50 {
+            worker.push(counter.track());
+        }
+        drop(stealer.steal());
+        drop(worker.pop());
+        assert_eq!(counter.alive(), 48);
+        drop(worker);
+        assert_eq!(counter.alive(), 48);
+        drop(stealer);
+        assert_eq!(counter.alive(), 0);
+    }
+
+    /// The owner pushes and pops at random, while the other threads steal.
+    /// Every item is taken exactly once.
+    #[test]
+    fn test_stress() {
+        let config = Config::from_env();
+        let counter = DropCounter::new();
+        let worker = Worker::new();
+        let stealer = worker.stealer();
+        let done = AtomicBool::new(false);
+        let (mut pushed, mut owner_sum, mut taken) = (0u64, 0u64, 0u64);
+        let stolen = thread::scope(|s| {
+            let stealers: Vec<_> = (1..config.threads.max(2))
+                .map(|_| {
+                    s.spawn(|| {
+                        let (mut sum, mut count) = (0u64, 0u64);
+                        loop {
+                            let finished = done.load(Acquire);
+                            match stealer.steal() {
+                                Steal::Success((i, _)) => {
+                                    sum += i;
+                                    count += 1;
+                                }
+                                Steal::Retry => {}
+                                Steal::Empty if finished => return (sum, count),
+                                Steal::Empty => thread::yield_now(),
+                            }
+                        }
+                    })
+                })
+                .collect();
+            let mut rng = stress::Rng::new(config.seed);
+            for _ in 0..config.iterations * 4 {
+                if rng.below(3) != 0 {
+                    worker.push((pushed, counter.track()));
+                    pushed += 1;
+                } else if let Some((i, _)) = worker.pop() {
+                    owner_sum += i;
+                    taken += 1;
+                }
+            }
+            done.store(true, Release);
+            stealers
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .fold((0, 0), |a, b| (a.0 + b.0, a.1 + b.1))
+        });
+        while let Some((i, _)) = worker.pop() {
+            owner_sum += i;
+            taken += 1;
+        }
+        assert_eq!(taken + stolen.1, pushed);
+        assert_eq!(owner_sum + stolen.0, pushed * pushed.saturating_sub(1) / 2);
+        assert_eq!(counter.alive(), 0);
+    }
+}
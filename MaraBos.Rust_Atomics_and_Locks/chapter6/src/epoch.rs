@@ -0,0 +1,728 @@
+// Epoch-based memory reclamation
+//
+// The lazy initialization examples in chapter 2 and 3 publish a `Box` through
+// an `AtomicPtr`, but can never free it again, since another thread might
+// still be reading it. Here, a thread that wants to read through such a
+// pointer first *pins* itself, and only uses what it loaded while the
+// resulting `Guard` is alive. A thread that unlinks something doesn't free it
+// right away, but hands it to `defer_destroy`, which frees it once every
+// thread that could still be looking at it has unpinned.
+//
+// To know when that is, there's a global epoch counter. Pinning copies the
+// global epoch into the thread's own slot. The global epoch can only advance
+// if all pinned threads have seen its current value. Garbage is tagged with the
+// epoch it was retired in, and is freed once the global epoch is two steps
+// further: by then, every thread that was pinned at the time has unpinned.
+//
+// Cheap for readers (pinning is a store and a fence, no counter per object),
+// but a thread that stays pinned for a long time stops all reclamation.
+// See hazard.rs for an alternative that doesn't have that problem.
+//
+// The API mirrors (a small part of) crossbeam-epoch:
+//
+//     let guard = epoch::pin();
+//     let old = atomic.swap(Owned::new(value), AcqRel, &guard);
+//     unsafe { guard.defer_destroy(old) };
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::sync::atomic::{
+    fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering,
+    Ordering::{Acquire, Relaxed, Release, SeqCst},
+};
+use std::sync::Mutex;
+
+/// The epoch advances in steps of 2, so the lowest bit is free for this.
+const PINNED: usize = 1;
+
+/// Number of deferred functions a thread collects before trying to free them.
+const BAG_SIZE: usize = 64;
+
+/// How often (in pins) a thread tries to advance the epoch on its own.
+const PINS_BETWEEN_COLLECT: usize = 128;
+
+static GLOBAL_EPOCH: AtomicUsize = AtomicUsize::new(0);
+
+/// Garbage left behind by threads that exited.
+static ORPHANS: Mutex<Vec<(usize, Deferred)>> = Mutex::new(Vec::new());
+static HAS_ORPHANS: AtomicBool = AtomicBool::new(false);
+
+// Deferred //
+
+/// A boxed `FnOnce`, without the `Send` requirement of a `Box<dyn FnOnce + Send>`.
+struct Deferred {
+    call: unsafe fn(*mut ()),
+    data: *mut (),
+}
+
+// Safety: only created through `defer` (which requires `Send`)
+// or the unsafe `defer_unchecked`/`defer_destroy`.
+unsafe impl Send for Deferred {}
+
+impl Deferred {
+    fn new<F: FnOnce()>(f: F) -> Self {
+        unsafe fn call<F: FnOnce()>(data: *mut ()) {
+            let f = Box::from_raw(data as *mut F);
+            f();
+        }
+        Self {
+            call: call::<F>,
+            data: Box::into_raw(Box::new(f)) as *mut (),
+        }
+    }
+
+    fn call(self) {
+        unsafe { (self.call)(self.data) }
+    }
+}
+
+// No Drop: a `Deferred` is only dropped without being called if a bag is
+// dropped while panicking. Running it then might be too early, so it leaks.
+
+/// Calls everything that was retired at least two epochs before `global`.
+fn collect(bag: &mut Vec<(usize, Deferred)>, global: usize) {
+    let mut i = 0;
+    while i < bag.len() {
+        if global.wrapping_sub(bag[i].0) >= 4 {
+            bag.swap_remove(i).1.call();
+        } else {
+            i += 1;
+        }
+    }
+}
+
+// Participants //
+
+/// A thread's slot, in a global list that's never freed.
+/// Slots of threads that exited are reused by new threads.
+struct Local {
+    /// The global epoch when this thread was pinned, plus PINNED. Or zero.
+    epoch: AtomicUsize,
+    in_use: AtomicBool,
+    next: *const Local,
+}
+
+// Safety: `next` is never changed once the slot is in the list.
+unsafe impl Sync for Local {}
+
+static LOCALS: AtomicPtr<Local> = AtomicPtr::new(ptr::null_mut());
+
+impl Local {
+    fn all() -> impl Iterator<Item = &'static Local> {
+        let mut p = LOCALS.load(Acquire) as *const Local;
+        std::iter::from_fn(move || {
+            // Safety: slots are never freed.
+            let local = unsafe { p.as_ref()? };
+            p = local.next;
+            Some(local)
+        })
+    }
+
+    fn claim() -> &'static Local {
+        for local in Self::all() {
+            if !local.in_use.load(Relaxed)
+                && local
+                    .in_use
+                    .compare_exchange(false, true, Acquire, Relaxed)
+                    .is_ok()
+            {
+                return local;
+            }
+        }
+        let local = Box::leak(Box::new(Local {
+            epoch: AtomicUsize::new(0),
+            in_use: AtomicBool::new(true),
+            next: ptr::null(),
+        }));
+        let mut head = LOCALS.load(Relaxed);
+        loop {
+            local.next = head;
+            match LOCALS.compare_exchange_weak(head, local, Release, Relaxed) {
+                Ok(_) => return local,
+                Err(e) => head = e,
+            }
+        }
+    }
+}
+
+/// Advances the global epoch if every pinned thread has seen the current one.
+/// Returns the (new) global epoch.
+fn try_advance() -> usize {
+    let global = GLOBAL_EPOCH.load(Relaxed);
+    // Pairs with the fence in `pin`: either we see the thread's pin,
+    // or it sees everything that was unlinked before this point.
+    // Pairs with the fence in `defer` too: garbage tagged with an epoch
+    // before the new one was unlinked before the new one started, so
+    // threads pinned in the new epoch can't find it anymore.
+    fence(SeqCst);
+    for local in Local::all() {
+        let epoch = local.epoch.load(Relaxed);
+        if epoch & PINNED != 0 && epoch & !PINNED != global {
+            return global;
+        }
+    }
+    fence(Acquire);
+    match GLOBAL_EPOCH.compare_exchange(global, global.wrapping_add(2), Release, Relaxed) {
+        Ok(_) => global.wrapping_add(2),
+        Err(e) => e,
+    }
+}
+
+/// The per-thread state.
+struct Handle {
+    local: &'static Local,
+    /// Number of `Guard`s of this thread.
+    guards: Cell<usize>,
+    pins: Cell<usize>,
+    bag: RefCell<Vec<(usize, Deferred)>>,
+    /// Bag size at which to collect next. Grows with what couldn't be freed
+    /// last time, so a thread that stays pinned doesn't make this quadratic.
+    collect_at: Cell<usize>,
+}
+
+impl Handle {
+    fn pin(&self) -> Guard {
+        let guards = self.guards.get();
+        self.guards.set(guards + 1);
+        if guards == 0 {
+            let global = GLOBAL_EPOCH.load(Relaxed);
+            self.local.epoch.store(global | PINNED, Relaxed);
+            // Make the pin visible before loading anything we might read
+            // through. Pairs with the fence in `try_advance`.
+            fence(SeqCst);
+
+            let pins = self.pins.get().wrapping_add(1);
+            self.pins.set(pins);
+            if pins.is_multiple_of(PINS_BETWEEN_COLLECT) {
+                self.collect();
+            }
+        }
+        Guard { handle: self }
+    }
+
+    fn unpin(&self) {
+        let guards = self.guards.get() - 1;
+        self.guards.set(guards);
+        if guards == 0 {
+            // Release: everything we read happens before it's freed.
+            self.local.epoch.store(0, Release);
+        }
+    }
+
+    fn defer(&self, f: Deferred) {
+        // Otherwise the epoch could be loaded before the unlinking store
+        // is visible, tagging the garbage with an epoch in which other
+        // threads could still pin and find it. (Like crossbeam's `push_bag`.)
+        fence(SeqCst);
+        let epoch = GLOBAL_EPOCH.load(Relaxed);
+        let mut bag = self.bag.borrow_mut();
+        bag.push((epoch, f));
+        let full = bag.len() >= self.collect_at.get();
+        drop(bag);
+        if full {
+            self.collect();
+        }
+    }
+
+    fn collect(&self) {
+        let global = try_advance();
+        // Take the bag out, so the deferred functions can use `pin` themselves.
+        let mut bag = self.bag.take();
+        collect(&mut bag, global);
+        if HAS_ORPHANS.load(Relaxed) {
+            if let Ok(mut orphans) = ORPHANS.try_lock() {
+                collect(&mut orphans, global);
+                HAS_ORPHANS.store(!orphans.is_empty(), Relaxed);
+            }
+        }
+        let mut current = self.bag.borrow_mut();
+        bag.append(&mut current);
+        self.collect_at.set(BAG_SIZE.max(2 * bag.len()));
+        *current = bag;
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let bag = self.bag.get_mut();
+        if !bag.is_empty() {
+            let mut orphans = ORPHANS.lock().unwrap_or_else(|e| e.into_inner());
+            orphans.append(bag);
+            HAS_ORPHANS.store(true, Relaxed);
+        }
+        self.local.epoch.store(0, Release);
+        self.local.in_use.store(false, Release);
+    }
+}
+
+thread_local! {
+    static HANDLE: Handle = Handle {
+        local: Local::claim(),
+        guards: Cell::new(0),
+        pins: Cell::new(0),
+        bag: RefCell::new(Vec::new()),
+        collect_at: Cell::new(BAG_SIZE),
+    };
+}
+
+// Guard //
+
+/// Pins the current thread, until the guard is dropped.
+///
+/// Anything loaded from an `Atomic` through this guard
+/// won't be freed until the guard is gone.
+pub fn pin() -> Guard {
+    // Safety: the guard is !Send, so it's dropped before the thread exits
+    // (or at least before the thread local is gone).
+    HANDLE.with(|handle| unsafe { &*(handle as *const Handle) }.pin())
+}
+
+/// A guard that doesn't pin anything. Deferred functions run immediately.
+///
+/// # Safety
+///
+/// Only for when nothing else can be accessing the data at the same time,
+/// e.g. in a `Drop` implementation or a constructor.
+pub unsafe fn unprotected() -> &'static Guard {
+    struct Unprotected(Guard);
+    unsafe impl Sync for Unprotected {}
+    static UNPROTECTED: Unprotected = Unprotected(Guard {
+        handle: ptr::null(),
+    });
+    &UNPROTECTED.0
+}
+
+pub struct Guard {
+    /// Null for `unprotected`. Makes the guard !Send.
+    handle: *const Handle,
+}
+
+impl Guard {
+    /// Runs `f` once nothing pinned right now can still be running.
+    pub fn defer<F: FnOnce() + Send + 'static>(&self, f: F) {
+        unsafe { self.defer_unchecked(f) }
+    }
+
+    /// # Safety
+    ///
+    /// `f` will run later, on any thread.
+    /// Whatever it uses must still be there, and be usable from there.
+    pub unsafe fn defer_unchecked<F: FnOnce()>(&self, f: F) {
+        match self.handle.as_ref() {
+            Some(handle) => handle.defer(Deferred::new(f)),
+            None => f(),
+        }
+    }
+
+    /// Frees `ptr` once no thread can be reading it anymore.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been unlinked already, so no new readers can find it,
+    /// and this may only happen once per pointer.
+    pub unsafe fn defer_destroy<T>(&self, ptr: Shared<'_, T>) {
+        let raw = ptr.as_raw() as *mut T;
+        self.defer_unchecked(move || drop(Box::from_raw(raw)));
+    }
+
+    /// Tries to advance the epoch, and frees what can be freed.
+    pub fn flush(&self) {
+        if let Some(handle) = unsafe { self.handle.as_ref() } {
+            handle.collect();
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(handle) = unsafe { self.handle.as_ref() } {
+            handle.unpin();
+        }
+    }
+}
+
+impl fmt::Debug for Guard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Guard")
+    }
+}
+
+// Pointers //
+
+/// Something that can be stored in an `Atomic`: `Owned` or `Shared`.
+pub trait Pointer<T> {
+    fn into_raw(self) -> *mut T;
+
+    /// # Safety
+    ///
+    /// `raw` must come from `into_raw` of the same type.
+    unsafe fn from_raw(raw: *mut T) -> Self;
+}
+
+/// A `Box` that's about to be put in an `Atomic`.
+pub struct Owned<T> {
+    data: Box<T>,
+}
+
+impl<T> Owned<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            data: Box::new(value),
+        }
+    }
+
+    pub fn into_box(self) -> Box<T> {
+        self.data
+    }
+
+    /// Gives up ownership, e.g. to put it in a data structure by hand.
+    pub fn into_shared(self, _: &Guard) -> Shared<'_, T> {
+        unsafe { Shared::from_raw(self.into_raw()) }
+    }
+}
+
+impl<T> Pointer<T> for Owned<T> {
+    fn into_raw(self) -> *mut T {
+        Box::into_raw(self.data)
+    }
+
+    unsafe fn from_raw(raw: *mut T) -> Self {
+        Self {
+            data: Box::from_raw(raw),
+        }
+    }
+}
+
+impl<T> Deref for Owned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
+
+impl<T> DerefMut for Owned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Owned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// A pointer loaded from an `Atomic`, valid while the guard `'g` is alive.
+pub struct Shared<'g, T> {
+    ptr: *const T,
+    _guard: PhantomData<&'g T>,
+}
+
+impl<'g, T> Shared<'g, T> {
+    pub const fn null() -> Self {
+        Self {
+            ptr: ptr::null(),
+            _guard: PhantomData,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.ptr.is_null()
+    }
+
+    pub fn as_raw(&self) -> *const T {
+        self.ptr
+    }
+
+    /// # Safety
+    ///
+    /// Must not be null, and must point to something that isn't freed
+    /// before the guard is gone (e.g. it was loaded through that guard).
+    pub unsafe fn deref(&self) -> &'g T {
+        &*self.ptr
+    }
+
+    /// # Safety
+    ///
+    /// As `deref`, except it may be null.
+    pub unsafe fn as_ref(&self) -> Option<&'g T> {
+        self.ptr.as_ref()
+    }
+
+    /// Takes ownership.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may be using or freeing it.
+    pub unsafe fn into_owned(self) -> Owned<T> {
+        Owned::from_raw(self.ptr as *mut T)
+    }
+}
+
+impl<T> Pointer<T> for Shared<'_, T> {
+    fn into_raw(self) -> *mut T {
+        self.ptr as *mut T
+    }
+
+    unsafe fn from_raw(raw: *mut T) -> Self {
+        Self {
+            ptr: raw,
+            _guard: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for Shared<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Shared<'_, T> {}
+
+impl<T> PartialEq for Shared<'_, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl<T> Eq for Shared<'_, T> {}
+
+impl<T> fmt::Debug for Shared<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Pointer::fmt(&self.ptr, f)
+    }
+}
+
+// Atomic //
+
+/// An `AtomicPtr` to a heap allocated `T`, whose loads are protected by a guard.
+///
+/// Doesn't free what it points to when dropped: the data structure using it
+/// decides what it owns.
+pub struct Atomic<T> {
+    ptr: AtomicPtr<T>,
+}
+
+unsafe impl<T: Send + Sync> Send for Atomic<T> {}
+unsafe impl<T: Send + Sync> Sync for Atomic<T> {}
+
+impl<T> Atomic<T> {
+    pub const fn null() -> Self {
+        Self {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn new(value: T) -> Self {
+        Self::from(Owned::new(value))
+    }
+
+    pub fn load<'g>(&self, order: Ordering, _: &'g Guard) -> Shared<'g, T> {
+        unsafe { Shared::from_raw(self.ptr.load(order)) }
+    }
+
+    pub fn store<P: Pointer<T>>(&self, new: P, order: Ordering) {
+        self.ptr.store(new.into_raw(), order);
+    }
+
+    pub fn swap<'g, P: Pointer<T>>(&self, new: P, order: Ordering, _: &'g Guard) -> Shared<'g, T> {
+        unsafe { Shared::from_raw(self.ptr.swap(new.into_raw(), order)) }
+    }
+
+    /// Stores `new` if the current value is `current`, and returns the previous value.
+    /// Otherwise, returns the current value, and gives `new` back.
+    pub fn compare_exchange<'g, P: Pointer<T>>(
+        &self,
+        current: Shared<'_, T>,
+        new: P,
+        success: Ordering,
+        failure: Ordering,
+        _: &'g Guard,
+    ) -> Result<Shared<'g, T>, CompareExchangeError<'g, T, P>> {
+        let new = new.into_raw();
+        match self
+            .ptr
+            .compare_exchange(current.as_raw() as *mut T, new, success, failure)
+        {
+            Ok(previous) => Ok(unsafe { Shared::from_raw(previous) }),
+            Err(current) => Err(CompareExchangeError {
+                current: unsafe { Shared::from_raw(current) },
+                new: unsafe { P::from_raw(new) },
+            }),
+        }
+    }
+
+    /// Takes ownership of the pointee.
+    ///
+    /// # Safety
+    ///
+    /// Must not be null, and nothing else may still be using it.
+    pub unsafe fn into_owned(self) -> Owned<T> {
+        Owned::from_raw(self.ptr.into_inner())
+    }
+}
+
+impl<T> From<Owned<T>> for Atomic<T> {
+    fn from(owned: Owned<T>) -> Self {
+        Self {
+            ptr: AtomicPtr::new(owned.into_raw()),
+        }
+    }
+}
+
+impl<T> Default for Atomic<T> {
+    fn default() -> Self {
+        Self::null()
+    }
+}
+
+impl<T> fmt::Debug for Atomic<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Pointer::fmt(&self.ptr.load(Relaxed), f)
+    }
+}
+
+/// A failed `compare_exchange`: the value that was there instead,
+/// and the new value that wasn't stored.
+pub struct CompareExchangeError<'g, T, P: Pointer<T>> {
+    pub current: Shared<'g, T>,
+    pub new: P,
+}
+
+impl<T, P: Pointer<T>> fmt::Debug for CompareExchangeError<'_, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CompareExchangeError")
+            .field("current", &self.current)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering::AcqRel;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use stress::{Config, DropCounter, Stamp, Tracked};
+
+    /// Flushes until `done` holds. Other tests (running at the same time)
+    /// might be pinned for a bit, keeping the epoch from advancing.
+    fn flush_until(done: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < Duration::from_secs(10), "nothing freed");
+            pin().flush();
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_swap_and_defer() {
+        static COUNTER: DropCounter = DropCounter::new();
+        let atomic = Atomic::new(COUNTER.track());
+        let guard = pin();
+        let old = atomic.swap(Owned::new(COUNTER.track()), AcqRel, &guard);
+        assert!(!old.is_null());
+        unsafe { guard.defer_destroy(old) };
+        // We're still pinned ourselves.
+        guard.flush();
+        guard.flush();
+        guard.flush();
+        assert_eq!(COUNTER.alive(), 2);
+        drop(guard);
+        flush_until(|| COUNTER.alive() == 1);
+        drop(unsafe { atomic.into_owned() });
+        assert_eq!(COUNTER.alive(), 0);
+    }
+
+    #[test]
+    fn test_compare_exchange() {
+        let atomic = Atomic::null();
+        let guard = &pin();
+        let a = atomic
+            .compare_exchange(Shared::null(), Owned::new(1), AcqRel, Acquire, guard)
+            .unwrap();
+        assert!(a.is_null());
+        let e = atomic
+            .compare_exchange(Shared::null(), Owned::new(2), AcqRel, Acquire, guard)
+            .unwrap_err();
+        assert_eq!(unsafe { *e.current.deref() }, 1);
+        assert_eq!(*e.new, 2);
+        unsafe { drop(atomic.into_owned()) };
+    }
+
+    #[test]
+    fn test_nested_and_unprotected() {
+        let outer = pin();
+        let inner = pin();
+        drop(outer);
+        let ran = std::sync::Arc::new(AtomicBool::new(false));
+        let r = ran.clone();
+        inner.defer(move || r.store(true, Relaxed));
+        drop(inner);
+        flush_until(|| ran.load(Relaxed));
+
+        let ran = AtomicBool::new(false);
+        unsafe { unprotected().defer_unchecked(|| ran.store(true, Relaxed)) };
+        assert!(ran.load(Relaxed));
+    }
+
+    /// A pinned thread keeps whatever it loaded alive,
+    /// no matter how often other threads swap and flush.
+    #[test]
+    fn test_pinned_reader_blocks_reclamation() {
+        static COUNTER: DropCounter = DropCounter::new();
+        let atomic = Atomic::new((Stamp::new(0), COUNTER.track()));
+        thread::scope(|s| {
+            let guard = pin();
+            let first = atomic.load(Acquire, &guard);
+            s.spawn(|| {
+                for i in 1..=100 {
+                    let guard = pin();
+                    let new = Owned::new((Stamp::new(i), COUNTER.track()));
+                    let old = atomic.swap(new, AcqRel, &guard);
+                    unsafe { guard.defer_destroy(old) };
+                    guard.flush();
+                }
+            })
+            .join()
+            .unwrap();
+            // Not freed, even though the thread that retired it is gone.
+            assert_eq!(unsafe { first.deref() }.0.value(), 0);
+            assert!(COUNTER.alive() > 1);
+        });
+        flush_until(|| COUNTER.alive() == 1);
+        drop(unsafe { atomic.into_owned() });
+    }
+
+    /// Readers keep reading while writers keep swapping and retiring.
+    /// Nobody sees a freed (torn) value, and in the end everything is freed.
+    #[test]
+    fn test_stress() {
+        static COUNTER: DropCounter = DropCounter::new();
+        let atomic: Atomic<(Stamp, Tracked)> = Atomic::new((Stamp::new(0), COUNTER.track()));
+        stress::run(&Config::from_env(), |w| {
+            for _ in 0..w.iterations {
+                let guard = pin();
+                if w.rng.below(4) == 0 {
+                    let new = Owned::new((Stamp::new(w.rng.next_u64()), COUNTER.track()));
+                    let old = atomic.swap(new, AcqRel, &guard);
+                    unsafe { guard.defer_destroy(old) };
+                } else {
+                    let current = unsafe { atomic.load(Acquire, &guard).deref() };
+                    if w.rng.below(8) == 0 {
+                        thread::yield_now();
+                    }
+                    assert!(current.0.is_intact());
+                }
+            }
+        });
+        flush_until(|| COUNTER.alive() == 1);
+        drop(unsafe { atomic.into_owned() });
+    }
+}
@@ -0,0 +1,483 @@
+// Hazard pointers
+//
+// An alternative to epoch.rs. Instead of announcing "I'm reading something",
+// a reader announces exactly which pointer it's reading, by putting it in a
+// hazard slot. A thread that unlinked a pointer `retire`s it, and it's only
+// freed once no hazard slot contains it anymore. A reader that stays around for
+// a long time only keeps the one object it protects alive, not everything
+// retired in the meantime, at the cost of a store and a fence per pointer.
+//
+// Protecting is a loop, like the lazy initialization with indirection in
+// chapter 3: load the pointer, publish it, and check that it's still there.
+// If it is, whoever unlinks it afterwards will see the hazard when scanning.
+//
+// Retired pointers go on a list in the domain. Scanning all hazards is
+// expensive, so it only happens once the list has grown to (twice) the number
+// of hazard slots, which frees at least half of it: amortized constant time.
+//
+// Hazard slots are claimed by threads from the domain's list, and returned
+// when their guard is dropped, to be reused. The list never shrinks.
+
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{
+    fence, AtomicBool, AtomicPtr, AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release, SeqCst},
+};
+
+/// Retired pointers are kept at least until there are this many.
+const MIN_RETIRED: usize = 64;
+
+struct HazardSlot {
+    /// The protected pointer, or null.
+    ptr: AtomicPtr<()>,
+    /// Claimed by a thread (through a guard).
+    active: AtomicBool,
+    next: *mut HazardSlot,
+}
+
+struct Retired {
+    ptr: *mut (),
+    /// The `unsafe fn(*mut T)` given to `retire`.
+    deleter: *const (),
+    /// Calls `deleter`, with the right `T`.
+    call: unsafe fn(*mut (), *const ()),
+    next: *mut Retired,
+}
+
+impl Retired {
+    unsafe fn free(&self) {
+        (self.call)(self.ptr, self.deleter)
+    }
+}
+
+/// A set of hazard slots, and the pointers retired against them.
+///
+/// Usually, the global one is good enough (see `protect` and `retire`).
+pub struct Domain {
+    slots: AtomicPtr<HazardSlot>,
+    num_slots: AtomicUsize,
+    retired: AtomicPtr<Retired>,
+    num_retired: AtomicUsize,
+}
+
+unsafe impl Send for Domain {}
+unsafe impl Sync for Domain {}
+
+static GLOBAL: Domain = Domain::new();
+
+impl Domain {
+    pub const fn new() -> Self {
+        Self {
+            slots: AtomicPtr::new(ptr::null_mut()),
+            num_slots: AtomicUsize::new(0),
+            retired: AtomicPtr::new(ptr::null_mut()),
+            num_retired: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn global() -> &'static Domain {
+        &GLOBAL
+    }
+
+    fn all_slots(&self) -> impl Iterator<Item = &HazardSlot> {
+        let mut p = self.slots.load(Acquire);
+        std::iter::from_fn(move || {
+            // Safety: slots are only freed when the domain is dropped.
+            let slot = unsafe { p.as_ref()? };
+            p = slot.next;
+            Some(slot)
+        })
+    }
+
+    /// Reuses an inactive slot, or adds a new one.
+    fn claim_slot(&self) -> &HazardSlot {
+        for slot in self.all_slots() {
+            if !slot.active.load(Relaxed)
+                && slot
+                    .active
+                    .compare_exchange(false, true, Acquire, Relaxed)
+                    .is_ok()
+            {
+                return slot;
+            }
+        }
+        let slot = Box::into_raw(Box::new(HazardSlot {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+            active: AtomicBool::new(true),
+            next: ptr::null_mut(),
+        }));
+        self.num_slots.fetch_add(1, Relaxed);
+        let mut head = self.slots.load(Relaxed);
+        loop {
+            unsafe { (*slot).next = head };
+            match self
+                .slots
+                .compare_exchange_weak(head, slot, Release, Relaxed)
+            {
+                Ok(_) => return unsafe { &*slot },
+                Err(e) => head = e,
+            }
+        }
+    }
+
+    /// A hazard slot that doesn't protect anything yet.
+    pub fn hazard_pointer<T>(&self) -> HazardGuard<'_, T> {
+        HazardGuard {
+            slot: self.claim_slot(),
+            ptr: ptr::null_mut(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Loads `src`, and protects the result from being freed
+    /// until the guard is dropped (or protects something else).
+    pub fn protect<T>(&self, src: &AtomicPtr<T>) -> HazardGuard<'_, T> {
+        let mut guard = self.hazard_pointer();
+        guard.protect(src);
+        guard
+    }
+
+    /// Frees `ptr` using `deleter` once no hazard slot contains it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been unlinked already, so no new guard can protect it,
+    /// and may only be retired once. `deleter` must be fine to call on it
+    /// from any thread.
+    pub unsafe fn retire<T>(&self, ptr: *mut T, deleter: unsafe fn(*mut T)) {
+        unsafe fn call<T>(ptr: *mut (), deleter: *const ()) {
+            let deleter: unsafe fn(*mut T) = mem::transmute(deleter);
+            deleter(ptr as *mut T)
+        }
+        let retired = Box::into_raw(Box::new(Retired {
+            ptr: ptr as *mut (),
+            deleter: deleter as *const (),
+            call: call::<T>,
+            next: ptr::null_mut(),
+        }));
+        self.push_retired(retired, retired, 1);
+        let threshold = MIN_RETIRED.max(2 * self.num_slots.load(Relaxed));
+        if self.num_retired.load(Relaxed) >= threshold {
+            self.reclaim();
+        }
+    }
+
+    /// Pushes the list from `first` to `last` onto the retired list.
+    fn push_retired(&self, first: *mut Retired, last: *mut Retired, n: usize) {
+        self.num_retired.fetch_add(n, Relaxed);
+        let mut head = self.retired.load(Relaxed);
+        loop {
+            unsafe { (*last).next = head };
+            match self
+                .retired
+                .compare_exchange_weak(head, first, Release, Relaxed)
+            {
+                Ok(_) => return,
+                Err(e) => head = e,
+            }
+        }
+    }
+
+    /// Frees every retired pointer that isn't protected.
+    pub fn reclaim(&self) {
+        let mut list = self.retired.swap(ptr::null_mut(), Acquire);
+        if list.is_null() {
+            return;
+        }
+        // Pairs with the SeqCst in `protect`: either we see the hazard,
+        // or the protecting thread sees that the pointer was unlinked.
+        fence(SeqCst);
+        let hazards: HashSet<*mut ()> = self
+            .all_slots()
+            .map(|slot| slot.ptr.load(SeqCst))
+            .filter(|p| !p.is_null())
+            .collect();
+
+        let mut kept_first: *mut Retired = ptr::null_mut();
+        let mut kept_last: *mut Retired = ptr::null_mut();
+        let mut kept = 0;
+        let mut freed = 0;
+        while !list.is_null() {
+            let retired = unsafe { Box::from_raw(list) };
+            list = retired.next;
+            if hazards.contains(&retired.ptr) {
+                let retired = Box::into_raw(retired);
+                if kept_last.is_null() {
+                    kept_last = retired;
+                }
+                unsafe { (*retired).next = kept_first };
+                kept_first = retired;
+                kept += 1;
+            } else {
+                unsafe { retired.free() };
+                freed += 1;
+            }
+        }
+        self.num_retired.fetch_sub(kept + freed, Relaxed);
+        if kept > 0 {
+            self.push_retired(kept_first, kept_last, kept);
+        }
+    }
+}
+
+impl Default for Domain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Domain {
+    fn drop(&mut self) {
+        // No guards left, since they borrow the domain.
+        let mut list = *self.retired.get_mut();
+        while !list.is_null() {
+            let retired = unsafe { Box::from_raw(list) };
+            list = retired.next;
+            unsafe { retired.free() };
+        }
+        let mut slot = *self.slots.get_mut();
+        while !slot.is_null() {
+            let s = unsafe { Box::from_raw(slot) };
+            slot = s.next;
+        }
+    }
+}
+
+/// Protects a pointer loaded from `src` in the global domain.
+pub fn protect<T>(src: &AtomicPtr<T>) -> HazardGuard<'static, T> {
+    Domain::global().protect(src)
+}
+
+/// Retires a pointer in the global domain.
+///
+/// # Safety
+///
+/// See `Domain::retire`.
+pub unsafe fn retire<T>(ptr: *mut T, deleter: unsafe fn(*mut T)) {
+    Domain::global().retire(ptr, deleter)
+}
+
+/// A deleter for pointers that came from `Box::into_raw`.
+///
+/// # Safety
+///
+/// `ptr` must come from `Box::into_raw`.
+pub unsafe fn drop_box<T>(ptr: *mut T) {
+    drop(Box::from_raw(ptr))
+}
+
+// HazardGuard //
+
+/// Keeps the pointer it protects from being freed until it's dropped.
+pub struct HazardGuard<'d, T> {
+    slot: &'d HazardSlot,
+    ptr: *mut T,
+    _marker: PhantomData<*const T>,
+}
+
+unsafe impl<T: Sync> Send for HazardGuard<'_, T> {}
+unsafe impl<T: Sync> Sync for HazardGuard<'_, T> {}
+
+impl<T> HazardGuard<'_, T> {
+    /// Loads `src`, and protects that instead of what it protected before.
+    /// Returns the pointer, which might be null.
+    pub fn protect(&mut self, src: &AtomicPtr<T>) -> *mut T {
+        let mut p = src.load(Relaxed);
+        loop {
+            self.slot.ptr.store(p as *mut (), SeqCst);
+            // SeqCst, to make sure a thread scanning for hazards after
+            // unlinking `p` sees the store above, if `p` is still there.
+            let q = src.load(SeqCst);
+            if q == p {
+                self.ptr = p;
+                return p;
+            }
+            p = q;
+        }
+    }
+
+    /// Stops protecting anything, but keeps the slot for later.
+    pub fn reset(&mut self) {
+        self.slot.ptr.store(ptr::null_mut(), Release);
+        self.ptr = ptr::null_mut();
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr
+    }
+
+    /// The protected value, if it isn't null.
+    ///
+    /// Valid for as long as the guard protects it, since it's only freed
+    /// after being retired (which promises it was unlinked).
+    pub fn as_ref(&self) -> Option<&T> {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> Drop for HazardGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release: we're done with it before whoever frees it next.
+        self.slot.ptr.store(ptr::null_mut(), Release);
+        self.slot.active.store(false, Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::ManuallyDrop;
+    use std::sync::atomic::Ordering::AcqRel;
+    use std::thread;
+    use stress::{Config, DropCounter, Stamp, Tracked};
+
+    /// A Treiber stack, as simple as possible: the CAS loop of
+    /// lazy_initialization_with_indirection.rs, plus hazard pointers.
+    struct Stack<T> {
+        head: AtomicPtr<Node<T>>,
+        domain: Domain,
+    }
+
+    struct Node<T> {
+        value: ManuallyDrop<T>,
+        next: *mut Node<T>,
+    }
+
+    impl<T> Stack<T> {
+        fn new() -> Self {
+            Self {
+                head: AtomicPtr::new(ptr::null_mut()),
+                domain: Domain::new(),
+            }
+        }
+
+        fn push(&self, value: T) {
+            let node = Box::into_raw(Box::new(Node {
+                value: ManuallyDrop::new(value),
+                next: ptr::null_mut(),
+            }));
+            let mut head = self.head.load(Relaxed);
+            loop {
+                unsafe { (*node).next = head };
+                match self
+                    .head
+                    .compare_exchange_weak(head, node, Release, Relaxed)
+                {
+                    Ok(_) => return,
+                    Err(e) => head = e,
+                }
+            }
+        }
+
+        fn pop(&self) -> Option<T> {
+            let mut guard = self.domain.hazard_pointer();
+            loop {
+                let head = guard.protect(&self.head);
+                let node = guard.as_ref()?;
+                // No ABA: `head` can't be freed and reused while protected.
+                if self
+                    .head
+                    .compare_exchange(head, node.next, Acquire, Relaxed)
+                    .is_ok()
+                {
+                    let value = unsafe { ptr::read(&*node.value) };
+                    drop(guard);
+                    unsafe { self.domain.retire(head, drop_box) };
+                    return Some(value);
+                }
+            }
+        }
+    }
+
+    impl<T> Drop for Stack<T> {
+        fn drop(&mut self) {
+            while self.pop().is_some() {}
+        }
+    }
+
+    #[test]
+    fn test_protect_and_retire() {
+        let counter = DropCounter::new();
+        let domain = Domain::new();
+        let atomic = AtomicPtr::new(Box::into_raw(Box::new(counter.track())));
+        let guard = domain.protect(&atomic);
+        let old = atomic.swap(ptr::null_mut(), AcqRel);
+        assert_eq!(old, guard.as_ptr());
+        unsafe { domain.retire(old, drop_box) };
+        domain.reclaim();
+        assert_eq!(counter.alive(), 1);
+        drop(guard);
+        domain.reclaim();
+        assert_eq!(counter.alive(), 0);
+
+        let guard = domain.protect(&atomic);
+        assert!(guard.as_ref().is_none());
+    }
+
+    /// A node that's protected is never freed,
+    /// no matter how many other nodes are popped, retired and reclaimed.
+    #[test]
+    fn test_protected_node_not_freed() {
+        struct Payload<'a> {
+            stamp: Stamp,
+            _tracked: Tracked<'a>,
+        }
+        let counter = DropCounter::new();
+        let stack = Stack::new();
+        for i in 0..1000 {
+            stack.push(Payload {
+                stamp: Stamp::new(i),
+                _tracked: counter.track(),
+            });
+        }
+        // Protect the top node without popping it.
+        let guard = stack.domain.protect(&stack.head);
+        let node = guard.as_ref().unwrap();
+        assert_eq!(node.value.stamp.value(), 999);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| while stack.pop().is_some() {});
+            }
+        });
+        stack.domain.reclaim();
+        // The values were all moved out and dropped by now,
+        // but the protected node is still there (with its value moved out).
+        assert_eq!(counter.alive(), 0);
+        assert_eq!(stack.domain.num_retired.load(Relaxed), 1);
+        assert_eq!(node.value.stamp.value(), 999);
+        drop(guard);
+        stack.domain.reclaim();
+        assert_eq!(stack.domain.num_retired.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn test_stress() {
+        let counter = DropCounter::new();
+        let stack = Stack::new();
+        let popped = stress::run(&Config::from_env(), |w| {
+            let mut pushed = 0;
+            let mut popped = 0;
+            for _ in 0..w.iterations {
+                if w.rng.below(2) == 0 {
+                    stack.push((Stamp::new(w.rng.next_u64()), counter.track()));
+                    pushed += 1;
+                } else if let Some((stamp, _)) = stack.pop() {
+                    assert!(stamp.is_intact());
+                    popped += 1;
+                }
+            }
+            pushed - popped
+        });
+        let mut left = popped.iter().sum::<i64>();
+        while stack.pop().is_some() {
+            left -= 1;
+        }
+        assert_eq!(left, 0);
+        drop(stack);
+        assert_eq!(counter.alive(), 0);
+    }
+}
@@ -0,0 +1,51 @@
+// Library versions of the Arc from this chapter, for the other chapters to build on.
+//
+// Arc, Weak: optimizing.rs itself, included as a module, tests and all,
+//            with `unsize_arc!` to turn an `Arc<T>` into an `Arc<dyn Trait>`
+// AtomicArc: a cell holding an `Arc`, that can be swapped out while
+//            other threads are reading it
+// epoch: epoch-based memory reclamation, for lock-free data structures
+// hazard: hazard pointers, the same but without readers blocking reclamation
+// TreiberStack, MsQueue: lock-free collections, on top of epoch
+// chase_lev: a work-stealing deque, for thread pools
+
+// Its main() goes unused here.
+#[allow(dead_code)]
+#[path = "optimizing.rs"]
+mod arc;
+pub mod atomic_arc;
+pub mod chase_lev;
+pub mod epoch;
+pub mod hazard;
+pub mod ms_queue;
+pub mod treiber_stack;
+
+pub use arc::{Arc, Weak};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::{Debug, Display};
+    use stress::DropCounter;
+
+    /// Out here, without access to the private fields.
+    #[test]
+    fn test_unsize() {
+        let counter = DropCounter::new();
+        let d: Arc<dyn Display + Send + Sync> = unsize_arc!(Arc::new(5));
+        let weak = Arc::downgrade(&d);
+        assert_eq!(weak.upgrade().unwrap().to_string(), "5");
+        let slice: Arc<[i32]> = unsize_arc!(Arc::new([1, 2, 3]));
+        assert_eq!(*slice, [1, 2, 3]);
+        let tracked = Arc::new((counter.track(), 1));
+        let tracked2 = tracked.clone();
+        let raw = Arc::into_raw(tracked) as *const dyn Debug;
+        let dbg = unsafe { Arc::from_raw(raw) };
+        drop(tracked2);
+        assert_eq!(counter.alive(), 1);
+        drop(dbg);
+        assert_eq!(counter.alive(), 0);
+        drop(d);
+        assert!(weak.upgrade().is_none());
+    }
+}
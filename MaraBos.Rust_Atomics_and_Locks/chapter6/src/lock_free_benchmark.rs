@@ -0,0 +1,100 @@
+// Throughput of the lock-free TreiberStack and MsQueue,
+// compared to a Mutex<Vec> and a Mutex<VecDeque>.
+//
+// Every thread alternates between pushing and popping, so the collections
+// stay small and all threads keep fighting over the same end(s).
+//
+// Run with --release, and on a machine with several cores
+// for the numbers to mean anything.
+
+use chapter6::ms_queue::MsQueue;
+use chapter6::treiber_stack::TreiberStack;
+use std::collections::VecDeque;
+use std::hint::black_box;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const OPERATIONS: usize = 1_000_000;
+
+trait Collection: Sync {
+    fn push(&self, value: usize);
+    fn pop(&self) -> Option<usize>;
+}
+
+impl Collection for Mutex<Vec<usize>> {
+    fn push(&self, value: usize) {
+        self.lock().unwrap().push(value);
+    }
+
+    fn pop(&self) -> Option<usize> {
+        self.lock().unwrap().pop()
+    }
+}
+
+impl Collection for Mutex<VecDeque<usize>> {
+    fn push(&self, value: usize) {
+        self.lock().unwrap().push_back(value);
+    }
+
+    fn pop(&self) -> Option<usize> {
+        self.lock().unwrap().pop_front()
+    }
+}
+
+impl Collection for TreiberStack<usize> {
+    fn push(&self, value: usize) {
+        self.push(value);
+    }
+
+    fn pop(&self) -> Option<usize> {
+        self.pop()
+    }
+}
+
+impl Collection for MsQueue<usize> {
+    fn push(&self, value: usize) {
+        self.push(value);
+    }
+
+    fn pop(&self) -> Option<usize> {
+        self.pop()
+    }
+}
+
+/// `OPERATIONS` pushes and pops in total, spread over `threads` threads.
+fn run(collection: &impl Collection, threads: usize) -> Duration {
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                let mut sum = 0;
+                for i in 0..OPERATIONS / threads / 2 {
+                    collection.push(i);
+                    sum += collection.pop().unwrap_or(0);
+                }
+                black_box(sum);
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn report(name: &str, threads: usize, duration: Duration) {
+    let rate = OPERATIONS as f64 / duration.as_secs_f64() / 1e6;
+    println!("{name:>20} {threads:>2} threads: {duration:>12.3?} ({rate:.1} M ops/s)");
+}
+
+fn main() {
+    for threads in [1, 2, 4, 8] {
+        let d = run(&Mutex::new(Vec::new()), threads);
+        report("Mutex<Vec>", threads, d);
+        let d = run(&TreiberStack::new(), threads);
+        report("TreiberStack", threads, d);
+        let d = run(&Mutex::new(VecDeque::new()), threads);
+        report("Mutex<VecDeque>", threads, d);
+        let d = run(&MsQueue::new(), threads);
+        report("MsQueue", threads, d);
+        println!();
+    }
+}
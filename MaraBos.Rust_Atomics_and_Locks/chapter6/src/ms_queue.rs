@@ -0,0 +1,217 @@
+// Michael–Scott queue
+//
+// A lock-free FIFO queue: a linked list with a head and a tail pointer.
+// The head always points to a sentinel node, whose value was already popped
+// (or never existed), so the list is never empty and pushing and popping
+// never have to change the same pointer.
+//
+// Pushing is two steps: link the new node after the last one (by swapping it
+// into that node's null `next` pointer), then move `tail` forward. Between
+// those steps, `tail` lags behind. Any thread that notices (because the tail's
+// `next` isn't null) helps by moving it forward itself, so nobody ever has to
+// wait for the pushing thread to finish.
+//
+// Popping moves `head` to the next node, which becomes the new sentinel.
+// Its value is moved out by the thread that moved `head`. The old sentinel
+// is freed through epoch.rs once nobody can be reading it, which also makes
+// sure the compare-and-exchanges can't be fooled by a reused address.
+
+use crate::epoch::{self, Atomic, Guard, Owned, Shared};
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+struct Node<T> {
+    /// Uninitialized in the first sentinel,
+    /// and moved out by whoever makes this node the sentinel.
+    value: MaybeUninit<T>,
+    next: Atomic<Node<T>>,
+}
+
+pub struct MsQueue<T> {
+    head: Atomic<Node<T>>,
+    tail: Atomic<Node<T>>,
+}
+
+unsafe impl<T: Send> Send for MsQueue<T> {}
+unsafe impl<T: Send> Sync for MsQueue<T> {}
+
+impl<T> MsQueue<T> {
+    pub fn new() -> Self {
+        let queue = Self {
+            head: Atomic::null(),
+            tail: Atomic::null(),
+        };
+        let sentinel = Owned::new(Node {
+            value: MaybeUninit::uninit(),
+            next: Atomic::null(),
+        });
+        // Safety: nobody else has the queue yet.
+        let sentinel = sentinel.into_shared(unsafe { epoch::unprotected() });
+        queue.head.store(sentinel, Relaxed);
+        queue.tail.store(sentinel, Relaxed);
+        queue
+    }
+
+    pub fn push(&self, value: T) {
+        let guard = &epoch::pin();
+        let new = Owned::new(Node {
+            value: MaybeUninit::new(value),
+            next: Atomic::null(),
+        })
+        .into_shared(guard);
+        loop {
+            let tail = self.tail.load(Acquire, guard);
+            // Safety: never null, and we're pinned.
+            let tail_node = unsafe { tail.deref() };
+            let next = tail_node.next.load(Acquire, guard);
+            if !next.is_null() {
+                // The tail is lagging behind. Help the other push finish.
+                let _ = self
+                    .tail
+                    .compare_exchange(tail, next, Release, Relaxed, guard);
+                continue;
+            }
+            // Release: whoever pops it sees the value.
+            if tail_node
+                .next
+                .compare_exchange(Shared::null(), new, Release, Relaxed, guard)
+                .is_ok()
+            {
+                // Might fail if another thread helped already.
+                let _ = self
+                    .tail
+                    .compare_exchange(tail, new, Release, Relaxed, guard);
+                return;
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.pop_with(&epoch::pin())
+    }
+
+    fn pop_with(&self, guard: &Guard) -> Option<T> {
+        loop {
+            let head = self.head.load(Acquire, guard);
+            // Safety: never null, and we're pinned.
+            let next = unsafe { head.deref() }.next.load(Acquire, guard);
+            let next_node = unsafe { next.as_ref() }?;
+            if self
+                .head
+                .compare_exchange(head, next, Release, Relaxed, guard)
+                .is_ok()
+            {
+                // Don't leave the tail pointing at the node we're about to free.
+                let tail = self.tail.load(Relaxed, guard);
+                if tail == head {
+                    let _ = self
+                        .tail
+                        .compare_exchange(tail, next, Release, Relaxed, guard);
+                }
+                unsafe {
+                    guard.defer_destroy(head);
+                    // We made this node the sentinel, so the value is ours.
+                    return Some(next_node.value.assume_init_read());
+                }
+            }
+        }
+    }
+
+    /// Might be outdated by the time you look at it.
+    pub fn is_empty(&self) -> bool {
+        let guard = &epoch::pin();
+        let head = self.head.load(Acquire, guard);
+        unsafe { head.deref() }.next.load(Acquire, guard).is_null()
+    }
+}
+
+impl<T> Default for MsQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for MsQueue<T> {
+    fn drop(&mut self) {
+        unsafe {
+            // Safety: nobody else can be using the queue anymore.
+            let guard = epoch::unprotected();
+            while self.pop_with(guard).is_some() {}
+            drop(self.head.load(Relaxed, guard).into_owned());
+        }
+    }
+}
+
+impl<T> fmt::Debug for MsQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MsQueue").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stress::{Config, DropCounter};
+
+    #[test]
+    fn test_push_pop() {
+        let queue = MsQueue::new();
+        assert!(queue.is_empty());
+        queue.push(1);
+        queue.push(2);
+        assert!(!queue.is_empty());
+        assert_eq!(queue.pop(), Some(1));
+        queue.push(3);
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_drop() {
+        let counter = DropCounter::new();
+        let queue = MsQueue::new();
+        for _ in 0..10 {
+            queue.push(counter.track());
+        }
+        drop(queue.pop());
+        assert_eq!(counter.alive(), 9);
+        drop(queue);
+        assert_eq!(counter.alive(), 0);
+    }
+
+    /// Every thread pushes and pops at random. Nothing is lost or duplicated,
+    /// and everything pushed by one thread comes out in the same order.
+    #[test]
+    fn test_stress() {
+        let config = Config::from_env();
+        let counter = DropCounter::new();
+        let queue = MsQueue::new();
+        let results = stress::run(&config, |w| {
+            let mut pushed = 0;
+            // The last sequence number seen from every thread.
+            let mut last = vec![None; config.threads];
+            let mut popped = 0;
+            for _ in 0..w.iterations {
+                if w.rng.below(2) == 0 {
+                    queue.push((w.index, pushed, counter.track()));
+                    pushed += 1;
+                } else if let Some((thread, seq, _)) = queue.pop() {
+                    assert!(last[thread] < Some(seq), "out of order");
+                    last[thread] = Some(seq);
+                    popped += 1;
+                }
+            }
+            (pushed, popped)
+        });
+        let pushed: usize = results.iter().map(|r| r.0).sum();
+        let mut popped: usize = results.iter().map(|r| r.1).sum();
+        while queue.pop().is_some() {
+            popped += 1;
+        }
+        assert_eq!(pushed, popped);
+        assert_eq!(counter.alive(), 0);
+    }
+}
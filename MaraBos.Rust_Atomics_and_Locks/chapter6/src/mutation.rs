@@ -70,9 +70,47 @@ impl<T> Drop for Arc<T> {
     }
 }
 
+fn main() {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop;
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    let x = Arc::new((100, DetectDrop));
+    let mut y = x.clone();
+
+    let t = std::thread::spawn(move || {
+        println!("other thread: x.0: {}", x.0);
+        assert_eq!(x.0, 100);
+    });
+
+    println!("main thread: y.0: {}", y.0);
+    assert_eq!(y.0, 100);
+    t.join().unwrap();
+
+    y.get_mut().unwrap().0 = 200;
+    println!("main thread: y.0 after get_mut() modification {}", y.0);
+
+    println!("1. NUM_DROPS: {}", NUM_DROPS.load(Relaxed));
+    assert_eq!(NUM_DROPS.load(Relaxed), 0);
+
+    drop(y);
+
+    println!("2. NUM_DROP: {}", NUM_DROPS.load(Relaxed));
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+
+    println!("done");
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use stress::{Config, DropCounter, Mix, Stamp};
 
     #[test]
     fn test_1() {
@@ -143,41 +181,38 @@ mod tests {
         println!("2. NUM_DROP: {}", NUM_DROPS.load(Relaxed));
         assert_eq!(NUM_DROPS.load(Relaxed), 1);
     }
-}
-
-fn main() {
-    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
-
-    struct DetectDrop;
 
-    impl Drop for DetectDrop {
-        fn drop(&mut self) {
-            NUM_DROPS.fetch_add(1, Relaxed);
+    #[test]
+    fn test_stress() {
+        #[derive(Clone, Copy)]
+        enum Op {
+            Clone,
+            Drop,
+            Read,
         }
-    }
-
-    let x = Arc::new((100, DetectDrop));
-    let mut y = x.clone();
-
-    let t = std::thread::spawn(move || {
-        println!("other thread: x.0: {}", x.0);
-        assert_eq!(x.0, 100);
-    });
 
-    println!("main thread: y.0: {}", y.0);
-    assert_eq!(y.0, 100);
-    t.join().unwrap();
-
-    y.get_mut().unwrap().0 = 200;
-    println!("main thread: y.0 after get_mut() modification {}", y.0);
-
-    println!("1. NUM_DROPS: {}", NUM_DROPS.load(Relaxed));
-    assert_eq!(NUM_DROPS.load(Relaxed), 0);
-
-    drop(y);
-
-    println!("2. NUM_DROP: {}", NUM_DROPS.load(Relaxed));
-    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+        let counter = DropCounter::new();
+        let mut arc = Arc::new((Stamp::new(42), counter.track()));
+        let mix = Mix::new(&[(Op::Clone, 3), (Op::Drop, 3), (Op::Read, 2)]);
+
+        stress::run(&Config::from_env(), |w| {
+            let mut arcs = Vec::new();
+            for _ in 0..w.iterations {
+                match mix.pick(&mut w.rng) {
+                    Op::Clone => arcs.push(arc.clone()),
+                    Op::Drop => drop(arcs.pop()),
+                    Op::Read => {
+                        if let Some(a) = arcs.last() {
+                            assert_eq!(a.0.value(), 42);
+                        }
+                    }
+                }
+            }
+        });
 
-    println!("done");
+        assert_eq!(counter.alive(), 1);
+        assert!(arc.get_mut().is_some());
+        drop(arc);
+        assert_eq!(counter.alive(), 0);
+    }
 }
@@ -1,7 +1,11 @@
+use std::alloc::Layout;
+use std::borrow::Borrow;
 use std::cell::UnsafeCell;
-use std::mem::ManuallyDrop;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem::{ManuallyDrop, MaybeUninit};
 use std::ops::Deref;
-use std::ptr::NonNull;
+use std::ptr::{self, NonNull};
 use std::sync::atomic::{
     fence, AtomicUsize,
     Ordering::{Acquire, Relaxed, Release},
@@ -9,7 +13,9 @@ use std::sync::atomic::{
 
 // ArcData //
 
-struct ArcData<T> {
+// repr(C), so we know where `data` is, for `from_raw` and for slices.
+#[repr(C)]
+struct ArcData<T: ?Sized> {
     /// Number of `Arc`s.
     strong_count: AtomicUsize,
     /// Number of `Weak`s, plus one if there are any `Arc`s.
@@ -18,20 +24,43 @@ struct ArcData<T> {
     data: UnsafeCell<ManuallyDrop<T>>,
 }
 
+impl<T: ?Sized> ArcData<T> {
+    /// The offset of `data`, for a `T` with the given alignment.
+    fn data_offset(align: usize) -> usize {
+        let value = Layout::from_size_align(0, align).unwrap();
+        Layout::new::<ArcData<()>>().extend(value).unwrap().1
+    }
+}
+
 // Arc //
 
-pub struct Arc<T> {
+pub struct Arc<T: ?Sized> {
     ptr: NonNull<ArcData<T>>,
 }
 
-unsafe impl<T: Sync + Send> Send for Arc<T> {}
-unsafe impl<T: Sync + Send> Sync for Arc<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Send for Arc<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Sync for Arc<T> {}
+
+/// Turns an `Arc<T>` into an `Arc<dyn Trait>` (or an `Arc<[T; N]>` into an
+/// `Arc<[T]>`), like the unsized coercion std's Arc gets through the unstable
+/// `CoerceUnsized` trait. It goes through `into_raw` and `from_raw`, with the
+/// pointer coerced in between, which can only be an unsized coercion that
+/// keeps the same address. So unlike those two, this is safe.
+///
+/// ```ignore
+/// let a: Arc<dyn Display> = unsize_arc!(Arc::new(5));
+/// ```
+#[macro_export]
+macro_rules! unsize_arc {
+    ($arc:expr) => {{
+        // Not inline, so the type of `$arc` doesn't get inferred from the result.
+        let ptr = $crate::Arc::into_raw($arc);
+        // Safety: The same pointer, only (maybe) unsized.
+        unsafe { $crate::Arc::from_raw(ptr) }
+    }};
+}
 
 impl<T> Arc<T> {
-    fn inner_data(&self) -> &ArcData<T> {
-        unsafe { self.ptr.as_ref() }
-    }
-
     pub fn new(data: T) -> Arc<T> {
         Arc {
             ptr: NonNull::from(Box::leak(Box::new(ArcData {
@@ -42,9 +71,103 @@ impl<T> Arc<T> {
         }
     }
 
+    /// Creates an `Arc` to something that contains a `Weak` pointer to itself.
+    ///
+    /// Upgrading the `Weak` fails until `data_fn` has returned.
+    pub fn new_cyclic(data_fn: impl FnOnce(&Weak<T>) -> T) -> Arc<T> {
+        let uninit = Box::leak(Box::new(ArcData {
+            strong_count: AtomicUsize::new(0),
+            weak_count: AtomicUsize::new(1),
+            data: UnsafeCell::new(ManuallyDrop::new(MaybeUninit::<T>::uninit())),
+        }));
+        // MaybeUninit<T> has the same layout as T.
+        let ptr = NonNull::from(uninit).cast::<ArcData<T>>();
+        // The `Weak` that will become the implicit weak pointer of all `Arc`s.
+        let weak = ManuallyDrop::new(Weak { ptr });
+        // If this panics, the `Weak` is leaked, together with the allocation.
+        let data = data_fn(&weak);
+        unsafe { (*ptr.as_ref().data.get()) = ManuallyDrop::new(data) };
+        // Release, for `upgrade` (which might happen on another thread already)
+        // to see the data.
+        unsafe { ptr.as_ref() }.strong_count.store(1, Release);
+        Arc { ptr }
+    }
+
+    /// Returns the data if this is the only `Arc`, or gives the `Arc` back otherwise.
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        if this
+            .inner_data()
+            .strong_count
+            .compare_exchange(1, 0, Relaxed, Relaxed)
+            .is_err()
+        {
+            return Err(this);
+        }
+        // Acquire, to match the Release decrement of the other `Arc`s' `drop`.
+        fence(Acquire);
+        let this = ManuallyDrop::new(this);
+        let data = unsafe { ManuallyDrop::take(&mut *this.inner_data().data.get()) };
+        drop(Weak { ptr: this.ptr });
+        Ok(data)
+    }
+
+    /// Drops this `Arc`, and returns the data if this was the last one.
+    ///
+    /// Unlike `try_unwrap`, if several threads call this on their `Arc`s
+    /// at the same time, exactly one of them gets the data.
+    pub fn into_inner(this: Self) -> Option<T> {
+        let this = ManuallyDrop::new(this);
+        if this.inner_data().strong_count.fetch_sub(1, Release) != 1 {
+            return None;
+        }
+        fence(Acquire);
+        let data = unsafe { ManuallyDrop::take(&mut *this.inner_data().data.get()) };
+        drop(Weak { ptr: this.ptr });
+        Some(data)
+    }
+
+    /// Clone-on-write: like `get_mut`, but if there are other `Arc`s,
+    /// the data is cloned into a new allocation first.
+    /// If there are only `Weak`s, the data is moved to a new allocation,
+    /// leaving them unable to upgrade.
+    pub fn make_mut(this: &mut Self) -> &mut T
+    where
+        T: Clone,
+    {
+        // Acquire, to see everything the other `Arc`s did before they were dropped.
+        if this
+            .inner_data()
+            .strong_count
+            .compare_exchange(1, 0, Acquire, Relaxed)
+            .is_err()
+        {
+            *this = Arc::new(T::clone(this));
+        } else if this.inner_data().weak_count.load(Relaxed) != 1 {
+            // We were the last `Arc`, but there are `Weak`s.
+            // The strong count is already zero, so they can't upgrade anymore.
+            let data = unsafe { ManuallyDrop::take(&mut *this.inner_data().data.get()) };
+            let old = Weak { ptr: this.ptr };
+            unsafe { ptr::write(this, Arc::new(data)) };
+            drop(old);
+        } else {
+            // Nothing else points to it. Undo the zero.
+            this.inner_data().strong_count.store(1, Release);
+        }
+        // Safety: we just made sure this is the only pointer to the data.
+        unsafe { &mut *this.inner_data().data.get() }
+    }
+}
+
+impl<T: ?Sized> Arc<T> {
+    fn inner_data(&self) -> &ArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
     pub fn downgrade(&self) -> Weak<T> {
-        let mut n = self.inner_data().strong_count.load(Relaxed);
+        let mut n = self.inner_data().weak_count.load(Relaxed);
         loop {
+            // Locked by `get_mut`. It'll be unlocked right away,
+            // since that `get_mut` can't succeed while we hold this `Arc`.
             if n == usize::MAX {
                 std::hint::spin_loop();
                 n = self.inner_data().weak_count.load(Relaxed);
@@ -89,9 +212,120 @@ impl<T> Arc<T> {
         fence(Acquire);
         unsafe { Some(&mut *self.inner_data().data.get()) }
     }
+
+    /// The number of `Arc`s. Might already be outdated by the time you look at it.
+    pub fn strong_count(this: &Self) -> usize {
+        this.inner_data().strong_count.load(Relaxed)
+    }
+
+    /// The number of `Weak`s. Might already be outdated by the time you look at it.
+    pub fn weak_count(this: &Self) -> usize {
+        match this.inner_data().weak_count.load(Relaxed) {
+            // Locked by `get_mut`, which only happens when there are no `Weak`s.
+            usize::MAX => 0,
+            // Don't count the implicit one that represents all `Arc`s.
+            n => n - 1,
+        }
+    }
+
+    /// True if both point to the same allocation (not just equal values).
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        ptr::addr_eq(this.ptr.as_ptr(), other.ptr.as_ptr())
+    }
+
+    /// Consumes the `Arc`, without changing the reference counter,
+    /// and returns a pointer to the data.
+    /// Use `from_raw` to get the `Arc` back, or it will be leaked.
+    pub fn into_raw(this: Self) -> *const T {
+        let this = ManuallyDrop::new(this);
+        // Not through a reference, to keep the provenance of the whole allocation.
+        unsafe { ptr::addr_of!((*this.ptr.as_ptr()).data) as *const T }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw`, and every `into_raw` may only be
+    /// matched by one `from_raw`.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        let offset = ArcData::<T>::data_offset(std::mem::align_of_val(&*ptr));
+        Arc {
+            ptr: NonNull::new_unchecked(ptr.byte_sub(offset) as *mut ArcData<T>),
+        }
+    }
 }
 
-impl<T> Clone for Arc<T> {
+impl<T> Arc<[T]> {
+    /// Allocates an `ArcData` for `len` elements, with both counters set to one.
+    /// The elements still need to be written.
+    fn allocate_slice(len: usize) -> NonNull<ArcData<[T]>> {
+        let layout = Layout::new::<ArcData<()>>()
+            .extend(Layout::array::<T>(len).unwrap())
+            .unwrap()
+            .0
+            .pad_to_align();
+        let mem = unsafe { std::alloc::alloc(layout) };
+        if mem.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        let ptr = ptr::slice_from_raw_parts_mut(mem.cast::<T>(), len) as *mut ArcData<[T]>;
+        unsafe {
+            ptr::addr_of_mut!((*ptr).strong_count).write(AtomicUsize::new(1));
+            ptr::addr_of_mut!((*ptr).weak_count).write(AtomicUsize::new(1));
+            NonNull::new_unchecked(ptr)
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for Arc<[T]> {
+    fn from(mut v: Vec<T>) -> Self {
+        let ptr = Arc::allocate_slice(v.len());
+        unsafe {
+            let data = ptr::addr_of_mut!((*ptr.as_ptr()).data).cast::<T>();
+            ptr::copy_nonoverlapping(v.as_ptr(), data, v.len());
+            // The elements are moved out. Only free the Vec's buffer.
+            v.set_len(0);
+        }
+        Arc { ptr }
+    }
+}
+
+impl<T: Clone> From<&[T]> for Arc<[T]> {
+    fn from(s: &[T]) -> Self {
+        // Through a Vec, so nothing leaks if a clone panics.
+        Arc::from(s.to_vec())
+    }
+}
+
+impl<T> FromIterator<T> for Arc<[T]> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Arc::from(iter.into_iter().collect::<Vec<T>>())
+    }
+}
+
+impl From<&str> for Arc<str> {
+    fn from(s: &str) -> Self {
+        let bytes = Arc::<[u8]>::from(s.as_bytes());
+        let bytes = ManuallyDrop::new(bytes);
+        // Safety: the bytes are valid UTF-8, and str has the same layout as [u8].
+        Arc {
+            ptr: unsafe { NonNull::new_unchecked(bytes.ptr.as_ptr() as *mut ArcData<str>) },
+        }
+    }
+}
+
+impl From<String> for Arc<str> {
+    fn from(s: String) -> Self {
+        Arc::from(&s[..])
+    }
+}
+
+impl<T> From<T> for Arc<T> {
+    fn from(data: T) -> Self {
+        Arc::new(data)
+    }
+}
+
+impl<T: ?Sized> Clone for Arc<T> {
     fn clone(&self) -> Self {
         if self.inner_data().strong_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
             std::process::abort();
@@ -100,7 +334,7 @@ impl<T> Clone for Arc<T> {
     }
 }
 
-impl<T> Deref for Arc<T> {
+impl<T: ?Sized> Deref for Arc<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -110,7 +344,7 @@ impl<T> Deref for Arc<T> {
     }
 }
 
-impl<T> Drop for Arc<T> {
+impl<T: ?Sized> Drop for Arc<T> {
     fn drop(&mut self) {
         if self.inner_data().strong_count.fetch_sub(1, Release) == 1 {
             fence(Acquire);
@@ -128,16 +362,80 @@ impl<T> Drop for Arc<T> {
     }
 }
 
+// Forwarding traits //
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized> fmt::Pointer for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Pointer::fmt(&(&**self as *const T), f)
+    }
+}
+
+impl<T: ?Sized + PartialEq> PartialEq for Arc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq> Eq for Arc<T> {}
+
+impl<T: ?Sized + PartialOrd> PartialOrd for Arc<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Ord> Ord for Arc<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Hash> Hash for Arc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T: Default> Default for Arc<T> {
+    fn default() -> Self {
+        Arc::new(T::default())
+    }
+}
+
+impl<T: ?Sized> AsRef<T> for Arc<T> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized> Borrow<T> for Arc<T> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
 // Weak //
 
-pub struct Weak<T> {
+pub struct Weak<T: ?Sized> {
     ptr: NonNull<ArcData<T>>,
 }
 
-unsafe impl<T: Sync + Send> Send for Weak<T> {}
-unsafe impl<T: Sync + Send> Sync for Weak<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Send for Weak<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Sync for Weak<T> {}
 
-impl<T> Weak<T> {
+impl<T: ?Sized> Weak<T> {
     fn inner_data(&self) -> &ArcData<T> {
         unsafe { self.ptr.as_ref() }
     }
@@ -150,10 +448,12 @@ impl<T> Weak<T> {
                 return None;
             }
             assert!(n < usize::MAX / 2);
+            // Acquire, to match the Release store in `new_cyclic`:
+            // the Weak might have been upgraded before the data was there.
             if let Err(e) =
                 self.inner_data()
                     .strong_count
-                    .compare_exchange_weak(n, n + 1, Relaxed, Relaxed)
+                    .compare_exchange_weak(n, n + 1, Acquire, Relaxed)
             {
                 n = e;
                 continue;
@@ -163,7 +463,7 @@ impl<T> Weak<T> {
     }
 }
 
-impl<T> Clone for Weak<T> {
+impl<T: ?Sized> Clone for Weak<T> {
     fn clone(&self) -> Self {
         if self.inner_data().weak_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
             std::process::abort();
@@ -172,7 +472,7 @@ impl<T> Clone for Weak<T> {
     }
 }
 
-impl<T> Drop for Weak<T> {
+impl<T: ?Sized> Drop for Weak<T> {
     fn drop(&mut self) {
         if self.inner_data().weak_count.fetch_sub(1, Release) == 1 {
             fence(Acquire);
@@ -183,9 +483,57 @@ impl<T> Drop for Weak<T> {
     }
 }
 
+impl<T: ?Sized> fmt::Debug for Weak<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("(Weak)")
+    }
+}
+
+fn main() {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop;
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    let x = Arc::new((100, DetectDrop));
+    let mut y = x.clone();
+
+    let t = std::thread::spawn(move || {
+        println!("other thread: x.0: {}", x.0);
+        assert_eq!(x.0, 100);
+    });
+
+    println!("main thread: y.0: {}", y.0);
+    assert_eq!(y.0, 100);
+    t.join().unwrap();
+
+    y.get_mut().unwrap().0 = 200;
+    println!("main thread: y.0 after get_mut() modification {}", y.0);
+
+    println!("1. NUM_DROPS: {}", NUM_DROPS.load(Relaxed));
+    assert_eq!(NUM_DROPS.load(Relaxed), 0);
+
+    drop(y);
+
+    println!("2. NUM_DROP: {}", NUM_DROPS.load(Relaxed));
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+
+    let z: Arc<dyn std::fmt::Display> = unsize_arc!(Arc::new(300));
+    println!("main thread: z as dyn Display: {z}");
+
+    println!("done");
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::thread;
+    use stress::{Config, DropCounter, Mix, Stamp};
 
     #[test]
     fn test_1() {
@@ -288,41 +636,360 @@ mod tests {
         assert_eq!(NUM_DROPS.load(Relaxed), 1);
         assert!(weak2.upgrade().is_none());
     }
-}
 
-fn main() {
-    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+    #[test]
+    fn test_counts_and_ptr_eq() {
+        let a = Arc::new(1);
+        let b = a.clone();
+        let c = Arc::new(1);
+        let w = Arc::downgrade(&a);
+        assert_eq!(Arc::strong_count(&a), 2);
+        assert_eq!(Arc::weak_count(&a), 1);
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &c));
+        assert_eq!(a, c);
+        drop(w);
+        assert_eq!(Arc::weak_count(&a), 0);
+    }
 
-    struct DetectDrop;
+    #[test]
+    fn test_unwrap() {
+        let counter = DropCounter::new();
+        let a = Arc::new(counter.track());
+        let b = a.clone();
+        let a = Arc::try_unwrap(a).unwrap_err();
+        assert!(Arc::into_inner(b).is_none());
+        let weak = Arc::downgrade(&a);
+        let tracked = Arc::try_unwrap(a).ok().unwrap();
+        assert!(weak.upgrade().is_none());
+        assert_eq!(counter.alive(), 1);
+        drop(tracked);
+        assert_eq!(counter.alive(), 0);
+
+        // Exactly one of the threads gets the value.
+        let a = Arc::new(5);
+        let got = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    let a = a.clone();
+                    s.spawn(move || Arc::into_inner(a))
+                })
+                .collect();
+            drop(a);
+            threads
+                .into_iter()
+                .filter_map(|t| t.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(got, [5]);
+    }
 
-    impl Drop for DetectDrop {
-        fn drop(&mut self) {
-            NUM_DROPS.fetch_add(1, Relaxed);
+    #[test]
+    fn test_make_mut() {
+        let mut a = Arc::new(vec![1]);
+        Arc::make_mut(&mut a).push(2);
+        let b = a.clone();
+        Arc::make_mut(&mut a).push(3);
+        assert_eq!(*a, [1, 2, 3]);
+        assert_eq!(*b, [1, 2]);
+
+        // Only weak pointers left: moved out, not cloned.
+        let weak = Arc::downgrade(&b);
+        let mut b = b;
+        let before = b.as_ptr();
+        Arc::make_mut(&mut b).push(4);
+        assert_eq!(b.as_ptr(), before);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(Arc::strong_count(&b), 1);
+        assert_eq!(Arc::weak_count(&b), 0);
+    }
+
+    #[test]
+    fn test_raw() {
+        let counter = DropCounter::new();
+        let a = Arc::new((7u8, counter.track()));
+        let p = Arc::into_raw(a.clone());
+        assert_eq!(unsafe { (*p).0 }, 7);
+        let b = unsafe { Arc::from_raw(p) };
+        assert!(Arc::ptr_eq(&a, &b));
+        drop((a, b));
+        assert_eq!(counter.alive(), 0);
+
+        let s: Arc<str> = Arc::from("hello");
+        let p = Arc::into_raw(s);
+        let s = unsafe { Arc::from_raw(p) };
+        assert_eq!(&*s, "hello");
+    }
+
+    #[test]
+    fn test_cyclic() {
+        struct Node {
+            me: Weak<Node>,
+            value: i32,
         }
+        let node = Arc::new_cyclic(|me| {
+            assert!(me.upgrade().is_none());
+            Node {
+                me: me.clone(),
+                value: 3,
+            }
+        });
+        assert_eq!(node.me.upgrade().unwrap().value, 3);
+        assert_eq!(Arc::strong_count(&node), 1);
+        assert_eq!(Arc::weak_count(&node), 1);
     }
 
-    let x = Arc::new((100, DetectDrop));
-    let mut y = x.clone();
+    /// Another thread gets the Weak from `new_cyclic` while the data is
+    /// still being created, and keeps trying to upgrade it. Once that
+    /// works, it must see all of the data.
+    #[test]
+    fn test_cyclic_upgrade_on_other_thread() {
+        for _ in 0..100 {
+            // Kept until the other thread is done, so it's always upgradable.
+            let node = thread::scope(|s| {
+                Arc::new_cyclic(|me: &Weak<Vec<u64>>| {
+                    let me = me.clone();
+                    s.spawn(move || loop {
+                        if let Some(node) = me.upgrade() {
+                            assert!(node.iter().copied().eq(0..1000));
+                            break;
+                        }
+                        thread::yield_now();
+                    });
+                    thread::yield_now();
+                    (0..1000).collect()
+                })
+            });
+            assert_eq!(node.len(), 1000);
+        }
+    }
 
-    let t = std::thread::spawn(move || {
-        println!("other thread: x.0: {}", x.0);
-        assert_eq!(x.0, 100);
-    });
+    /// `downgrade` looks at the weak count, both for the lock that `get_mut`
+    /// takes, and for overflow. (It used to start from the strong count,
+    /// and panic once that was over usize::MAX / 2.)
+    #[test]
+    fn test_downgrade_uses_weak_count() {
+        let a = Arc::new(1);
+        // As if as many clones were leaked as `clone` allows.
+        a.inner_data()
+            .strong_count
+            .store(usize::MAX / 2 + 1, Relaxed);
+        let weak = Arc::downgrade(&a);
+        assert_eq!(Arc::weak_count(&a), 1);
+        a.inner_data().strong_count.store(1, Relaxed);
+        drop(weak);
+
+        // Locked, as if by `get_mut`: it waits for it to be unlocked.
+        a.inner_data().weak_count.store(usize::MAX, Relaxed);
+        thread::scope(|s| {
+            let t = s.spawn(|| Arc::downgrade(&a));
+            thread::sleep(std::time::Duration::from_millis(20));
+            assert!(!t.is_finished(), "downgraded a locked Arc");
+            a.inner_data().weak_count.store(1, Release);
+            drop(t.join().unwrap());
+        });
+        assert_eq!(Arc::weak_count(&a), 0);
+    }
 
-    println!("main thread: y.0: {}", y.0);
-    assert_eq!(y.0, 100);
-    t.join().unwrap();
+    #[test]
+    fn test_unsized() {
+        let counter = DropCounter::new();
+        let slice: Arc<[_]> = (0..3).map(|_| counter.track()).collect();
+        assert_eq!(slice.len(), 3);
+        let weak = Arc::downgrade(&slice);
+        drop(slice);
+        assert_eq!(counter.alive(), 0);
+        assert!(weak.upgrade().is_none());
+
+        let s: Arc<str> = Arc::from(String::from("hi"));
+        assert_eq!(format!("{s} {s:?}"), "hi \"hi\"");
+        let empty: Arc<[u64]> = Arc::from(Vec::new());
+        assert!(empty.is_empty());
+
+        let d: Arc<dyn std::fmt::Display + Send + Sync> = unsize_arc!(Arc::new(5));
+        let d2 = d.clone();
+        std::thread::spawn(move || assert_eq!(d2.to_string(), "5"))
+            .join()
+            .unwrap();
+        let array: Arc<[i32]> = unsize_arc!(Arc::new([1, 2, 3]));
+        assert_eq!(*array, [1, 2, 3]);
+        let boxed: Arc<dyn std::fmt::Debug + '_> = unsize_arc!(Arc::new((counter.track(), 1)));
+        assert_eq!(counter.alive(), 1);
+        drop(boxed);
+        assert_eq!(counter.alive(), 0);
+    }
 
-    y.get_mut().unwrap().0 = 200;
-    println!("main thread: y.0 after get_mut() modification {}", y.0);
+    #[test]
+    fn test_forwarding() {
+        use std::collections::HashSet;
+        let a: Arc<i32> = Arc::default();
+        assert_eq!(format!("{a} {a:?}"), "0 0");
+        let set: HashSet<Arc<str>> = ["a", "b", "a"].into_iter().map(Arc::from).collect();
+        assert_eq!(set.len(), 2);
+        assert!(set.contains("a"));
+        assert!(Arc::new(1) < Arc::new(2));
+    }
 
-    println!("1. NUM_DROPS: {}", NUM_DROPS.load(Relaxed));
-    assert_eq!(NUM_DROPS.load(Relaxed), 0);
+    #[test]
+    fn test_stress() {
+        #[derive(Clone, Copy)]
+        enum Op {
+            Clone,
+            Drop,
+            Read,
+            Downgrade,
+            Upgrade,
+        }
 
-    drop(y);
+        let counter = DropCounter::new();
+        let mut arc = Arc::new((Stamp::new(42), counter.track()));
+        let mix = Mix::new(&[
+            (Op::Clone, 3),
+            (Op::Drop, 3),
+            (Op::Read, 2),
+            (Op::Downgrade, 1),
+            (Op::Upgrade, 1),
+        ]);
+
+        stress::run(&Config::from_env(), |w| {
+            let mut arcs = Vec::new();
+            let mut weaks = Vec::new();
+            for _ in 0..w.iterations {
+                match mix.pick(&mut w.rng) {
+                    Op::Clone => arcs.push(arc.clone()),
+                    Op::Drop => drop(arcs.pop()),
+                    Op::Read => {
+                        if let Some(a) = arcs.last() {
+                            assert_eq!(a.0.value(), 42);
+                        }
+                    }
+                    Op::Downgrade => weaks.push(Arc::downgrade(&arc)),
+                    Op::Upgrade => {
+                        if let Some(weak) = weaks.pop() {
+                            // `arc` outlives all threads, so this never fails.
+                            arcs.push(weak.upgrade().unwrap());
+                        }
+                    }
+                }
+            }
+        });
 
-    println!("2. NUM_DROP: {}", NUM_DROPS.load(Relaxed));
-    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+        assert_eq!(counter.alive(), 1);
+        assert!(arc.get_mut().is_some());
+        drop(arc);
+        assert_eq!(counter.alive(), 0);
+    }
+}
 
-    println!("done");
+/// Many threads passing `Arc`s and `Weak`s to the same few allocations back
+/// and forth, while trying `get_mut` on whatever they hold, to check the
+/// "weak_count == usize::MAX as lock" trick: `get_mut` may only succeed
+/// if nothing else can reach the data, and nothing is dropped twice or leaked.
+#[cfg(test)]
+mod concurrent_tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread;
+    use stress::{Config, DropCounter, Mix, Stamp, Tracked};
+
+    struct Payload<'a> {
+        stamp: Stamp,
+        _tracked: Tracked<'a>,
+    }
+
+    #[derive(Clone, Copy)]
+    enum Op {
+        New,
+        Clone,
+        Drop,
+        Share,
+        Take,
+        Downgrade,
+        Upgrade,
+        GetMut,
+    }
+
+    #[test]
+    fn test_downgrade_upgrade_get_mut() {
+        let counter = DropCounter::new();
+        let config = Config::from_env();
+        // A few slots, so threads often hit the same ones.
+        let arcs: Vec<Mutex<Option<Arc<Payload>>>> = (0..4).map(|_| Mutex::new(None)).collect();
+        let weaks: Vec<Mutex<Option<Weak<Payload>>>> = (0..4).map(|_| Mutex::new(None)).collect();
+        let mix = Mix::new(&[
+            (Op::New, 1),
+            (Op::Clone, 2),
+            (Op::Drop, 2),
+            (Op::Share, 2),
+            (Op::Take, 2),
+            (Op::Downgrade, 2),
+            (Op::Upgrade, 3),
+            (Op::GetMut, 4),
+        ]);
+
+        let successes = stress::run(&config, |w| {
+            let mut local: Vec<Arc<Payload>> = Vec::new();
+            let mut get_mut_successes = 0;
+            for _ in 0..w.iterations {
+                let slot = w.rng.below(arcs.len());
+                match mix.pick(&mut w.rng) {
+                    Op::New => local.push(Arc::new(Payload {
+                        stamp: Stamp::new(0),
+                        _tracked: counter.track(),
+                    })),
+                    Op::Clone => {
+                        if let Some(a) = local.last() {
+                            local.push(a.clone());
+                        }
+                    }
+                    Op::Drop => drop(local.pop()),
+                    Op::Share => {
+                        if let Some(a) = local.last() {
+                            let old = arcs[slot].lock().unwrap().replace(a.clone());
+                            // Outside of the lock, to race with the others.
+                            drop(old);
+                        }
+                    }
+                    Op::Take => {
+                        let a = arcs[slot].lock().unwrap().take();
+                        local.extend(a);
+                    }
+                    Op::Downgrade => {
+                        if let Some(a) = local.last() {
+                            let old = weaks[slot].lock().unwrap().replace(Arc::downgrade(a));
+                            drop(old);
+                        }
+                    }
+                    Op::Upgrade => {
+                        let weak = weaks[slot].lock().unwrap().clone();
+                        if let Some(a) = weak.and_then(|weak| weak.upgrade()) {
+                            assert!(a.stamp.is_intact(), "torn read: {:?}", a.stamp);
+                            local.push(a);
+                        }
+                    }
+                    Op::GetMut => {
+                        if let Some(a) = local.last_mut() {
+                            if let Some(payload) = a.get_mut() {
+                                // More than one store, so anybody else looking
+                                // might see it half-written.
+                                let next = payload.stamp.next();
+                                payload.stamp = next;
+                                // Nobody may change it while we're not looking.
+                                thread::yield_now();
+                                assert_eq!(payload.stamp, next);
+                                get_mut_successes += 1;
+                            }
+                        }
+                    }
+                }
+            }
+            get_mut_successes
+        });
+
+        // Make sure the interesting case happened at all.
+        assert!(successes.iter().sum::<usize>() > 0);
+        drop(arcs);
+        drop(weaks);
+        assert_eq!(counter.alive(), 0);
+    }
 }
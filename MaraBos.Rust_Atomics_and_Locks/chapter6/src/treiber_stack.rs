@@ -0,0 +1,181 @@
+// Treiber stack
+//
+// A lock-free stack: a linked list, of which only the head pointer changes.
+// Pushing and popping are the same compare-and-exchange loop as in
+// lazy_initialization_with_indirection.rs (chapter 3) and id_allocation.rs
+// (chapter 2): load the head, prepare the new head, and try to swap it in.
+// If another thread got there first, try again with what it left behind.
+//
+// The hard part is popping: after loading the head, another thread might pop
+// and free it, before we get to read its `next` pointer. And if that memory
+// is reused for a new node that's pushed again, our compare-and-exchange
+// would succeed on a different node with a stale `next` (the ABA problem).
+// Both are solved by epoch.rs: a popped node is only freed once every thread
+// that was pinned at the time has unpinned, so as long as we are pinned,
+// the head we loaded can't be freed, let alone reused.
+
+use crate::epoch::{self, Atomic, Guard, Owned, Pointer, Shared};
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+struct Node<T> {
+    /// Moved out by whoever pops the node.
+    value: ManuallyDrop<T>,
+    /// Never changes once the node is pushed.
+    next: *const Node<T>,
+}
+
+pub struct TreiberStack<T> {
+    head: Atomic<Node<T>>,
+}
+
+unsafe impl<T: Send> Send for TreiberStack<T> {}
+unsafe impl<T: Send> Sync for TreiberStack<T> {}
+
+impl<T> TreiberStack<T> {
+    pub const fn new() -> Self {
+        Self {
+            head: Atomic::null(),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        let mut node = Owned::new(Node {
+            value: ManuallyDrop::new(value),
+            next: ptr::null(),
+        });
+        let guard = &epoch::pin();
+        let mut head = self.head.load(Relaxed, guard);
+        loop {
+            node.next = head.as_raw();
+            // Release: whoever pops it sees the value.
+            match self
+                .head
+                .compare_exchange(head, node, Release, Relaxed, guard)
+            {
+                Ok(_) => return,
+                Err(e) => {
+                    head = e.current;
+                    node = e.new;
+                }
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.pop_with(&epoch::pin())
+    }
+
+    fn pop_with(&self, guard: &Guard) -> Option<T> {
+        let mut head = self.head.load(Acquire, guard);
+        loop {
+            // Safety: we're pinned, so it isn't freed yet.
+            let node = unsafe { head.as_ref() }?;
+            let next = unsafe { Shared::from_raw(node.next as *mut Node<T>) };
+            match self
+                .head
+                .compare_exchange(head, next, Acquire, Acquire, guard)
+            {
+                Ok(_) => unsafe {
+                    // We're the only one who got this node out,
+                    // so we're the only one taking the value.
+                    let value = ptr::read(&*node.value);
+                    guard.defer_destroy(head);
+                    return Some(value);
+                },
+                Err(e) => head = e.current,
+            }
+        }
+    }
+
+    /// Might be outdated by the time you look at it.
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Acquire, &epoch::pin()).is_null()
+    }
+}
+
+impl<T> Default for TreiberStack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for TreiberStack<T> {
+    fn drop(&mut self) {
+        // Safety: nobody else can be using the stack anymore.
+        let guard = unsafe { epoch::unprotected() };
+        while self.pop_with(guard).is_some() {}
+    }
+}
+
+impl<T> fmt::Debug for TreiberStack<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TreiberStack").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stress::{Config, DropCounter, Stamp};
+
+    #[test]
+    fn test_push_pop() {
+        let stack = TreiberStack::new();
+        assert!(stack.is_empty());
+        stack.push(1);
+        stack.push(2);
+        stack.push(3);
+        assert!(!stack.is_empty());
+        assert_eq!(stack.pop(), Some(3));
+        assert_eq!(stack.pop(), Some(2));
+        stack.push(4);
+        assert_eq!(stack.pop(), Some(4));
+        assert_eq!(stack.pop(), Some(1));
+        assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn test_drop() {
+        let counter = DropCounter::new();
+        let stack = TreiberStack::new();
+        for _ in 0..10 {
+            stack.push(counter.track());
+        }
+        drop(stack.pop());
+        assert_eq!(counter.alive(), 9);
+        drop(stack);
+        assert_eq!(counter.alive(), 0);
+    }
+
+    /// Every thread pushes and pops at random.
+    /// Nothing is lost, duplicated or torn.
+    #[test]
+    fn test_stress() {
+        let counter = DropCounter::new();
+        let stack = TreiberStack::new();
+        let results = stress::run(&Config::from_env(), |w| {
+            let mut pushed = 0u64;
+            let mut popped = 0u64;
+            for _ in 0..w.iterations {
+                if w.rng.below(2) == 0 {
+                    stack.push((Stamp::new(w.rng.next_u64()), counter.track()));
+                    pushed += 1;
+                } else if let Some((stamp, _)) = stack.pop() {
+                    assert!(stamp.is_intact());
+                    popped += 1;
+                }
+            }
+            (pushed, popped)
+        });
+        let pushed: u64 = results.iter().map(|r| r.0).sum();
+        let mut popped: u64 = results.iter().map(|r| r.1).sum();
+        while stack.pop().is_some() {
+            popped += 1;
+        }
+        assert_eq!(pushed, popped);
+        assert_eq!(counter.alive(), 0);
+    }
+}
@@ -0,0 +1,95 @@
+// Library version of the pthread mutex from this chapter.
+//
+// PthreadMutex: from pthread_mutex.rs, plus `try_lock`.
+//
+// A pthread_mutex_t must not be moved once it's been used, while anything in
+// Rust can be moved at any time, so it lives in a Box that never moves.
+// (See https://marabos.nl/atomics/os-primitives.html#pthread)
+//
+// There's no data in it, just lock and unlock, for chapter9's generic Mutex
+// to wrap, or to use directly like in pthread_mutex.rs.
+
+#[cfg(not(unix))]
+compile_error!("Unix only. Sorry!");
+
+use std::cell::UnsafeCell;
+
+pub struct PthreadMutex {
+    m: Box<UnsafeCell<libc::pthread_mutex_t>>,
+}
+
+// Safety: pthread mutexes are made to be used from multiple threads.
+unsafe impl Send for PthreadMutex {}
+unsafe impl Sync for PthreadMutex {}
+
+impl PthreadMutex {
+    pub fn new() -> Self {
+        Self {
+            m: Box::new(UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER)),
+        }
+    }
+
+    pub fn lock(&self) {
+        let r = unsafe { libc::pthread_mutex_lock(self.m.get()) };
+        // Can only fail with EDEADLK (for error checking mutexes) or EINVAL.
+        assert_eq!(r, 0, "pthread_mutex_lock failed");
+    }
+
+    /// Returns false if it's already locked.
+    pub fn try_lock(&self) -> bool {
+        unsafe { libc::pthread_mutex_trylock(self.m.get()) == 0 }
+    }
+
+    /// # Safety
+    ///
+    /// Only call this on the thread that locked it, once.
+    pub unsafe fn unlock(&self) {
+        libc::pthread_mutex_unlock(self.m.get());
+    }
+
+    /// Might be outdated by the time you look at it.
+    ///
+    /// There's no way to ask a pthread mutex, so this tries to lock (and unlock) it.
+    pub fn is_locked(&self) -> bool {
+        if self.try_lock() {
+            unsafe { self.unlock() };
+            false
+        } else {
+            true
+        }
+    }
+}
+
+impl Default for PthreadMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PthreadMutex {
+    fn drop(&mut self) {
+        // Dropping a locked mutex (e.g. after mem::forget of a guard)
+        // makes destroy fail with EBUSY, which we can only ignore.
+        unsafe { libc::pthread_mutex_destroy(self.m.get()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_lock_unlock() {
+        let m = PthreadMutex::new();
+        m.lock();
+        assert!(m.is_locked());
+        thread::scope(|s| {
+            s.spawn(|| assert!(!m.try_lock()));
+        });
+        unsafe { m.unlock() };
+        assert!(!m.is_locked());
+        assert!(m.try_lock());
+        unsafe { m.unlock() };
+    }
+}
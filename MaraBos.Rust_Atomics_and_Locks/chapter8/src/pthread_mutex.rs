@@ -69,10 +69,10 @@ fn main() {
 */
 
 use std::cell::UnsafeCell;
-use std::sync::Arc;
 
 struct XMutex {
-    m: Arc<libc::pthread_mutex_t>,
+    // Boxed, since a pthread_mutex_t must not be moved once it's been used.
+    m: Box<UnsafeCell<libc::pthread_mutex_t>>,
 }
 
 unsafe impl Send for XMutex {}
@@ -89,21 +89,21 @@ impl XMutex {
         // }
 
         Self {
-            m: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+            m: Box::new(UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER)),
         }
     }
 
-    pub fn lock(&mut self) {
+    pub fn lock(&self) {
         unsafe {
             // libc::pthread_mutex_lock((&mut self.m) as *mut libc::pthread_mutex_t);
-            libc::pthread_mutex_lock(self.m.get() as *mut libc::pthread_mutex_t);
+            libc::pthread_mutex_lock(self.m.get());
         }
     }
 
-    pub fn unlock(&mut self) {
+    pub fn unlock(&self) {
         unsafe {
             // libc::pthread_mutex_unlock((&mut self.m) as *mut libc::pthread_mutex_t);
-            libc::pthread_mutex_unlock(self.m.get() as *mut libc::pthread_mutex_t);
+            libc::pthread_mutex_unlock(self.m.get());
         }
     }
 }
@@ -112,14 +112,14 @@ impl Drop for XMutex {
     fn drop(&mut self) {
         unsafe {
             // libc::pthread_mutex_destroy((&mut self.m) as *mut libc::pthread_mutex_t);
-            libc::pthread_mutex_destroy(self.m.get() as *mut libc::pthread_mutex_t);
+            libc::pthread_mutex_destroy(self.m.get());
         }
     }
 }
 
 fn main() {
     // let mut m1 = Arc::new(XMutex::new());
-    let mut m1 = XMutex::new();
+    let m1 = XMutex::new();
     // let m2 = Arc::clone(&m1);
     static mut DATA: [u32; 2] = [0u32, 0u32];
 
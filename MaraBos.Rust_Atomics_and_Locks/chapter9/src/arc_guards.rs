@@ -0,0 +1,263 @@
+// Guards that own an Arc of their lock, rather than borrowing it.
+//
+// A MutexGuard<'a, T> borrows the Mutex, so it can't outlive the stack frame
+// (or scope) that the Mutex is borrowed from. That rules out moving a held
+// lock into a thread::spawn, or keeping it in a struct without a lifetime.
+// These guards instead hold a clone of the Arc the lock lives in, which keeps
+// the lock alive for as long as the guard exists, so they're 'static
+// (as long as T is).
+//
+// Their Send and Sync are spelled out, rather than following the Arc: an
+// `Arc<Mutex<T>>` is Sync with only T: Send, but sharing a guard shares T.
+// Sharing one also shares the Arc (through `mutex` or `rwlock`), which another
+// thread could clone and lock later on, so Sync needs T: Send as well.
+// Sending an RWLock guard needs T: Sync too, since other readers
+// can be on other threads, just like for `Arc<RWLock<T>>` itself.
+//
+// Only with the "std" feature, for the Arc.
+
+use crate::{DefaultWait, Mutex, RWLock, WaitStrategy};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+// Mutex //
+
+impl<T, W: WaitStrategy> Mutex<T, W> {
+    pub fn lock_arc(self: &Arc<Self>) -> ArcMutexGuard<T, W> {
+        self.raw.lock();
+        ArcMutexGuard {
+            mutex: Arc::clone(self),
+            _marker: PhantomData,
+        }
+    }
+}
+
+pub struct ArcMutexGuard<T, W: WaitStrategy = DefaultWait> {
+    mutex: Arc<Mutex<T, W>>,
+    /// No automatic Send or Sync, only the ones below.
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Send, W: WaitStrategy> Send for ArcMutexGuard<T, W> {}
+unsafe impl<T: Send + Sync, W: WaitStrategy> Sync for ArcMutexGuard<T, W> {}
+
+impl<T, W: WaitStrategy> ArcMutexGuard<T, W> {
+    /// The Mutex this guard has locked.
+    pub fn mutex(guard: &Self) -> &Arc<Mutex<T, W>> {
+        &guard.mutex
+    }
+}
+
+impl<T, W: WaitStrategy> Deref for ArcMutexGuard<T, W> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T, W: WaitStrategy> DerefMut for ArcMutexGuard<T, W> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T, W: WaitStrategy> Drop for ArcMutexGuard<T, W> {
+    fn drop(&mut self) {
+        // Safety: The guard proves we locked it.
+        unsafe { self.mutex.raw.unlock() };
+    }
+}
+
+// RWLock //
+
+impl<T, W: WaitStrategy> RWLock<T, W> {
+    pub fn read_arc(self: &Arc<Self>) -> ArcReadGuard<T, W> {
+        self.raw.read();
+        ArcReadGuard {
+            rwlock: Arc::clone(self),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn write_arc(self: &Arc<Self>) -> ArcWriteGuard<T, W> {
+        self.raw.write();
+        ArcWriteGuard {
+            rwlock: Arc::clone(self),
+            _marker: PhantomData,
+        }
+    }
+}
+
+pub struct ArcReadGuard<T, W: WaitStrategy = DefaultWait> {
+    rwlock: Arc<RWLock<T, W>>,
+    /// No automatic Send or Sync, only the ones below.
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Send + Sync, W: WaitStrategy> Send for ArcReadGuard<T, W> {}
+unsafe impl<T: Send + Sync, W: WaitStrategy> Sync for ArcReadGuard<T, W> {}
+
+impl<T, W: WaitStrategy> ArcReadGuard<T, W> {
+    /// The RWLock this guard has read locked.
+    pub fn rwlock(guard: &Self) -> &Arc<RWLock<T, W>> {
+        &guard.rwlock
+    }
+}
+
+impl<T, W: WaitStrategy> Deref for ArcReadGuard<T, W> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T, W: WaitStrategy> Drop for ArcReadGuard<T, W> {
+    fn drop(&mut self) {
+        // Safety: The guard proves we read locked it.
+        unsafe { self.rwlock.raw.read_unlock() };
+    }
+}
+
+pub struct ArcWriteGuard<T, W: WaitStrategy = DefaultWait> {
+    rwlock: Arc<RWLock<T, W>>,
+    /// No automatic Send or Sync, only the ones below.
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Send + Sync, W: WaitStrategy> Send for ArcWriteGuard<T, W> {}
+unsafe impl<T: Send + Sync, W: WaitStrategy> Sync for ArcWriteGuard<T, W> {}
+
+impl<T, W: WaitStrategy> ArcWriteGuard<T, W> {
+    /// The RWLock this guard has write locked.
+    pub fn rwlock(guard: &Self) -> &Arc<RWLock<T, W>> {
+        &guard.rwlock
+    }
+}
+
+impl<T, W: WaitStrategy> Deref for ArcWriteGuard<T, W> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T, W: WaitStrategy> DerefMut for ArcWriteGuard<T, W> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<T, W: WaitStrategy> Drop for ArcWriteGuard<T, W> {
+    fn drop(&mut self) {
+        // Safety: The guard proves we write locked it.
+        unsafe { self.rwlock.raw.write_unlock() };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::sync::mpsc;
+    use std::thread;
+    use stress::{Config, Stamp};
+
+    #[test]
+    fn test_lock_arc_across_spawn() {
+        let mutex = Arc::new(Mutex::new(Vec::new()));
+        let mut guard = mutex.lock_arc();
+        guard.push(1);
+        // Hand the locked mutex over to another thread, which unlocks it.
+        let t = thread::spawn(move || {
+            guard.push(2);
+            assert_eq!(Arc::strong_count(ArcMutexGuard::mutex(&guard)), 2);
+        });
+        t.join().unwrap();
+        mutex.lock().push(3);
+        assert_eq!(*mutex.lock(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_guard_keeps_lock_alive() {
+        let rwlock = Arc::new(RWLock::new(String::from("hello")));
+        let read = rwlock.read_arc();
+        let read2 = rwlock.read_arc();
+        drop(rwlock);
+        assert_eq!(*read, "hello");
+        let rwlock = Arc::clone(ArcReadGuard::rwlock(&read2));
+        drop((read, read2));
+        let mut write = rwlock.write_arc();
+        drop(rwlock);
+        write.push('!');
+        let rwlock = Arc::clone(ArcWriteGuard::rwlock(&write));
+        drop(write);
+        assert_eq!(*rwlock.read(), "hello!");
+    }
+
+    #[test]
+    fn test_send_sync() {
+        use std::cell::Cell;
+        assert!(implements!(ArcMutexGuard<u32>: Send));
+        assert!(implements!(ArcMutexGuard<u32>: Sync));
+        assert!(implements!(ArcReadGuard<u32>: Send));
+        assert!(implements!(ArcReadGuard<u32>: Sync));
+        assert!(implements!(ArcWriteGuard<u32>: Send));
+        assert!(implements!(ArcWriteGuard<u32>: Sync));
+        // Sharing the guard shares the value, and a Cell can't be shared.
+        assert!(implements!(ArcMutexGuard<Cell<u32>>: Send));
+        assert!(!implements!(ArcMutexGuard<Cell<u32>>: Sync));
+        assert!(!implements!(ArcReadGuard<Cell<u32>>: Sync));
+        assert!(!implements!(ArcWriteGuard<Cell<u32>>: Sync));
+        // Nor can a value that must stay on its own thread, Sync or not.
+        assert!(!implements!(ArcMutexGuard<Rc<u32>>: Send));
+        assert!(!implements!(ArcMutexGuard<std::sync::MutexGuard<'static, u32>>: Sync));
+    }
+
+    /// Every thread locks, and passes the guard on to a helper thread,
+    /// which does the update and unlocks.
+    #[test]
+    fn test_stress() {
+        let mutex = Arc::new(Mutex::new(Stamp::new(0)));
+        let rwlock = Arc::new(RWLock::new(Stamp::new(0)));
+        let (mutex_send, mutex_recv) = mpsc::channel::<ArcMutexGuard<Stamp>>();
+        let (rwlock_send, rwlock_recv) = mpsc::channel::<ArcWriteGuard<Stamp>>();
+        let helpers = [
+            thread::spawn(move || {
+                for mut guard in mutex_recv {
+                    *guard = guard.next();
+                }
+            }),
+            thread::spawn(move || {
+                for mut guard in rwlock_recv {
+                    *guard = guard.next();
+                }
+            }),
+        ];
+        let locks: u64 = stress::run(&Config::from_env(), |w| {
+            let mut locks = 0;
+            for _ in 0..w.iterations {
+                match w.rng.below(3) {
+                    0 => mutex_send.send(mutex.lock_arc()).unwrap(),
+                    1 => rwlock_send.send(rwlock.write_arc()).unwrap(),
+                    _ => {
+                        assert!(rwlock.read_arc().is_intact(), "torn read");
+                        continue;
+                    }
+                }
+                locks += 1;
+            }
+            locks
+        })
+        .into_iter()
+        .sum();
+        drop((mutex_send, rwlock_send));
+        for helper in helpers {
+            helper.join().unwrap();
+        }
+        assert_eq!(mutex.lock().value() + rwlock.read().value(), locks);
+    }
+}
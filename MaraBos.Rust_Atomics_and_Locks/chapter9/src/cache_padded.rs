@@ -0,0 +1,39 @@
+// Cache line padding
+//
+// Aligns a value to its own cache line(s), so that two of them next to each
+// other in memory (e.g. in an array of shards, or the head and tail of a ring
+// buffer) don't end up on the same cache line. Otherwise, threads that only
+// touch one of them would still keep taking the line away from each other
+// (false sharing, see chapter7/caching7.rs).
+//
+// 128 rather than 64, since some processors fetch cache lines in pairs.
+
+use core::ops::{Deref, DerefMut};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(align(128))]
+pub struct CachePadded<T>(pub T);
+
+impl<T> CachePadded<T> {
+    pub const fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
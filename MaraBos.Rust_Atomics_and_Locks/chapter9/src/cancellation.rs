@@ -0,0 +1,398 @@
+// Cancellation token
+//
+// chapter2/stop_flag.rs, as something reusable: a flag that's set once,
+// to tell other threads (or async tasks) to stop what they're doing.
+//
+// Tokens form a tree: cancelling a token also cancels all its children,
+// and their children, and so on, but not its parent. That way, one part of
+// a program can be stopped on its own, while a shutdown still stops everything.
+//
+// The flag is an AtomicU32, so threads can block on it with a futex
+// (`wait_cancelled`), while async tasks register a Waker (`cancelled`).
+// Cancelling sets the flag first, and only then takes the list of children
+// and wakers, while holding the Mutex. Adding a child or a waker happens
+// under the same Mutex, after checking the flag, so none can be missed.
+//
+// A token only holds on to its children weakly. Dropped ones are pruned
+// whenever the list has doubled in length since the last time, so they don't
+// pile up, while adding a child still takes amortized constant time.
+// A child does keep its parent alive, so cancelling a grandparent
+// still reaches it after the parent in between was dropped. That makes for
+// chains of nodes only held alive by their child, which `Node::drop` frees
+// in a loop, rather than recursively.
+//
+// Likewise, a `Cancelled` future takes its waker out again when it's dropped.
+
+use crate::{futex, Mutex};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{
+    AtomicU32,
+    Ordering::{Acquire, Release},
+};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+struct Node {
+    /// 0: not cancelled
+    /// 1: cancelled
+    state: AtomicU32,
+    inner: Mutex<Inner>,
+    /// Only to keep it alive. Taken out by `drop`.
+    parent: Option<Arc<Node>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    children: Vec<Weak<Node>>,
+    /// Prune `children` when it gets this long.
+    prune_at: usize,
+    wakers: Vec<Waker>,
+}
+
+impl Inner {
+    fn add_child(&mut self, child: Weak<Node>) {
+        if self.children.len() >= self.prune_at {
+            self.children.retain(|c| c.strong_count() > 0);
+            self.prune_at = (self.children.len() * 2).max(16);
+        }
+        self.children.push(child);
+    }
+}
+
+impl Node {
+    fn is_cancelled(&self) -> bool {
+        self.state.load(Acquire) == 1
+    }
+
+    /// Returns the children, which still need to be cancelled.
+    fn cancel(&self) -> Vec<Weak<Node>> {
+        if self.state.swap(1, Release) == 1 {
+            return Vec::new();
+        }
+        let inner = std::mem::take(&mut *self.inner.lock());
+        atomic_wait::wake_all(&self.state);
+        for waker in inner.wakers {
+            waker.wake();
+        }
+        inner.children
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        // A loop rather than recursion, so dropping the last token of a long
+        // chain can't overflow the stack. Every parent that we held the last
+        // reference to is dropped here, after taking its own parent out.
+        let mut parent = self.parent.take();
+        while let Some(node) = parent {
+            parent = Arc::into_inner(node).and_then(|mut node| node.parent.take());
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CancellationToken {
+    node: Arc<Node>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::with_parent(None)
+    }
+
+    fn with_parent(parent: Option<Arc<Node>>) -> Self {
+        Self {
+            node: Arc::new(Node {
+                state: AtomicU32::new(0),
+                inner: Mutex::new(Inner::default()),
+                parent,
+            }),
+        }
+    }
+
+    /// A new token that's cancelled when this one is, but that
+    /// can also be cancelled on its own, without affecting this one.
+    ///
+    /// If this one is already cancelled, so is the child.
+    pub fn child_token(&self) -> Self {
+        let child = Self::with_parent(Some(self.node.clone()));
+        let mut inner = self.node.inner.lock();
+        // Checked while locked, so `cancel` can't have taken the children yet.
+        if self.node.is_cancelled() {
+            drop(inner);
+            child.cancel();
+        } else {
+            inner.add_child(Arc::downgrade(&child.node));
+        }
+        child
+    }
+
+    /// Cancels this token and all its descendants, and wakes up
+    /// everyone waiting for any of them. Does nothing the second time.
+    pub fn cancel(&self) {
+        // A loop rather than recursion, so a long chain of children
+        // can't overflow the stack.
+        let mut todo = self.node.cancel();
+        while let Some(child) = todo.pop() {
+            if let Some(child) = child.upgrade() {
+                todo.extend(child.cancel());
+            }
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.node.is_cancelled()
+    }
+
+    /// Blocks until the token is cancelled, or `timeout` has passed.
+    /// Returns whether it was cancelled.
+    ///
+    /// Use `Duration::MAX` to wait forever.
+    pub fn wait_cancelled(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        while !self.is_cancelled() {
+            match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return false;
+                    }
+                    futex::wait_timeout(&self.node.state, 0, remaining);
+                }
+                None => atomic_wait::wait(&self.node.state, 0),
+            }
+        }
+        true
+    }
+
+    /// A future that completes when the token is cancelled.
+    ///
+    /// It holds a clone of the token, so it can be moved into a spawned task.
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled {
+            token: self.clone(),
+            waker: None,
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("is_cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// From `CancellationToken::cancelled`.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Cancelled {
+    token: CancellationToken,
+    /// The one we put in the node's list, if any.
+    waker: Option<Waker>,
+}
+
+impl Future for Cancelled {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let node = &this.token.node;
+        if node.is_cancelled() {
+            return Poll::Ready(());
+        }
+        if this.waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+            return Poll::Pending;
+        }
+        let mut inner = node.inner.lock();
+        // Checked while locked, so `cancel` can't have taken the wakers yet.
+        if node.is_cancelled() {
+            return Poll::Ready(());
+        }
+        if let Some(old) = this.waker.take() {
+            remove_waker(&mut inner.wakers, &old);
+        }
+        inner.wakers.push(cx.waker().clone());
+        this.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Cancelled {
+    fn drop(&mut self) {
+        let node = &self.token.node;
+        if let Some(waker) = &self.waker {
+            // Once cancelled, the wakers are gone already.
+            if !node.is_cancelled() {
+                remove_waker(&mut node.inner.lock().wakers, waker);
+            }
+        }
+    }
+}
+
+/// Removes one waker that wakes the same task as `waker`.
+fn remove_waker(wakers: &mut Vec<Waker>, waker: &Waker) {
+    if let Some(i) = wakers.iter().position(|w| w.will_wake(waker)) {
+        wakers.swap_remove(i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use stress::{block_on, Config};
+
+    #[test]
+    fn test_hierarchy() {
+        let root = CancellationToken::new();
+        let a = root.child_token();
+        let a1 = a.child_token();
+        let b = root.child_token();
+        a.cancel();
+        assert!(a.is_cancelled() && a1.is_cancelled());
+        assert!(!root.is_cancelled() && !b.is_cancelled());
+        // Cancelling twice does nothing.
+        a.cancel();
+        let b1 = b.child_token();
+        drop(b);
+        root.cancel();
+        // Even though its parent was dropped.
+        assert!(b1.is_cancelled());
+        assert!(root.child_token().is_cancelled());
+    }
+
+    #[test]
+    fn test_dropped_children_are_removed() {
+        let root = CancellationToken::new();
+        for _ in 0..100 {
+            drop(root.child_token());
+        }
+        // Not pruned every time, but they don't pile up.
+        assert!(root.node.inner.lock().children.len() <= 16);
+        // The ones still alive are kept.
+        let alive: Vec<_> = (0..100).map(|_| root.child_token()).collect();
+        for _ in 0..100 {
+            drop(root.child_token());
+        }
+        let len = root.node.inner.lock().children.len();
+        assert!((100..=200).contains(&len));
+        root.cancel();
+        assert!(alive.iter().all(|t| t.is_cancelled()));
+    }
+
+    #[test]
+    fn test_dropped_futures_are_removed() {
+        let token = CancellationToken::new();
+        let mut cx = Context::from_waker(Waker::noop());
+        for _ in 0..100 {
+            let mut future = token.cancelled();
+            assert!(Pin::new(&mut future).poll(&mut cx).is_pending());
+            // Polling again doesn't add it again.
+            assert!(Pin::new(&mut future).poll(&mut cx).is_pending());
+        }
+        assert!(token.node.inner.lock().wakers.is_empty());
+        let mut futures: Vec<_> = (0..3).map(|_| token.cancelled()).collect();
+        for future in &mut futures {
+            assert!(Pin::new(future).poll(&mut cx).is_pending());
+        }
+        assert_eq!(token.node.inner.lock().wakers.len(), 3);
+        drop(futures.pop());
+        assert_eq!(token.node.inner.lock().wakers.len(), 2);
+        token.cancel();
+        for future in &mut futures {
+            assert!(Pin::new(future).poll(&mut cx).is_ready());
+        }
+    }
+
+    /// A long chain of tokens, where only the root and the last are left.
+    #[test]
+    fn test_long_chain() {
+        let root = CancellationToken::new();
+        let mut token = root.child_token();
+        let first = Arc::downgrade(&token.node);
+        for _ in 0..100_000 {
+            token = token.child_token();
+        }
+        root.cancel();
+        assert!(token.is_cancelled());
+        // Dropping the last one drops the whole chain.
+        drop(token);
+        assert_eq!(first.strong_count(), 0);
+    }
+
+    #[test]
+    fn test_wait_cancelled() {
+        let token = CancellationToken::new();
+        assert!(!token.wait_cancelled(Duration::from_millis(10)));
+        thread::scope(|s| {
+            let child = token.child_token();
+            let t = s.spawn(move || child.wait_cancelled(Duration::MAX));
+            thread::sleep(Duration::from_millis(10));
+            token.cancel();
+            assert!(t.join().unwrap());
+        });
+        assert!(token.wait_cancelled(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_cancelled_async() {
+        let token = CancellationToken::new();
+        let child = token.child_token();
+        let future = child.cancelled();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                token.cancel();
+            });
+            block_on(future);
+        });
+        block_on(child.cancelled());
+    }
+
+    /// Threads build a tree of tokens and cancel random ones, while
+    /// others wait for random ones. Every token below a cancelled
+    /// one ends up cancelled too.
+    #[test]
+    fn test_stress() {
+        let config = Config::from_env();
+        let root = CancellationToken::new();
+        let trees = stress::run(&config, |w| {
+            // (token, index of its parent)
+            let mut tokens = vec![(root.child_token(), 0)];
+            for _ in 0..w.iterations {
+                let i = w.rng.below(tokens.len());
+                match w.rng.below(8) {
+                    0 => tokens[i].0.cancel(),
+                    1 => _ = tokens[i].0.wait_cancelled(Duration::from_micros(10)),
+                    2 => {
+                        if tokens[i].0.is_cancelled() {
+                            block_on(tokens[i].0.cancelled());
+                        }
+                    }
+                    _ => tokens.push((tokens[i].0.child_token(), i)),
+                }
+            }
+            for (token, parent) in &tokens {
+                if tokens[*parent].0.is_cancelled() {
+                    assert!(token.is_cancelled(), "child not cancelled");
+                }
+            }
+            tokens
+        });
+        root.cancel();
+        for (token, _) in trees.iter().flatten() {
+            assert!(token.is_cancelled());
+        }
+    }
+}
@@ -0,0 +1,475 @@
+// Concurrent hash map
+//
+// chapter1/list_lock.rs puts a whole collection behind one Mutex, so every
+// thread that touches any element has to wait for all the others. Here, the
+// map is split into shards, each a normal HashMap behind its own RWLock.
+// The hash of a key picks the shard, so threads working on different keys
+// usually don't touch the same lock, and readers of the same shard don't
+// block each other at all.
+//
+// Every shard is aligned to its own cache line(s), so threads locking
+// neighbouring shards don't slow each other down through false sharing.
+//
+// Shards grow (and shrink, with `shrink_to_fit`) independently: a resize
+// rehashes only that shard's entries, while holding only that shard's lock.
+// `reserve_for` and `shrink_to_fit_for` resize only the shard of one key.
+//
+// Iterating takes a snapshot of one shard at a time. It never sees half of
+// an update, but it's not a snapshot of the whole map at one point in time.
+
+use crate::{CachePadded, MappedReadGuard, MappedWriteGuard, RWLock, ReadGuard, WriteGuard};
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+type Shard<K, V, S> = CachePadded<RWLock<HashMap<K, V, S>>>;
+
+pub struct ConcurrentHashMap<K, V, S = RandomState> {
+    shards: Box<[Shard<K, V, S>]>,
+    hasher: S,
+}
+
+impl<K: Hash + Eq, V> ConcurrentHashMap<K, V> {
+    /// Four shards per CPU.
+    pub fn new() -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_shards(cpus * 4)
+    }
+
+    pub fn with_shards(shards: usize) -> Self {
+        Self::with_shards_and_hasher(shards, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Clone> ConcurrentHashMap<K, V, S> {
+    /// `shards` is rounded up to a power of two.
+    pub fn with_shards_and_hasher(shards: usize, hasher: S) -> Self {
+        let shards = shards.max(1).next_power_of_two();
+        Self {
+            shards: (0..shards)
+                .map(|_| CachePadded(RWLock::new(HashMap::with_hasher(hasher.clone()))))
+                .collect(),
+            hasher,
+        }
+    }
+
+    fn shard<Q>(&self, key: &Q) -> &RWLock<HashMap<K, V, S>>
+    where
+        Q: Hash + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        // The shard's HashMap hashes the key again with the same hasher. It
+        // uses the bottom bits to pick a bucket, and the top 7 bits as a tag,
+        // to skip most non-matching entries without comparing keys. So take
+        // the bits right below those 7, which the HashMap doesn't use at all.
+        // Otherwise, all keys in a shard would share (some of) their tag.
+        // (With one shard, that's a shift by 64, which gives None.)
+        let bits = self.shards.len().trailing_zeros();
+        let index = (hash << 7).checked_shr(64 - bits).unwrap_or(0) as usize;
+        &self.shards[index]
+    }
+
+    /// A read-locked reference to the value.
+    ///
+    /// Blocks writers to the same shard until dropped, so don't hold on to it.
+    pub fn get<Q>(&self, key: &Q) -> Option<MappedReadGuard<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        ReadGuard::filter_map(self.shard(key).read(), |map| map.get(key)).ok()
+    }
+
+    /// A write-locked reference to the value.
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<MappedWriteGuard<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        WriteGuard::filter_map(self.shard(key).write(), |map| map.get_mut(key)).ok()
+    }
+
+    /// A copy of the value, without keeping anything locked.
+    pub fn get_cloned<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.shard(key).read().get(key).cloned()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).read().contains_key(key)
+    }
+
+    /// Returns the old value, if any.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).write().insert(key, value)
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).write().remove(key)
+    }
+
+    /// Write-locks the key's shard, to look at and change the entry
+    /// without another thread changing it in between.
+    pub fn entry(&self, key: K) -> Entry<'_, K, V, S> {
+        Entry {
+            shard: self.shard(&key).write(),
+            key,
+        }
+    }
+
+    /// Sums the lengths of all shards, so it might be outdated
+    /// (or never have been right) if other threads are inserting or removing.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.read().is_empty())
+    }
+
+    /// Removes everything, one shard at a time.
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.write().clear();
+        }
+    }
+
+    /// Makes room for `additional` more entries, spread evenly over the shards.
+    pub fn reserve(&self, additional: usize) {
+        let per_shard = additional.div_ceil(self.shards.len());
+        for shard in self.shards.iter() {
+            shard.write().reserve(per_shard);
+        }
+    }
+
+    /// Shrinks every shard to fit its entries, one shard at a time.
+    pub fn shrink_to_fit(&self) {
+        for shard in self.shards.iter() {
+            shard.write().shrink_to_fit();
+        }
+    }
+
+    /// Makes room for `additional` more entries in the shard of `key`,
+    /// e.g. before inserting many entries with keys that share a shard.
+    /// Only locks that one shard.
+    pub fn reserve_for<Q>(&self, key: &Q, additional: usize)
+    where
+        Q: Hash + ?Sized,
+    {
+        self.shard(key).write().reserve(additional);
+    }
+
+    /// Shrinks only the shard of `key` to fit its entries.
+    pub fn shrink_to_fit_for<Q>(&self, key: &Q)
+    where
+        Q: Hash + ?Sized,
+    {
+        self.shard(key).write().shrink_to_fit();
+    }
+
+    /// Iterates over copies of all entries, taking a snapshot of one shard
+    /// at a time: only one shard is read-locked at once, and only while
+    /// it's being copied.
+    pub fn iter(&self) -> Iter<'_, K, V, S>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        Iter {
+            shards: self.shards.iter(),
+            snapshot: Vec::new().into_iter(),
+        }
+    }
+
+    /// Calls `f` for every entry, with one shard read-locked at a time.
+    /// Doesn't copy anything, but blocks writers to that shard while `f` runs.
+    pub fn for_each(&self, mut f: impl FnMut(&K, &V)) {
+        for shard in self.shards.iter() {
+            for (k, v) in shard.read().iter() {
+                f(k, v);
+            }
+        }
+    }
+
+    /// Keeps only the entries for which `f` returns true,
+    /// with one shard write-locked at a time.
+    pub fn retain(&self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        for shard in self.shards.iter() {
+            shard.write().retain(&mut f);
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Default for ConcurrentHashMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, S> fmt::Debug for ConcurrentHashMap<K, V, S>
+where
+    K: Hash + Eq + fmt::Debug,
+    V: fmt::Debug,
+    S: BuildHasher + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut map = f.debug_map();
+        self.for_each(|k, v| {
+            map.entry(k, v);
+        });
+        map.finish()
+    }
+}
+
+impl<K: Hash + Eq, V> FromIterator<(K, V)> for ConcurrentHashMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let map = Self::new();
+        for (k, v) in iter {
+            map.insert(k, v);
+        }
+        map
+    }
+}
+
+// Iter //
+
+pub struct Iter<'a, K, V, S> {
+    shards: std::slice::Iter<'a, Shard<K, V, S>>,
+    snapshot: std::vec::IntoIter<(K, V)>,
+}
+
+impl<K: Clone, V: Clone, S> Iterator for Iter<'_, K, V, S> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            if let Some(entry) = self.snapshot.next() {
+                return Some(entry);
+            }
+            let shard = self.shards.next()?.read();
+            self.snapshot = shard
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Vec<_>>()
+                .into_iter();
+        }
+    }
+}
+
+// Entry //
+
+/// A key and its write-locked shard.
+pub struct Entry<'a, K, V, S> {
+    shard: WriteGuard<'a, HashMap<K, V, S>>,
+    key: K,
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> Entry<'a, K, V, S> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn get(&self) -> Option<&V> {
+        self.shard.get(&self.key)
+    }
+
+    pub fn is_occupied(&self) -> bool {
+        self.shard.contains_key(&self.key)
+    }
+
+    /// Changes the value, if there is one.
+    pub fn and_modify(mut self, f: impl FnOnce(&mut V)) -> Self {
+        if let Some(v) = self.shard.get_mut(&self.key) {
+            f(v);
+        }
+        self
+    }
+
+    pub fn or_insert(self, default: V) -> MappedWriteGuard<'a, V> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Only calls `default` if there's no value yet.
+    pub fn or_insert_with(self, default: impl FnOnce() -> V) -> MappedWriteGuard<'a, V> {
+        let key = self.key;
+        WriteGuard::map(self.shard, |map| map.entry(key).or_insert_with(default))
+    }
+
+    pub fn or_default(self) -> MappedWriteGuard<'a, V>
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    /// Returns the old value, if any.
+    pub fn insert(mut self, value: V) -> Option<V> {
+        self.shard.insert(self.key, value)
+    }
+
+    /// Returns the value, if there was one.
+    pub fn remove(mut self) -> Option<V> {
+        self.shard.remove(&self.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::thread;
+    use stress::{Config, Stamp};
+
+    #[test]
+    fn test_insert_get_remove() {
+        let map = ConcurrentHashMap::with_shards(4);
+        assert!(map.is_empty());
+        assert_eq!(map.insert("a", 1), None);
+        assert_eq!(map.insert("b", 2), None);
+        assert_eq!(map.insert("a", 3), Some(1));
+        assert_eq!(*map.get("a").unwrap(), 3);
+        assert!(map.get("c").is_none());
+        *map.get_mut("b").unwrap() += 10;
+        assert_eq!(map.get_cloned("b"), Some(12));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.remove("a"), Some(3));
+        assert!(!map.contains_key("a"));
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_entry() {
+        let map = ConcurrentHashMap::with_shards(1);
+        *map.entry("x").or_insert(0) += 1;
+        *map.entry("x").or_insert(0) += 1;
+        assert_eq!(*map.entry("y").or_insert_with(|| 5), 5);
+        let e = map.entry("x").and_modify(|v| *v *= 10);
+        assert!(e.is_occupied());
+        assert_eq!(e.get(), Some(&20));
+        drop(e);
+        assert_eq!(map.entry("z").remove(), None);
+        assert_eq!(map.entry("y").insert(6), Some(5));
+        let mut entries: Vec<_> = map.iter().collect();
+        entries.sort();
+        assert_eq!(entries, [("x", 20), ("y", 6)]);
+    }
+
+    #[test]
+    fn test_resize_shards() {
+        let map: ConcurrentHashMap<u32, u32> = (0..10_000).map(|i| (i, i)).collect();
+        map.retain(|k, _| k % 100 == 0);
+        map.shrink_to_fit();
+        map.reserve(1000);
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.iter().map(|(_, v)| v).sum::<u32>(), 495_000);
+    }
+
+    /// The keys in one shard don't all have the same top 7 bits,
+    /// which the shard's HashMap uses as a tag.
+    #[test]
+    fn test_shard_leaves_tag_bits() {
+        let map: ConcurrentHashMap<u32, ()> = ConcurrentHashMap::with_shards(128);
+        let first = map.shard(&0);
+        let tags: HashSet<u64> = (0..100_000)
+            .filter(|k| std::ptr::eq(map.shard(k), first))
+            .map(|k| map.hasher.hash_one(k) >> 57)
+            .collect();
+        assert!(tags.len() > 64, "only {} tags", tags.len());
+    }
+
+    #[test]
+    fn test_resize_one_shard() {
+        let map = ConcurrentHashMap::with_shards(64);
+        let a = 0;
+        let b = (1..)
+            .find(|k| !std::ptr::eq(map.shard(k), map.shard(&a)))
+            .unwrap();
+        let capacity = |k: &u32| map.shard(k).read().capacity();
+        map.reserve_for(&a, 1000);
+        assert!(capacity(&a) >= 1000);
+        assert_eq!(capacity(&b), 0);
+        map.insert(a, 1);
+        map.insert(b, 2);
+        let before = capacity(&b);
+        map.shrink_to_fit_for(&a);
+        assert!(capacity(&a) < 1000);
+        assert_eq!(capacity(&b), before);
+        assert_eq!(map.get_cloned(&a), Some(1));
+    }
+
+    /// A reader holding a guard on one shard doesn't block writers of another.
+    #[test]
+    fn test_shards_independent() {
+        let map = ConcurrentHashMap::with_shards(64);
+        // Two keys in different shards.
+        let a = 0;
+        let b = (1..)
+            .find(|k| !std::ptr::eq(map.shard(k), map.shard(&a)))
+            .unwrap();
+        map.insert(a, 0);
+        let guard = map.get(&a).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| map.insert(b, 1)).join().unwrap();
+        });
+        assert_eq!(*guard, 0);
+    }
+
+    /// Every thread increments shared counters through `entry`,
+    /// and inserts and removes its own keys, while iterators run.
+    /// No increment is lost and no value is torn.
+    #[test]
+    fn test_stress() {
+        let config = Config::from_env();
+        let map = ConcurrentHashMap::with_shards(8);
+        let counters = 16;
+        let increments: u64 = stress::run(&config, |w| {
+            let mut increments = 0;
+            for i in 0..w.iterations as u64 {
+                match w.rng.below(4) {
+                    0 => {
+                        let key = w.rng.below(counters) as u64;
+                        let mut v = map.entry(key).or_insert(Stamp::new(0));
+                        *v = v.next();
+                        increments += 1;
+                    }
+                    1 => {
+                        let key = ((1000 + w.index as u64) << 32) | i;
+                        map.insert(key, Stamp::new(i));
+                        assert_eq!(map.remove(&key).map(|s| s.value()), Some(i));
+                    }
+                    2 => {
+                        if let Some(v) = map.get(&(w.rng.below(counters) as u64)) {
+                            assert!(v.is_intact());
+                        }
+                    }
+                    _ => {
+                        if w.rng.below(64) == 0 {
+                            assert!(map.iter().all(|(_, v)| v.is_intact()));
+                        }
+                    }
+                }
+            }
+            increments
+        })
+        .into_iter()
+        .sum();
+        let total: u64 = map.iter().map(|(_, v)| v.value()).sum();
+        assert_eq!(total, increments);
+        assert!(map.len() <= counters);
+    }
+}
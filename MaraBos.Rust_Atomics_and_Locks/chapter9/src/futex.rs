@@ -0,0 +1,32 @@
+// Futex wait with a timeout, which `atomic_wait` doesn't provide.
+// Based on chapter8/src/futex.rs.
+
+#[cfg(not(target_os = "linux"))]
+compile_error!("Linux only. Sorry!");
+
+use std::sync::atomic::AtomicU32;
+use std::time::Duration;
+
+/// Waits until woken up, or until `timeout` passed.
+/// Returns false if it timed out.
+///
+/// Like `atomic_wait::wait`, this returns immediately if `a` isn't `expected`,
+/// and it can return spuriously.
+pub fn wait_timeout(a: &AtomicU32, expected: u32, timeout: Duration) -> bool {
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    };
+    // Refer to the futex(2) man page for the syscall signature.
+    // Private, just like the futexes used by `atomic_wait`.
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            &timeout as *const libc::timespec,
+        )
+    };
+    r == 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::ETIMEDOUT)
+}
@@ -0,0 +1,133 @@
+// How often the compare-and-exchange loops from chapter2/id_allocation.rs
+// and id_allocation_without_overflow.rs have to retry under contention,
+// and what that costs, compared to a plain `fetch_add` and to IdAllocator.
+//
+// `fetch_add` never retries, but can't stop at a maximum without going past
+// it first. `compare_exchange_weak` and `fetch_update` can, but every retry
+// is another round trip of the cache line between cores. LocalIds avoids
+// most of those by taking IDs from the shared counter a block at a time.
+//
+// Run with --release, and on a machine with many cores
+// for the numbers to mean anything.
+
+use chapter9::{IdAllocator, Overflow};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering::Relaxed};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const IDS: usize = 1_000_000;
+
+trait Strategy: Sync {
+    /// Returns the number of retries it took.
+    fn allocate(&self) -> u64;
+}
+
+struct FetchAdd(AtomicU32);
+
+impl Strategy for FetchAdd {
+    fn allocate(&self) -> u64 {
+        let id = self.0.fetch_add(1, Relaxed);
+        assert!(id < u32::MAX, "too many IDs!");
+        0
+    }
+}
+
+struct CompareExchange(AtomicU32);
+
+impl Strategy for CompareExchange {
+    fn allocate(&self) -> u64 {
+        let mut retries = 0;
+        let mut id = self.0.load(Relaxed);
+        loop {
+            assert!(id < u32::MAX, "too many IDs!");
+            match self.0.compare_exchange_weak(id, id + 1, Relaxed, Relaxed) {
+                Ok(_) => return retries,
+                Err(v) => {
+                    retries += 1;
+                    id = v;
+                }
+            }
+        }
+    }
+}
+
+struct FetchUpdate(AtomicU32);
+
+impl Strategy for FetchUpdate {
+    fn allocate(&self) -> u64 {
+        let mut attempts = 0;
+        self.0
+            .fetch_update(Relaxed, Relaxed, |n| {
+                attempts += 1;
+                n.checked_add(1)
+            })
+            .expect("too many IDs!");
+        attempts - 1
+    }
+}
+
+/// Runs `IDS` allocations in total, spread over `threads` threads,
+/// and returns the time and total number of retries.
+fn run(strategy: &impl Strategy, threads: usize) -> (Duration, u64) {
+    let retries = AtomicU64::new(0);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                let mut r = 0;
+                for _ in 0..IDS / threads {
+                    r += strategy.allocate();
+                }
+                retries.fetch_add(r, Relaxed);
+            });
+        }
+    });
+    (start.elapsed(), retries.into_inner())
+}
+
+/// The same, with IdAllocator, which counts its own retries.
+fn run_allocator(threads: usize, block_size: Option<u32>) -> (Duration, u64) {
+    let ids = IdAllocator::new(u32::MAX, Overflow::Panic);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| match block_size {
+                Some(block_size) => {
+                    let local = ids.local(block_size);
+                    for _ in 0..IDS / threads {
+                        local.allocate();
+                    }
+                }
+                None => {
+                    for _ in 0..IDS / threads {
+                        ids.allocate();
+                    }
+                }
+            });
+        }
+    });
+    (start.elapsed(), ids.retries())
+}
+
+fn report(name: &str, threads: usize, (duration, retries): (Duration, u64)) {
+    let ns = duration.as_nanos() as f64 / IDS as f64;
+    let rate = retries as f64 / IDS as f64 * 100.0;
+    println!("{name:>18} {threads:>2} threads: {ns:>7.1} ns/ID, {rate:>6.2}% retries");
+}
+
+fn main() {
+    for threads in [1, 2, 4, 8, 16] {
+        report(
+            "fetch_add",
+            threads,
+            run(&FetchAdd(AtomicU32::new(0)), threads),
+        );
+        let cas = CompareExchange(AtomicU32::new(0));
+        report("compare_exchange", threads, run(&cas, threads));
+        let update = FetchUpdate(AtomicU32::new(0));
+        report("fetch_update", threads, run(&update, threads));
+        report("IdAllocator", threads, run_allocator(threads, None));
+        report("LocalIds (64)", threads, run_allocator(threads, Some(64)));
+        println!();
+    }
+}
@@ -0,0 +1,310 @@
+// ID allocator
+//
+// chapter2/id_allocation.rs and id_allocation_without_overflow.rs, as
+// something reusable. Like `allocate_new_id` there, it's a compare-and-
+// exchange loop on a counter, so it never goes past the maximum, not even
+// for a moment (which `fetch_add` followed by a check would). What happens
+// when all IDs are used up is up to the `Overflow` policy.
+//
+// Every thread incrementing the same counter makes that counter's cache
+// line bounce between cores. `LocalIds` avoids most of that: it reserves a
+// whole block of IDs at once, and hands those out without touching any
+// shared state.
+//
+// Freed IDs go onto a free list (a TreiberStack from chapter6), from which
+// they are handed out again before any new ones.
+//
+// The number of failed compare-and-exchange operations is counted, just
+// like OOPS_COUNTER in chapter 2, for id_allocation_benchmark.rs.
+
+use chapter6::treiber_stack::TreiberStack;
+use std::cell::Cell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+/// What to do when all IDs are used up (and none were freed).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    Panic,
+    /// Keep returning the maximum ID.
+    Saturate,
+    /// Start from zero again, even if those IDs might still be in use.
+    Wrap,
+    /// Return None.
+    Fail,
+}
+
+pub struct IdAllocator {
+    /// The next new ID. Goes up to `max + 1`, which means they're used up.
+    next: AtomicU64,
+    max: u32,
+    overflow: Overflow,
+    free: TreiberStack<u32>,
+    retries: AtomicU64,
+}
+
+impl IdAllocator {
+    /// For the IDs `0..=max`.
+    pub const fn new(max: u32, overflow: Overflow) -> Self {
+        Self {
+            next: AtomicU64::new(0),
+            max,
+            overflow,
+            free: TreiberStack::new(),
+            retries: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns None only with `Overflow::Fail`.
+    pub fn allocate(&self) -> Option<u32> {
+        if let Some(id) = self.free.pop() {
+            return Some(id);
+        }
+        match self.reserve(1) {
+            Some(block) => Some(block.start as u32),
+            None => self.overflow(),
+        }
+    }
+
+    /// Makes `id` available again, for `allocate` to hand out before any new ones.
+    ///
+    /// Freeing an ID twice, or one that wasn't allocated,
+    /// makes it get handed out twice.
+    pub fn free(&self, id: u32) {
+        debug_assert!(id <= self.max, "ID out of range");
+        self.free.push(id);
+    }
+
+    /// For allocating IDs from one thread, `block_size` at a time.
+    pub fn local(&self, block_size: u32) -> LocalIds<'_> {
+        assert!(block_size > 0, "block size must be non-zero");
+        LocalIds {
+            allocator: self,
+            block: Cell::new(Block { start: 0, end: 0 }),
+            block_size,
+            _not_sync: PhantomData,
+        }
+    }
+
+    pub fn max(&self) -> u32 {
+        self.max
+    }
+
+    /// The number of times a compare-and-exchange on the counter failed
+    /// because another thread changed it first.
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Relaxed)
+    }
+
+    /// Takes up to `n` new IDs, or None if they're used up.
+    fn reserve(&self, n: u32) -> Option<Block> {
+        let end_of_ids = self.max as u64 + 1;
+        let mut next = self.next.load(Relaxed);
+        loop {
+            let start = match next {
+                n if n < end_of_ids => n,
+                _ if self.overflow == Overflow::Wrap => 0,
+                _ => return None,
+            };
+            let end = (start + n as u64).min(end_of_ids);
+            match self.next.compare_exchange_weak(next, end, Relaxed, Relaxed) {
+                Ok(_) => return Some(Block { start, end }),
+                Err(v) => {
+                    self.retries.fetch_add(1, Relaxed);
+                    next = v;
+                }
+            }
+        }
+    }
+
+    #[cold]
+    fn overflow(&self) -> Option<u32> {
+        match self.overflow {
+            Overflow::Panic => panic!("too many IDs!"),
+            Overflow::Saturate => Some(self.max),
+            Overflow::Fail => None,
+            Overflow::Wrap => unreachable!("wrapping never runs out"),
+        }
+    }
+}
+
+impl fmt::Debug for IdAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IdAllocator")
+            .field("next", &self.next.load(Relaxed))
+            .field("max", &self.max)
+            .field("overflow", &self.overflow)
+            .finish_non_exhaustive()
+    }
+}
+
+/// `start..end`, which can go up to 2^32.
+#[derive(Clone, Copy)]
+struct Block {
+    start: u64,
+    end: u64,
+}
+
+// LocalIds //
+
+/// From `IdAllocator::local`. Can be sent to another thread, but not shared.
+///
+/// Dropping it puts the IDs it reserved but didn't hand out on the free list.
+pub struct LocalIds<'a> {
+    allocator: &'a IdAllocator,
+    block: Cell<Block>,
+    block_size: u32,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl LocalIds<'_> {
+    /// Returns None only with `Overflow::Fail`.
+    pub fn allocate(&self) -> Option<u32> {
+        let Block { start, end } = self.block.get();
+        if start < end {
+            self.block.set(Block {
+                start: start + 1,
+                end,
+            });
+            return Some(start as u32);
+        }
+        if let Some(id) = self.allocator.free.pop() {
+            return Some(id);
+        }
+        match self.allocator.reserve(self.block_size) {
+            Some(block) => {
+                self.block.set(Block {
+                    start: block.start + 1,
+                    end: block.end,
+                });
+                Some(block.start as u32)
+            }
+            None => self.allocator.overflow(),
+        }
+    }
+
+    pub fn free(&self, id: u32) {
+        self.allocator.free(id);
+    }
+
+    pub fn allocator(&self) -> &IdAllocator {
+        self.allocator
+    }
+}
+
+impl Drop for LocalIds<'_> {
+    fn drop(&mut self) {
+        let Block { start, end } = self.block.get();
+        for id in start..end {
+            self.allocator.free.push(id as u32);
+        }
+    }
+}
+
+impl fmt::Debug for LocalIds<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LocalIds")
+            .field("block_size", &self.block_size)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::panic::{self, AssertUnwindSafe};
+    use stress::Config;
+
+    #[test]
+    fn test_overflow() {
+        let ids = IdAllocator::new(2, Overflow::Fail);
+        let all: Vec<_> = (0..4).map(|_| ids.allocate()).collect();
+        assert_eq!(all, [Some(0), Some(1), Some(2), None]);
+
+        let ids = IdAllocator::new(2, Overflow::Saturate);
+        let all: Vec<_> = (0..4).map(|_| ids.allocate().unwrap()).collect();
+        assert_eq!(all, [0, 1, 2, 2]);
+
+        let ids = IdAllocator::new(2, Overflow::Wrap);
+        let all: Vec<_> = (0..5).map(|_| ids.allocate().unwrap()).collect();
+        assert_eq!(all, [0, 1, 2, 0, 1]);
+
+        let ids = IdAllocator::new(1, Overflow::Panic);
+        ids.allocate();
+        ids.allocate();
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| ids.allocate())).is_err());
+        // Unlike allocate_new_id_2 in chapter 2,
+        // the counter didn't go past the maximum.
+        assert_eq!(ids.next.load(Relaxed), 2);
+        // A freed one can still be allocated.
+        ids.free(0);
+        assert_eq!(ids.allocate(), Some(0));
+
+        // The full range, where the end doesn't fit in a u32.
+        let ids = IdAllocator::new(u32::MAX, Overflow::Fail);
+        ids.next.store(u32::MAX as u64 - 1, Relaxed);
+        let local = ids.local(10);
+        assert_eq!(local.allocate(), Some(u32::MAX - 1));
+        assert_eq!(local.allocate(), Some(u32::MAX));
+        assert_eq!(local.allocate(), None);
+    }
+
+    #[test]
+    fn test_free_list() {
+        let ids = IdAllocator::new(100, Overflow::Fail);
+        for i in 0..5 {
+            assert_eq!(ids.allocate(), Some(i));
+        }
+        ids.free(1);
+        ids.free(3);
+        assert_eq!(ids.allocate(), Some(3));
+        assert_eq!(ids.allocate(), Some(1));
+        assert_eq!(ids.allocate(), Some(5));
+    }
+
+    #[test]
+    fn test_local() {
+        let ids = IdAllocator::new(100, Overflow::Fail);
+        let a = ids.local(10);
+        let b = ids.local(10);
+        assert_eq!(a.allocate(), Some(0));
+        assert_eq!(b.allocate(), Some(10));
+        assert_eq!(a.allocate(), Some(1));
+        assert_eq!(ids.allocate(), Some(20));
+        // The rest of a's block goes to the free list.
+        drop(a);
+        let mut freed: Vec<_> = (0..8).map(|_| ids.allocate().unwrap()).collect();
+        freed.sort();
+        assert_eq!(freed, (2..10).collect::<Vec<_>>());
+        assert_eq!(ids.allocate(), Some(21));
+    }
+
+    /// Threads allocate (some of them through LocalIds) and free IDs.
+    /// No ID is ever in use twice at the same time.
+    #[test]
+    fn test_stress() {
+        let config = Config::from_env();
+        let ids = IdAllocator::new(u32::MAX, Overflow::Panic);
+        let all = stress::run(&config, |w| {
+            let local = ids.local(1 + w.rng.below(16) as u32);
+            let mut mine = Vec::new();
+            for _ in 0..w.iterations {
+                match w.rng.below(4) {
+                    0 if !mine.is_empty() => {
+                        let id = mine.swap_remove(w.rng.below(mine.len()));
+                        local.free(id);
+                    }
+                    1 => mine.push(ids.allocate().unwrap()),
+                    _ => mine.push(local.allocate().unwrap()),
+                }
+            }
+            mine
+        });
+        let mut seen = HashSet::new();
+        for id in all.into_iter().flatten() {
+            assert!(seen.insert(id), "ID {id} allocated twice");
+        }
+    }
+}
@@ -0,0 +1,767 @@
+// Library versions of the locks from this chapter, for the other chapters to build on.
+//
+// Mutex: from mutex_3state_optimizing_further.rs, with the locking itself in raw.rs.
+// Condvar: from condvar2.rs, plus `wait_timeout`,
+//          and skipping the syscall in `notify_one` as well if nobody is waiting.
+// RWLock: from rwlock3.rs, plus `map`/`filter_map` on the guards,
+//         to hand out a guard for just a part of the value.
+//         Also with the locking itself in raw.rs.
+// futex: a futex wait with a timeout, for `Condvar::wait_timeout`
+//        and for anything else that needs one.
+// ArcMutexGuard, ArcReadGuard, ArcWriteGuard: from `lock_arc`, `read_arc` and
+//     `write_arc`, guards that own an Arc of their lock, rather than borrowing it.
+// CancellationToken: from chapter2/stop_flag.rs, a stop flag with child tokens,
+//                    to wait for with a futex, or to await.
+// ConcurrentHashMap: a HashMap split into shards, each behind its own RWLock.
+// IdAllocator: from chapter2/id_allocation.rs, with a choice of what to do on
+//              overflow, blocks of IDs per thread, and recycling freed IDs.
+// Once: runs something only once, with the others waiting for it to finish.
+// SpinLock: re-exported from chapter4.
+// lock_api: a Mutex and RwLock generic over the raw lock inside,
+//           to switch between the ones in raw.rs, chapter4 and chapter8.
+// ThreadPool: work-stealing workers on chapter6's Chase–Lev deques,
+//             with `spawn`, `scope` and `join`, sleeping on a Condvar.
+// par: `par_for_each`, `par_map`, `par_chunks` and `par_reduce` on a ThreadPool.
+// ProgressTracker: from chapter2's progress reporting and statistics.rs,
+//                  counting items done, their average and peak time, and the ETA.
+// ConcurrentStats: also from statistics.rs, but with consistent snapshots of the
+//                  count, min, max, mean and a histogram for percentiles.
+// CachePadded: aligns a value to its own cache line(s), against false sharing.
+//
+// Mutex, RWLock and Once wait through a WaitStrategy (see wait.rs),
+// which is a futex, unless the "futex" feature is disabled.
+//
+// Without the (default) "std" feature, this is a #![no_std] library,
+// with only Mutex, RWLock, Once, SpinLock, CachePadded, raw and lock_api.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{
+    AtomicU32,
+    Ordering::{Acquire, Release},
+};
+#[cfg(feature = "std")]
+use std::{
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+    time::Duration,
+};
+
+/// Whether a type implements a trait, as a bool rather than a
+/// compile error, so tests can check that something is *not* Sync.
+///
+/// The inherent `YES` is only there if `$t: $trait`, and wins over the
+/// one from the trait if it is.
+#[cfg(test)]
+macro_rules! implements {
+    ($t:ty: $trait:path) => {{
+        struct Check<T: ?Sized>(core::marker::PhantomData<T>);
+        #[allow(dead_code)]
+        trait No {
+            const YES: bool = false;
+        }
+        impl<T: ?Sized> No for Check<T> {}
+        #[allow(dead_code)]
+        impl<T: ?Sized + $trait> Check<T> {
+            const YES: bool = true;
+        }
+        Check::<$t>::YES
+    }};
+}
+
+#[cfg(feature = "std")]
+mod arc_guards;
+pub mod cache_padded;
+#[cfg(feature = "std")]
+pub mod cancellation;
+#[cfg(feature = "std")]
+pub mod concurrent_hash_map;
+#[cfg(feature = "std")]
+pub mod futex;
+#[cfg(feature = "std")]
+pub mod id_allocator;
+pub mod lock_api;
+#[cfg(feature = "std")]
+pub mod par;
+#[cfg(feature = "std")]
+pub mod progress;
+pub mod raw;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod thread_pool;
+pub mod wait;
+
+#[cfg(feature = "std")]
+pub use arc_guards::{ArcMutexGuard, ArcReadGuard, ArcWriteGuard};
+pub use cache_padded::CachePadded;
+pub use chapter4::{Backoff, SpinLock};
+#[cfg(feature = "std")]
+pub use cancellation::CancellationToken;
+#[cfg(feature = "std")]
+pub use id_allocator::{IdAllocator, Overflow};
+#[cfg(feature = "std")]
+pub use progress::{Progress, ProgressTracker};
+#[cfg(feature = "std")]
+pub use stats::{ConcurrentStats, StatsSnapshot};
+#[cfg(feature = "std")]
+pub use thread_pool::ThreadPool;
+pub use wait::{DefaultWait, WaitStrategy};
+
+use raw::{RawRWLock, ThreeStateMutex};
+
+// Mutex //
+
+pub struct Mutex<T, W: WaitStrategy = DefaultWait> {
+    raw: ThreeStateMutex<W>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T, W: WaitStrategy> Sync for Mutex<T, W> where T: Send {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self::with_strategy(value)
+    }
+}
+
+impl<T, W: WaitStrategy> Mutex<T, W> {
+    /// E.g. `Mutex::<_, Spin>::with_strategy(value)`.
+    pub const fn with_strategy(value: T) -> Self {
+        Self {
+            raw: ThreeStateMutex::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[inline]
+    pub fn lock(&self) -> MutexGuard<'_, T, W> {
+        self.raw.lock();
+        MutexGuard { mutex: self }
+    }
+}
+
+// MutexGuard //
+
+pub struct MutexGuard<'a, T, W: WaitStrategy = DefaultWait> {
+    mutex: &'a Mutex<T, W>,
+}
+
+// Not the automatic one, which would follow `Mutex: Sync` and only need T: Send.
+unsafe impl<T, W: WaitStrategy> Sync for MutexGuard<'_, T, W> where T: Sync {}
+
+impl<T, W: WaitStrategy> Deref for MutexGuard<'_, T, W> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T, W: WaitStrategy> DerefMut for MutexGuard<'_, T, W> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T, W: WaitStrategy> Drop for MutexGuard<'_, T, W> {
+    #[inline]
+    fn drop(&mut self) {
+        // Safety: The guard proves we locked it.
+        unsafe { self.mutex.raw.unlock() };
+    }
+}
+
+// Condvar //
+
+/// Always waits with a futex itself, whatever the Mutex it's used with does.
+#[cfg(feature = "std")]
+pub struct Condvar {
+    counter: AtomicU32,
+    num_waiters: AtomicUsize,
+}
+
+#[cfg(feature = "std")]
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            counter: AtomicU32::new(0),
+            num_waiters: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn notify_one(&self) {
+        if self.num_waiters.load(Relaxed) > 0 {
+            self.counter.fetch_add(1, Relaxed);
+            atomic_wait::wake_one(&self.counter);
+        }
+    }
+
+    pub fn notify_all(&self) {
+        if self.num_waiters.load(Relaxed) > 0 {
+            self.counter.fetch_add(1, Relaxed);
+            atomic_wait::wake_all(&self.counter);
+        }
+    }
+
+    pub fn wait<'a, T, W: WaitStrategy>(
+        &self,
+        guard: MutexGuard<'a, T, W>,
+    ) -> MutexGuard<'a, T, W> {
+        self.num_waiters.fetch_add(1, Relaxed);
+
+        let counter_value = self.counter.load(Relaxed);
+
+        let mutex = guard.mutex;
+        drop(guard);
+
+        atomic_wait::wait(&self.counter, counter_value);
+
+        self.num_waiters.fetch_sub(1, Relaxed);
+
+        mutex.lock()
+    }
+
+    /// Like `wait`, but gives up after `timeout`.
+    /// The returned bool is true if it timed out.
+    ///
+    /// Just like `wait`, this can wake up spuriously,
+    /// so the caller has to check its condition (and the time) again.
+    pub fn wait_timeout<'a, T, W: WaitStrategy>(
+        &self,
+        guard: MutexGuard<'a, T, W>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T, W>, bool) {
+        self.num_waiters.fetch_add(1, Relaxed);
+
+        let counter_value = self.counter.load(Relaxed);
+
+        let mutex = guard.mutex;
+        drop(guard);
+
+        let woken = futex::wait_timeout(&self.counter, counter_value, timeout);
+
+        self.num_waiters.fetch_sub(1, Relaxed);
+
+        (mutex.lock(), !woken)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// RWLock //
+
+pub struct RWLock<T, W: WaitStrategy = DefaultWait> {
+    raw: RawRWLock<W>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T, W: WaitStrategy> Sync for RWLock<T, W> where T: Send + Sync {}
+
+impl<T> RWLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self::with_strategy(value)
+    }
+}
+
+impl<T, W: WaitStrategy> RWLock<T, W> {
+    /// E.g. `RWLock::<_, Spin>::with_strategy(value)`.
+    pub const fn with_strategy(value: T) -> Self {
+        Self {
+            raw: RawRWLock::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> ReadGuard<'_, T, W> {
+        self.raw.read();
+        ReadGuard { rwlock: self }
+    }
+
+    pub fn write(&self) -> WriteGuard<'_, T, W> {
+        self.raw.write();
+        WriteGuard { rwlock: self }
+    }
+}
+
+// ReadGuard //
+
+pub struct ReadGuard<'a, T, W: WaitStrategy = DefaultWait> {
+    rwlock: &'a RWLock<T, W>,
+}
+
+unsafe impl<T, W: WaitStrategy> Sync for ReadGuard<'_, T, W> where T: Sync {}
+
+impl<T, W: WaitStrategy> Deref for ReadGuard<'_, T, W> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T, W: WaitStrategy> Drop for ReadGuard<'_, T, W> {
+    fn drop(&mut self) {
+        // Safety: The guard proves we read locked it.
+        unsafe { self.rwlock.raw.read_unlock() };
+    }
+}
+
+impl<'a, T, W: WaitStrategy> ReadGuard<'a, T, W> {
+    /// A guard for a part of the value, e.g. one element of a collection.
+    pub fn map<U: ?Sized>(guard: Self, f: impl FnOnce(&T) -> &U) -> MappedReadGuard<'a, U, W> {
+        match Self::filter_map(guard, |v| Some(f(v))) {
+            Ok(mapped) => mapped,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `map`, but gives the guard back if `f` returns None.
+    pub fn filter_map<U: ?Sized>(
+        guard: Self,
+        f: impl FnOnce(&T) -> Option<&U>,
+    ) -> Result<MappedReadGuard<'a, U, W>, Self> {
+        let rwlock = guard.rwlock;
+        // Safety: the reference can live as long as the read lock,
+        // which is handed over to the new guard.
+        match f(unsafe { &*rwlock.value.get() }) {
+            Some(value) => {
+                core::mem::forget(guard);
+                Ok(MappedReadGuard {
+                    value: NonNull::from(value),
+                    raw: &rwlock.raw,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(guard),
+        }
+    }
+}
+
+pub struct MappedReadGuard<'a, T: ?Sized, W: WaitStrategy = DefaultWait> {
+    value: NonNull<T>,
+    raw: &'a RawRWLock<W>,
+    _marker: PhantomData<&'a T>,
+}
+
+unsafe impl<T: ?Sized + Sync, W: WaitStrategy> Sync for MappedReadGuard<'_, T, W> {}
+
+impl<T: ?Sized, W: WaitStrategy> Deref for MappedReadGuard<'_, T, W> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<T: ?Sized, W: WaitStrategy> Drop for MappedReadGuard<'_, T, W> {
+    fn drop(&mut self) {
+        // Safety: The read lock was handed over from the ReadGuard.
+        unsafe { self.raw.read_unlock() };
+    }
+}
+
+// WriteGuard //
+
+pub struct WriteGuard<'a, T, W: WaitStrategy = DefaultWait> {
+    rwlock: &'a RWLock<T, W>,
+}
+
+unsafe impl<T, W: WaitStrategy> Sync for WriteGuard<'_, T, W> where T: Sync {}
+
+impl<T, W: WaitStrategy> Deref for WriteGuard<'_, T, W> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T, W: WaitStrategy> DerefMut for WriteGuard<'_, T, W> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<T, W: WaitStrategy> Drop for WriteGuard<'_, T, W> {
+    fn drop(&mut self) {
+        // Safety: The guard proves we write locked it.
+        unsafe { self.rwlock.raw.write_unlock() };
+    }
+}
+
+impl<'a, T, W: WaitStrategy> WriteGuard<'a, T, W> {
+    /// A guard for a part of the value, e.g. one element of a collection.
+    pub fn map<U: ?Sized>(
+        guard: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedWriteGuard<'a, U, W> {
+        match Self::filter_map(guard, |v| Some(f(v))) {
+            Ok(mapped) => mapped,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `map`, but gives the guard back if `f` returns None.
+    pub fn filter_map<U: ?Sized>(
+        guard: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedWriteGuard<'a, U, W>, Self> {
+        let rwlock = guard.rwlock;
+        // Safety: the reference can live as long as the write lock,
+        // which is handed over to the new guard.
+        match f(unsafe { &mut *rwlock.value.get() }) {
+            Some(value) => {
+                core::mem::forget(guard);
+                Ok(MappedWriteGuard {
+                    value: NonNull::from(value),
+                    raw: &rwlock.raw,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(guard),
+        }
+    }
+}
+
+pub struct MappedWriteGuard<'a, T: ?Sized, W: WaitStrategy = DefaultWait> {
+    value: NonNull<T>,
+    raw: &'a RawRWLock<W>,
+    _marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<T: ?Sized + Sync, W: WaitStrategy> Sync for MappedWriteGuard<'_, T, W> {}
+
+impl<T: ?Sized, W: WaitStrategy> Deref for MappedWriteGuard<'_, T, W> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<T: ?Sized, W: WaitStrategy> DerefMut for MappedWriteGuard<'_, T, W> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<T: ?Sized, W: WaitStrategy> Drop for MappedWriteGuard<'_, T, W> {
+    fn drop(&mut self) {
+        // Safety: The write lock was handed over from the WriteGuard.
+        unsafe { self.raw.write_unlock() };
+    }
+}
+
+// Once //
+
+const INCOMPLETE: u32 = 0;
+const RUNNING: u32 = 1;
+const RUNNING_WITH_WAITERS: u32 = 2;
+const COMPLETE: u32 = 3;
+
+pub struct Once<W: WaitStrategy = DefaultWait> {
+    state: AtomicU32,
+    _wait: PhantomData<fn() -> W>,
+}
+
+impl Once {
+    pub const fn new() -> Self {
+        Self::with_strategy()
+    }
+}
+
+impl<W: WaitStrategy> Once<W> {
+    /// E.g. `Once::<Spin>::with_strategy()`.
+    pub const fn with_strategy() -> Self {
+        Self {
+            state: AtomicU32::new(INCOMPLETE),
+            _wait: PhantomData,
+        }
+    }
+
+    /// Runs `f`, unless it already ran, or is running on another thread,
+    /// in which case this waits for that to finish.
+    ///
+    /// If `f` panics, the next `call_once` tries again.
+    #[inline]
+    pub fn call_once(&self, f: impl FnOnce()) {
+        // Acquire: see everything `f` did.
+        if self.state.load(Acquire) != COMPLETE {
+            self.call_once_slow(f);
+        }
+    }
+
+    #[cold]
+    fn call_once_slow(&self, f: impl FnOnce()) {
+        let mut s = self.state.load(Acquire);
+        loop {
+            match s {
+                COMPLETE => return,
+                INCOMPLETE => {
+                    match self
+                        .state
+                        .compare_exchange(INCOMPLETE, RUNNING, Acquire, Acquire)
+                    {
+                        Ok(_) => break,
+                        Err(new_s) => s = new_s,
+                    }
+                }
+                RUNNING => {
+                    // Let the running thread know it needs to wake us up.
+                    match self.state.compare_exchange(
+                        RUNNING,
+                        RUNNING_WITH_WAITERS,
+                        Acquire,
+                        Acquire,
+                    ) {
+                        Ok(_) => s = RUNNING_WITH_WAITERS,
+                        Err(new_s) => s = new_s,
+                    }
+                }
+                _ => {
+                    W::wait(&self.state, RUNNING_WITH_WAITERS);
+                    s = self.state.load(Acquire);
+                }
+            }
+        }
+
+        // Set the state back to INCOMPLETE if `f` panics.
+        let mut finish = Finish {
+            state: &self.state,
+            to: INCOMPLETE,
+            _wait: PhantomData::<W>,
+        };
+        f();
+        // Release: the others see everything `f` did.
+        finish.to = COMPLETE;
+        drop(finish);
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Acquire) == COMPLETE
+    }
+}
+
+impl<W: WaitStrategy> Default for Once<W> {
+    fn default() -> Self {
+        Self::with_strategy()
+    }
+}
+
+/// Sets the state of a Once when dropped, and wakes up whoever's waiting.
+struct Finish<'a, W: WaitStrategy> {
+    state: &'a AtomicU32,
+    to: u32,
+    _wait: PhantomData<W>,
+}
+
+impl<W: WaitStrategy> Drop for Finish<'_, W> {
+    fn drop(&mut self) {
+        if self.state.swap(self.to, Release) == RUNNING_WITH_WAITERS {
+            W::wake_all(self.state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+    use std::thread;
+    use stress::{Config, Stamp};
+    use wait::Spin;
+
+    /// A user-provided strategy: just yield to the OS.
+    struct Yield;
+
+    impl WaitStrategy for Yield {
+        fn wait(atomic: &AtomicU32, expected: u32) {
+            if atomic.load(Relaxed) == expected {
+                thread::yield_now();
+            }
+        }
+
+        fn wake_one(_: &AtomicU32) {}
+
+        fn wake_all(_: &AtomicU32) {}
+    }
+
+    #[test]
+    fn test_guards_sync() {
+        use core::cell::Cell;
+        assert!(implements!(MutexGuard<u32>: Sync));
+        assert!(implements!(MutexGuard<u32>: Send));
+        // Sharing the guard shares the value, and a Cell can't be shared.
+        assert!(!implements!(MutexGuard<Cell<u32>>: Sync));
+        assert!(implements!(MutexGuard<Cell<u32>>: Send));
+        assert!(!implements!(ReadGuard<Cell<u32>>: Sync));
+        assert!(!implements!(WriteGuard<Cell<u32>>: Sync));
+        assert!(!implements!(MappedReadGuard<Cell<u32>>: Sync));
+        assert!(!implements!(MappedWriteGuard<Cell<u32>>: Sync));
+        assert!(implements!(ReadGuard<u32>: Sync));
+        assert!(implements!(WriteGuard<u32>: Sync));
+    }
+
+    #[test]
+    fn test_mutex() {
+        stress_mutex::<DefaultWait>();
+        stress_mutex::<Spin>();
+        stress_mutex::<Yield>();
+    }
+
+    fn stress_mutex<W: WaitStrategy>() {
+        let mutex = Mutex::<_, W>::with_strategy(Stamp::new(0));
+        let locks: usize = stress::run(&Config::from_env(), |w| {
+            for _ in 0..w.iterations {
+                let mut guard = mutex.lock();
+                *guard = guard.next();
+            }
+            w.iterations
+        })
+        .into_iter()
+        .sum();
+        assert_eq!(mutex.lock().value(), locks as u64);
+    }
+
+    #[test]
+    fn test_rwlock() {
+        stress_rwlock::<DefaultWait>();
+        stress_rwlock::<Spin>();
+        stress_rwlock::<Yield>();
+    }
+
+    fn stress_rwlock<W: WaitStrategy>() {
+        let rwlock = RWLock::<_, W>::with_strategy(Stamp::new(0));
+        let writes: u64 = stress::run(&Config::from_env(), |w| {
+            let mut writes = 0;
+            for _ in 0..w.iterations {
+                if w.rng.below(5) == 0 {
+                    let mut guard = rwlock.write();
+                    *guard = guard.next();
+                    writes += 1;
+                } else {
+                    assert!(rwlock.read().is_intact(), "torn read");
+                }
+            }
+            writes
+        })
+        .into_iter()
+        .sum();
+        assert_eq!(rwlock.read().value(), writes);
+    }
+
+    #[test]
+    fn test_rwlock_map() {
+        let rwlock = RWLock::new((1, vec![2, 3]));
+        let a = ReadGuard::map(rwlock.read(), |v| &v.0);
+        let b = ReadGuard::filter_map(rwlock.read(), |v| v.1.get(1))
+            .ok()
+            .unwrap();
+        assert_eq!((*a, *b), (1, 3));
+        assert!(ReadGuard::filter_map(rwlock.read(), |v| v.1.get(5)).is_err());
+        drop((a, b));
+
+        let mut c = WriteGuard::map(rwlock.write(), |v| &mut v.1[..]);
+        c[0] = 4;
+        drop(c);
+        assert_eq!(rwlock.read().1, [4, 3]);
+        // Still unlocked properly.
+        *rwlock.write() = (0, Vec::new());
+    }
+
+    #[test]
+    fn test_once() {
+        let once = Once::new();
+        let result = catch_unwind(AssertUnwindSafe(|| once.call_once(|| panic!())));
+        assert!(result.is_err());
+        assert!(!once.is_completed());
+
+        let calls = AtomicUsize::new(0);
+        stress::run(&Config::from_env(), |w| {
+            for _ in 0..w.iterations {
+                once.call_once(|| {
+                    // Give the others a chance to run into it.
+                    thread::yield_now();
+                    calls.fetch_add(1, Relaxed);
+                });
+                assert!(once.is_completed());
+                assert_eq!(calls.load(Relaxed), 1);
+            }
+        });
+        assert_eq!(calls.into_inner(), 1);
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod condvar_tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_condvar() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+
+        let mut wakeups = 0;
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                *mutex.lock() = 123;
+                condvar.notify_one();
+            });
+
+            let mut m = mutex.lock();
+            while *m < 100 {
+                m = condvar.wait(m);
+                wakeups += 1;
+            }
+
+            assert_eq!(*m, 123);
+        });
+
+        // Check that the main thread actually did wait (not busy-loop),
+        // while still allowing for a few spurious wake ups.
+        assert!(wakeups < 10);
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+
+        // Nobody notifies: times out.
+        let start = Instant::now();
+        let (m, timed_out) = condvar.wait_timeout(mutex.lock(), Duration::from_millis(50));
+        assert!(timed_out);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        drop(m);
+
+        // Notified well before the timeout.
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                *mutex.lock() = 1;
+                condvar.notify_all();
+            });
+
+            let mut m = mutex.lock();
+            while *m == 0 {
+                let timed_out;
+                (m, timed_out) = condvar.wait_timeout(m, Duration::from_secs(10));
+                assert!(!timed_out);
+            }
+        });
+    }
+}
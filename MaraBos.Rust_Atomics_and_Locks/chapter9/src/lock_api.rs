@@ -0,0 +1,528 @@
+// A Mutex and RwLock generic over the raw lock inside.
+//
+// Every mutex in this chapter is the same UnsafeCell plus guard with
+// Deref/DerefMut around a different way of locking and unlocking.
+// Here, that part is written once, around anything that implements RawMutex
+// (or RawRwLock): the ones in raw.rs, chapter4's RawSpinLock or chapter8's
+// PthreadMutex, or your own. Switching between them only changes the type,
+// for example in a type alias, not the code using the lock:
+//
+//     type Lock<T> = lock_api::Mutex<raw::ThreeStateMutex, T>;
+//     // type Lock<T> = lock_api::Mutex<chapter4::RawSpinLock, T>;
+//
+// (Named after the lock_api crate, which does the same thing.)
+
+use crate::raw::{RawRWLock, ThreeStateMutex, TwoStateMutex};
+use crate::WaitStrategy;
+use chapter4::RawSpinLock;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+
+/// Makes the guards !Send, since some raw locks (like pthread's)
+/// must be unlocked by the same thread that locked them.
+type NotSend = PhantomData<*const ()>;
+
+// Traits //
+
+/// # Safety
+///
+/// `lock` and a successful `try_lock` must only return when no other thread
+/// holds the lock, until `unlock`. Unlocking must happen before (Release)
+/// whatever locks it next (Acquire).
+pub unsafe trait RawMutex {
+    /// An unlocked mutex.
+    fn new() -> Self;
+
+    fn lock(&self);
+
+    /// Returns false if it's already locked.
+    fn try_lock(&self) -> bool;
+
+    /// # Safety
+    ///
+    /// Only call this while locked, once.
+    unsafe fn unlock(&self);
+
+    /// Might be outdated by the time you look at it.
+    fn is_locked(&self) -> bool;
+}
+
+/// # Safety
+///
+/// Like `RawMutex`, but with any number of shared locks at the same time,
+/// as long as there's no exclusive lock.
+pub unsafe trait RawRwLock {
+    /// An unlocked lock.
+    fn new() -> Self;
+
+    fn lock_shared(&self);
+
+    fn try_lock_shared(&self) -> bool;
+
+    /// # Safety
+    ///
+    /// Only call this while share locked, once per `lock_shared`.
+    unsafe fn unlock_shared(&self);
+
+    fn lock_exclusive(&self);
+
+    fn try_lock_exclusive(&self) -> bool;
+
+    /// # Safety
+    ///
+    /// Only call this while exclusively locked, once.
+    unsafe fn unlock_exclusive(&self);
+
+    /// Shared or exclusively locked.
+    /// Might be outdated by the time you look at it.
+    fn is_locked(&self) -> bool;
+}
+
+// Mutex //
+
+pub struct Mutex<R: RawMutex, T: ?Sized> {
+    raw: R,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<R: RawMutex + Sync, T: ?Sized + Send> Sync for Mutex<R, T> {}
+
+impl<R: RawMutex, T> Mutex<R, T> {
+    pub fn new(value: T) -> Self {
+        Self::from_raw(R::new(), value)
+    }
+
+    /// For a raw mutex that was made in another way, e.g. with some settings,
+    /// or in a const.
+    pub const fn from_raw(raw: R, value: T) -> Self {
+        Self {
+            raw,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<R: RawMutex, T: ?Sized> Mutex<R, T> {
+    pub fn lock(&self) -> MutexGuard<'_, R, T> {
+        self.raw.lock();
+        MutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Doesn't wait, and returns `None` if it's already locked.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, R, T>> {
+        self.raw.try_lock().then(|| MutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Might be outdated by the time you look at it.
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn raw(&self) -> &R {
+        &self.raw
+    }
+}
+
+impl<R: RawMutex, T: Default> Default for Mutex<R, T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct MutexGuard<'a, R: RawMutex, T: ?Sized> {
+    mutex: &'a Mutex<R, T>,
+    _not_send: NotSend,
+}
+
+unsafe impl<R: RawMutex + Sync, T: ?Sized + Sync> Sync for MutexGuard<'_, R, T> {}
+
+impl<R: RawMutex, T: ?Sized> Deref for MutexGuard<'_, R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<R: RawMutex, T: ?Sized> DerefMut for MutexGuard<'_, R, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<R: RawMutex, T: ?Sized> Drop for MutexGuard<'_, R, T> {
+    fn drop(&mut self) {
+        // Safety: The guard proves we locked it.
+        unsafe { self.mutex.raw.unlock() };
+    }
+}
+
+// RwLock //
+
+pub struct RwLock<R: RawRwLock, T: ?Sized> {
+    raw: R,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<R: RawRwLock + Sync, T: ?Sized + Send + Sync> Sync for RwLock<R, T> {}
+
+impl<R: RawRwLock, T> RwLock<R, T> {
+    pub fn new(value: T) -> Self {
+        Self::from_raw(R::new(), value)
+    }
+
+    /// For a raw lock that was made in another way, e.g. in a const.
+    pub const fn from_raw(raw: R, value: T) -> Self {
+        Self {
+            raw,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<R: RawRwLock, T: ?Sized> RwLock<R, T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, R, T> {
+        self.raw.lock_shared();
+        RwLockReadGuard {
+            rwlock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, R, T>> {
+        self.raw.try_lock_shared().then(|| RwLockReadGuard {
+            rwlock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, R, T> {
+        self.raw.lock_exclusive();
+        RwLockWriteGuard {
+            rwlock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, R, T>> {
+        self.raw.try_lock_exclusive().then(|| RwLockWriteGuard {
+            rwlock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Read or write locked.
+    /// Might be outdated by the time you look at it.
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn raw(&self) -> &R {
+        &self.raw
+    }
+}
+
+impl<R: RawRwLock, T: Default> Default for RwLock<R, T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct RwLockReadGuard<'a, R: RawRwLock, T: ?Sized> {
+    rwlock: &'a RwLock<R, T>,
+    _not_send: NotSend,
+}
+
+unsafe impl<R: RawRwLock + Sync, T: ?Sized + Sync> Sync for RwLockReadGuard<'_, R, T> {}
+
+impl<R: RawRwLock, T: ?Sized> Deref for RwLockReadGuard<'_, R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<R: RawRwLock, T: ?Sized> Drop for RwLockReadGuard<'_, R, T> {
+    fn drop(&mut self) {
+        // Safety: The guard proves we share locked it.
+        unsafe { self.rwlock.raw.unlock_shared() };
+    }
+}
+
+pub struct RwLockWriteGuard<'a, R: RawRwLock, T: ?Sized> {
+    rwlock: &'a RwLock<R, T>,
+    _not_send: NotSend,
+}
+
+unsafe impl<R: RawRwLock + Sync, T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, R, T> {}
+
+impl<R: RawRwLock, T: ?Sized> Deref for RwLockWriteGuard<'_, R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<R: RawRwLock, T: ?Sized> DerefMut for RwLockWriteGuard<'_, R, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<R: RawRwLock, T: ?Sized> Drop for RwLockWriteGuard<'_, R, T> {
+    fn drop(&mut self) {
+        // Safety: The guard proves we exclusively locked it.
+        unsafe { self.rwlock.raw.unlock_exclusive() };
+    }
+}
+
+// Implementations //
+
+unsafe impl<W: WaitStrategy> RawMutex for TwoStateMutex<W> {
+    fn new() -> Self {
+        Self::new()
+    }
+
+    fn lock(&self) {
+        self.lock();
+    }
+
+    fn try_lock(&self) -> bool {
+        self.try_lock()
+    }
+
+    unsafe fn unlock(&self) {
+        self.unlock();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.is_locked()
+    }
+}
+
+unsafe impl<W: WaitStrategy> RawMutex for ThreeStateMutex<W> {
+    fn new() -> Self {
+        Self::new()
+    }
+
+    #[inline]
+    fn lock(&self) {
+        self.lock();
+    }
+
+    fn try_lock(&self) -> bool {
+        self.try_lock()
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
+        self.unlock();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.is_locked()
+    }
+}
+
+unsafe impl RawMutex for RawSpinLock {
+    fn new() -> Self {
+        Self::new()
+    }
+
+    fn lock(&self) {
+        self.lock();
+    }
+
+    fn try_lock(&self) -> bool {
+        self.try_lock()
+    }
+
+    unsafe fn unlock(&self) {
+        self.unlock();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.is_locked()
+    }
+}
+
+#[cfg(feature = "std")]
+unsafe impl RawMutex for chapter8::PthreadMutex {
+    fn new() -> Self {
+        Self::new()
+    }
+
+    fn lock(&self) {
+        self.lock();
+    }
+
+    fn try_lock(&self) -> bool {
+        self.try_lock()
+    }
+
+    unsafe fn unlock(&self) {
+        self.unlock();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.is_locked()
+    }
+}
+
+unsafe impl<W: WaitStrategy> RawRwLock for RawRWLock<W> {
+    fn new() -> Self {
+        Self::new()
+    }
+
+    fn lock_shared(&self) {
+        self.read();
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.try_read()
+    }
+
+    unsafe fn unlock_shared(&self) {
+        self.read_unlock();
+    }
+
+    fn lock_exclusive(&self) {
+        self.write();
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.try_write()
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        self.write_unlock();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.is_locked()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wait::Spin;
+    use stress::{Config, Stamp};
+
+    fn stress_mutex<R: RawMutex + Sync>() {
+        let mutex = Mutex::<R, _>::new(Stamp::new(0));
+        {
+            let _guard = mutex.lock();
+            assert!(mutex.is_locked());
+            assert!(mutex.try_lock().is_none());
+            assert!(mutex.is_locked(), "a failed try_lock unlocked it");
+        }
+        assert!(!mutex.is_locked());
+        let locks: u64 = stress::run(&Config::from_env(), |w| {
+            let mut locks = 0;
+            for _ in 0..w.iterations {
+                let mut guard = match w.rng.below(2) {
+                    0 => mutex.lock(),
+                    _ => match mutex.try_lock() {
+                        Some(guard) => guard,
+                        None => continue,
+                    },
+                };
+                assert!(guard.is_intact());
+                *guard = guard.next();
+                locks += 1;
+            }
+            locks
+        })
+        .into_iter()
+        .sum();
+        assert_eq!(mutex.into_inner().value(), locks);
+    }
+
+    #[test]
+    fn test_mutex() {
+        stress_mutex::<TwoStateMutex>();
+        stress_mutex::<TwoStateMutex<Spin>>();
+        stress_mutex::<ThreeStateMutex>();
+        stress_mutex::<RawSpinLock>();
+        #[cfg(feature = "std")]
+        stress_mutex::<chapter8::PthreadMutex>();
+    }
+
+    #[test]
+    fn test_unsized() {
+        let mutex: &Mutex<ThreeStateMutex, [i32]> = &Mutex::new([1, 2, 3]);
+        mutex.lock()[1] = 5;
+        assert_eq!(*mutex.lock(), [1, 5, 3]);
+    }
+
+    #[test]
+    fn test_rwlock() {
+        let rwlock = RwLock::<RawRWLock, _>::new(Stamp::new(0));
+        {
+            let _read = rwlock.read();
+            assert!(rwlock.try_read().is_some());
+            assert!(rwlock.try_write().is_none());
+        }
+        {
+            let _write = rwlock.write();
+            assert!(rwlock.try_read().is_none());
+            assert!(rwlock.try_write().is_none());
+            assert!(rwlock.is_locked());
+        }
+        assert!(!rwlock.is_locked());
+        let writes: u64 = stress::run(&Config::from_env(), |w| {
+            let mut writes = 0;
+            for _ in 0..w.iterations {
+                match w.rng.below(4) {
+                    0 => {
+                        let mut guard = rwlock.write();
+                        *guard = guard.next();
+                        writes += 1;
+                    }
+                    1 => {
+                        if let Some(mut guard) = rwlock.try_write() {
+                            *guard = guard.next();
+                            writes += 1;
+                        }
+                    }
+                    2 => {
+                        if let Some(guard) = rwlock.try_read() {
+                            assert!(guard.is_intact(), "torn read");
+                        }
+                    }
+                    _ => assert!(rwlock.read().is_intact(), "torn read"),
+                }
+            }
+            writes
+        })
+        .into_iter()
+        .sum();
+        assert_eq!(rwlock.into_inner().value(), writes);
+    }
+}
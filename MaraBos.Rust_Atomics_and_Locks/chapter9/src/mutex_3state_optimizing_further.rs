@@ -10,6 +10,8 @@ use std::sync::atomic::{
 };
 use std::thread;
 use std::time::Instant;
+#[cfg(test)]
+use stress::{Config, Stamp};
 
 // Mutex //
 
@@ -32,7 +34,7 @@ impl<T> Mutex<T> {
     }
 
     #[inline]
-    pub fn lock(&self) -> MutexGuard<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
         if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
             lock_contended(&self.state);
         }
@@ -88,6 +90,23 @@ impl<T> Drop for MutexGuard<'_, T> {
     }
 }
 
+// stress //
+
+#[test]
+fn test_stress() {
+    let mutex = Mutex::new(Stamp::new(0));
+    let locks: usize = stress::run(&Config::from_env(), |w| {
+        for _ in 0..w.iterations {
+            let mut guard = mutex.lock();
+            *guard = guard.next();
+        }
+        w.iterations
+    })
+    .into_iter()
+    .sum();
+    assert_eq!(mutex.lock().value(), locks as u64);
+}
+
 // main //
 
 fn benchmarking1() {
@@ -0,0 +1,357 @@
+// Parallel loops over slices, on a ThreadPool.
+//
+// The slice is split in half, and each half handed to `join`, recursively,
+// until the pieces are small enough. The pieces don't go to a particular
+// worker: idle workers steal the big halves that are still waiting, which
+// spreads the work out even when some items take much longer than others.
+//
+// `pool.par_map(items, f)` etc. are shorthands for `pool.par().map(items, f)`.
+// Through `pool.par()` you can also attach a ProgressTracker (progress.rs),
+// to report on the progress from another thread, or set the piece size.
+
+use crate::{ProgressTracker, ThreadPool};
+use std::mem::MaybeUninit;
+
+impl ThreadPool {
+    pub fn par(&self) -> Par<'_> {
+        Par {
+            pool: self,
+            progress: None,
+            min_len: None,
+        }
+    }
+
+    pub fn par_for_each<T: Sync>(&self, items: &[T], f: impl Fn(&T) + Sync) {
+        self.par().for_each(items, f)
+    }
+
+    pub fn par_map<T: Sync, R: Send>(&self, items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+        self.par().map(items, f)
+    }
+
+    pub fn par_chunks<T: Sync, R: Send>(
+        &self,
+        items: &[T],
+        chunk_size: usize,
+        f: impl Fn(&[T]) -> R + Sync,
+    ) -> Vec<R> {
+        self.par().chunks(items, chunk_size, f)
+    }
+
+    pub fn par_reduce<T: Sync, R: Send>(
+        &self,
+        items: &[T],
+        identity: impl Fn() -> R + Sync,
+        fold: impl Fn(R, &T) -> R + Sync,
+        reduce: impl Fn(R, R) -> R + Sync,
+    ) -> R {
+        self.par().reduce(items, identity, fold, reduce)
+    }
+}
+
+/// Parallel loops, with some options. From `ThreadPool::par`.
+#[derive(Clone, Copy, Debug)]
+pub struct Par<'a> {
+    pool: &'a ThreadPool,
+    progress: Option<&'a ProgressTracker>,
+    min_len: Option<usize>,
+}
+
+impl<'a> Par<'a> {
+    /// Records every item (or chunk) in `tracker`.
+    pub fn progress(self, tracker: &'a ProgressTracker) -> Self {
+        Self {
+            progress: Some(tracker),
+            ..self
+        }
+    }
+
+    /// Don't split into pieces of fewer than `min_len` items (or chunks).
+    /// By default, it aims for four pieces per thread.
+    pub fn min_len(self, min_len: usize) -> Self {
+        Self {
+            min_len: Some(min_len.max(1)),
+            ..self
+        }
+    }
+
+    pub fn for_each<T: Sync>(self, items: &[T], f: impl Fn(&T) + Sync) {
+        self.run(
+            items,
+            &|items: &[T]| {
+                for item in items {
+                    self.time(1, || f(item));
+                }
+            },
+            &|(), ()| (),
+        );
+    }
+
+    pub fn map<T: Sync, R: Send>(self, items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+        self.collect(items, |item| self.time(1, || f(item)))
+    }
+
+    /// Like `map`, but with `f` getting `chunk_size` items at once.
+    /// (The last chunk can be shorter.)
+    pub fn chunks<T: Sync, R: Send>(
+        self,
+        items: &[T],
+        chunk_size: usize,
+        f: impl Fn(&[T]) -> R + Sync,
+    ) -> Vec<R> {
+        assert!(chunk_size > 0, "chunk size must be non-zero");
+        let chunks: Vec<&[T]> = items.chunks(chunk_size).collect();
+        self.collect(&chunks, |chunk| self.time(chunk.len(), || f(chunk)))
+    }
+
+    /// Folds every piece into its own `identity()`,
+    /// and then combines the results with `reduce`.
+    ///
+    /// The order of the items is kept, but the grouping isn't:
+    /// `reduce` should be associative.
+    pub fn reduce<T: Sync, R: Send>(
+        self,
+        items: &[T],
+        identity: impl Fn() -> R + Sync,
+        fold: impl Fn(R, &T) -> R + Sync,
+        reduce: impl Fn(R, R) -> R + Sync,
+    ) -> R {
+        self.run(
+            items,
+            &|items: &[T]| {
+                items
+                    .iter()
+                    .fold(identity(), |acc, item| self.time(1, || fold(acc, item)))
+            },
+            &reduce,
+        )
+    }
+
+    fn time<R>(&self, n: usize, f: impl FnOnce() -> R) -> R {
+        match self.progress {
+            Some(tracker) => tracker.time_many(n, f),
+            None => f(),
+        }
+    }
+
+    /// `map`, for both `map` and `chunks`.
+    fn collect<T: Sync, R: Send>(self, items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+        let mut results = Vec::with_capacity(items.len());
+        self.run(
+            (items, &mut results.spare_capacity_mut()[..items.len()]),
+            &|(items, out): (&[T], &mut [MaybeUninit<R>])| {
+                for (item, out) in items.iter().zip(out) {
+                    out.write(f(item));
+                }
+            },
+            &|(), ()| (),
+        );
+        // Safety: Every one of them was written. (If `f` panicked,
+        // we don't get here, and the written ones are leaked.)
+        unsafe { results.set_len(items.len()) };
+        results
+    }
+
+    fn run<S: Split, R: Send>(
+        self,
+        input: S,
+        leaf: &(impl Fn(S) -> R + Sync),
+        reduce: &(impl Fn(R, R) -> R + Sync),
+    ) -> R {
+        let min_len = self
+            .min_len
+            .unwrap_or_else(|| input.len().div_ceil(self.pool.threads() * 4).max(1));
+        self.split(input, min_len, leaf, reduce)
+    }
+
+    fn split<S: Split, R: Send>(
+        self,
+        input: S,
+        min_len: usize,
+        leaf: &(impl Fn(S) -> R + Sync),
+        reduce: &(impl Fn(R, R) -> R + Sync),
+    ) -> R {
+        let len = input.len();
+        if len <= min_len || len < 2 {
+            return leaf(input);
+        }
+        let (a, b) = input.split_at(len / 2);
+        let (a, b) = self.pool.join(
+            || self.split(a, min_len, leaf, reduce),
+            || self.split(b, min_len, leaf, reduce),
+        );
+        reduce(a, b)
+    }
+}
+
+/// Something that can be split in two, to process the halves in parallel.
+trait Split: Sized + Send {
+    fn len(&self) -> usize;
+    fn split_at(self, mid: usize) -> (Self, Self);
+}
+
+impl<T: Sync> Split for &[T] {
+    fn len(&self) -> usize {
+        <[T]>::len(self)
+    }
+
+    fn split_at(self, mid: usize) -> (Self, Self) {
+        <[T]>::split_at(self, mid)
+    }
+}
+
+impl<T: Send> Split for &mut [T] {
+    fn len(&self) -> usize {
+        <[T]>::len(self)
+    }
+
+    fn split_at(self, mid: usize) -> (Self, Self) {
+        <[T]>::split_at_mut(self, mid)
+    }
+}
+
+/// Both split at the same place.
+impl<A: Split, B: Split> Split for (A, B) {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn split_at(self, mid: usize) -> (Self, Self) {
+        let (a1, a2) = self.0.split_at(mid);
+        let (b1, b2) = self.1.split_at(mid);
+        ((a1, b1), (a2, b2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+    use std::thread;
+    use std::time::Duration;
+    use stress::{Config, DropCounter};
+
+    #[test]
+    fn test_par() {
+        let pool = ThreadPool::new(4);
+        let items: Vec<u64> = (0..10_000).collect();
+
+        let sum = AtomicUsize::new(0);
+        pool.par_for_each(&items, |&i| _ = sum.fetch_add(i as usize, Relaxed));
+        assert_eq!(sum.into_inner(), 9999 * 10_000 / 2);
+
+        let squares = pool.par_map(&items, |&i| i * i);
+        assert!(squares
+            .iter()
+            .enumerate()
+            .all(|(i, &s)| s == (i * i) as u64));
+
+        let sums = pool.par_chunks(&items, 3000, |chunk| chunk.iter().sum::<u64>());
+        assert_eq!(sums.len(), 4);
+        assert_eq!(sums[3], (9000..10_000).sum());
+        assert_eq!(sums.iter().sum::<u64>(), 9999 * 10_000 / 2);
+
+        // Not commutative, so this checks the order is kept.
+        let joined = pool.par_reduce(
+            &items[..300],
+            String::new,
+            |s, i| s + &i.to_string() + ",",
+            |a, b| a + &b,
+        );
+        let expected: String = (0..300).map(|i| format!("{i},")).collect();
+        assert_eq!(joined, expected);
+
+        // Empty input.
+        assert_eq!(pool.par_map(&[] as &[u64], |&i| i), []);
+        assert_eq!(pool.par_reduce(&[] as &[u64], || 7, |a, _| a, |a, _| a), 7);
+        assert!(pool.par_chunks(&[] as &[u64], 10, |c| c.len()).is_empty());
+    }
+
+    #[test]
+    fn test_min_len() {
+        let pool = ThreadPool::new(2);
+        let items = [0; 100];
+        let pieces = AtomicUsize::new(0);
+        pool.par()
+            .min_len(100)
+            .reduce(&items, || pieces.fetch_add(1, Relaxed), |a, _| a, |a, _| a);
+        assert_eq!(pieces.load(Relaxed), 1);
+        let leaves = pool
+            .par()
+            .min_len(10)
+            .chunks(&items, 1, |_| thread::current().id());
+        assert_eq!(leaves.len(), 100);
+    }
+
+    #[test]
+    fn test_progress() {
+        let pool = ThreadPool::new(3);
+        let items: Vec<u64> = (0..20).collect();
+        let tracker = ProgressTracker::new(items.len() * 2);
+        thread::scope(|s| {
+            s.spawn(|| {
+                while !tracker.is_finished() {
+                    let _ = tracker.snapshot().to_string();
+                    thread::sleep(Duration::from_millis(1));
+                }
+            });
+            pool.par().progress(&tracker).for_each(&items, |&i| {
+                thread::sleep(Duration::from_micros(100 * i));
+            });
+            assert_eq!(tracker.done(), 20);
+            pool.par().progress(&tracker).chunks(&items, 6, |_| {});
+        });
+        let progress = tracker.snapshot();
+        assert_eq!(progress.done, 40);
+        assert!(progress.max >= Duration::from_micros(1900));
+    }
+
+    #[test]
+    fn test_panic() {
+        let pool = ThreadPool::new(2);
+        let counter = DropCounter::new();
+        let items: Vec<usize> = (0..100).collect();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.par_map(&items, |&i| {
+                assert_ne!(i, 50, "boom");
+                counter.track()
+            })
+        }));
+        assert!(result.is_err());
+        // The results that were already there are leaked, not dropped.
+        let leaked = counter.alive();
+        let results = pool.par_map(&items, |_| counter.track());
+        assert_eq!(counter.alive(), leaked + 100);
+        drop(results);
+        assert_eq!(counter.alive(), leaked);
+    }
+
+    /// Several threads run parallel loops on the same pool at once.
+    #[test]
+    fn test_stress() {
+        let config = Config::from_env();
+        let pool = ThreadPool::new(config.threads);
+        let iterations = config.iterations / 100;
+        stress::run(&config.iterations(iterations), |w| {
+            for _ in 0..w.iterations {
+                let items: Vec<usize> = (0..w.rng.below(1000)).collect();
+                let expected: usize = items.iter().sum();
+                let sum = match w.rng.below(4) {
+                    0 => {
+                        let sum = AtomicUsize::new(0);
+                        pool.par_for_each(&items, |&i| _ = sum.fetch_add(i, Relaxed));
+                        sum.into_inner()
+                    }
+                    1 => pool.par_map(&items, |&i| i).into_iter().sum(),
+                    2 => pool
+                        .par_chunks(&items, 1 + w.rng.below(50), |c| c.iter().sum::<usize>())
+                        .into_iter()
+                        .sum(),
+                    _ => pool.par_reduce(&items, || 0, |a, &i| a + i, |a, b| a + b),
+                };
+                assert_eq!(sum, expected);
+            }
+        });
+    }
+}
@@ -0,0 +1,31 @@
+use chapter9::{ProgressTracker, ThreadPool};
+use std::thread;
+use std::time::Duration;
+
+// chapter2/statistics.rs, with the items processed on a ThreadPool.
+
+fn main() {
+    let pool = ThreadPool::new(4);
+    let items: Vec<usize> = (0..100).collect();
+    let tracker = &ProgressTracker::new(items.len());
+
+    thread::scope(|s| {
+        s.spawn(move || {
+            while !tracker.is_finished() {
+                println!("Working... {}", tracker.snapshot());
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+
+        pool.par()
+            .progress(tracker)
+            .for_each(&items, |&item| process_item(item));
+    });
+
+    println!("Done! {}", tracker.snapshot());
+}
+
+fn process_item(item: usize) {
+    // processing...
+    thread::sleep(Duration::from_millis(item as u64 * 3 + 100));
+}
@@ -0,0 +1,196 @@
+// Progress reporting
+//
+// chapter2/progress_reporting_from_multiple_threads.rs and statistics.rs,
+// as something reusable: any number of threads record the items they've
+// processed (and how long each took), while another thread reads a
+// snapshot of the progress whenever it wants to report it.
+//
+// Just like in statistics.rs, everything is a separate Relaxed counter,
+// so a snapshot might be a tiny bit inconsistent (e.g. the total time
+// already including an item that's not counted as done yet). That's fine
+// for a progress report, and keeps recording an item cheap.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct ProgressTracker {
+    total: usize,
+    done: AtomicUsize,
+    /// Sum of the time taken by all items, in nanoseconds.
+    total_time: AtomicU64,
+    /// Time taken by the slowest item, in nanoseconds.
+    max_time: AtomicU64,
+    start: Instant,
+}
+
+impl ProgressTracker {
+    /// For `total` items, starting now.
+    pub fn new(total: usize) -> Self {
+        Self {
+            total,
+            done: AtomicUsize::new(0),
+            total_time: AtomicU64::new(0),
+            max_time: AtomicU64::new(0),
+            start: Instant::now(),
+        }
+    }
+
+    /// Records one item that took `time`.
+    pub fn record(&self, time: Duration) {
+        self.record_many(1, time);
+    }
+
+    /// Records `n` items that together took `time`,
+    /// e.g. when they're processed as one chunk.
+    /// For the peak, they count as all having taken equally long.
+    pub fn record_many(&self, n: usize, time: Duration) {
+        if n == 0 {
+            return;
+        }
+        let nanos = time.as_nanos().min(u64::MAX as u128) as u64;
+        self.done.fetch_add(n, Relaxed);
+        self.total_time.fetch_add(nanos, Relaxed);
+        self.max_time.fetch_max(nanos / n as u64, Relaxed);
+    }
+
+    /// Runs `f`, and records it as one item.
+    pub fn time<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.time_many(1, f)
+    }
+
+    /// Runs `f`, and records it as `n` items.
+    pub fn time_many<R>(&self, n: usize, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let result = f();
+        self.record_many(n, start.elapsed());
+        result
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn done(&self) -> usize {
+        self.done.load(Relaxed)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.done() >= self.total
+    }
+
+    pub fn snapshot(&self) -> Progress {
+        let done = self.done.load(Relaxed);
+        let total_time = Duration::from_nanos(self.total_time.load(Relaxed));
+        let max = Duration::from_nanos(self.max_time.load(Relaxed));
+        let elapsed = self.start.elapsed();
+        let (average, eta) = if done == 0 {
+            (None, None)
+        } else {
+            // The ETA is based on the overall rate, rather than on the
+            // average time per item, since items are processed in parallel.
+            let remaining = self.total.saturating_sub(done);
+            let eta = elapsed.mul_f64(remaining as f64 / done as f64);
+            (Some(total_time.div_f64(done as f64)), Some(eta))
+        };
+        Progress {
+            done,
+            total: self.total,
+            average,
+            max,
+            elapsed,
+            eta,
+        }
+    }
+}
+
+/// A snapshot of a `ProgressTracker`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+    /// Average time per item. None if nothing is done yet.
+    pub average: Option<Duration>,
+    /// Time taken by the slowest item.
+    pub max: Duration,
+    /// Time since the tracker was created.
+    pub elapsed: Duration,
+    /// Estimated time until all items are done. None if nothing is done yet.
+    pub eta: Option<Duration>,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.average, self.eta) {
+            (Some(average), Some(eta)) => write!(
+                f,
+                "{}/{} done, {:.3?} average, {:.3?} peak, {:.1?} left",
+                self.done, self.total, average, self.max, eta
+            ),
+            _ => write!(f, "0/{} done", self.total),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use stress::Config;
+
+    #[test]
+    fn test_snapshot() {
+        let tracker = ProgressTracker::new(10);
+        let progress = tracker.snapshot();
+        assert_eq!(
+            (progress.done, progress.average, progress.eta),
+            (0, None, None)
+        );
+        assert_eq!(progress.to_string(), "0/10 done");
+        tracker.record(Duration::from_millis(10));
+        tracker.record_many(3, Duration::from_millis(60));
+        assert_eq!(tracker.time(|| 5), 5);
+        let progress = tracker.snapshot();
+        assert_eq!(progress.done, 5);
+        assert!(!tracker.is_finished());
+        assert_eq!(progress.max, Duration::from_millis(20));
+        let average = progress.average.unwrap();
+        assert!(average >= Duration::from_millis(14) && average < Duration::from_millis(15));
+        // Half done, so about as long to go as it's been running.
+        let eta = progress.eta.unwrap();
+        assert!(eta.abs_diff(progress.elapsed) < Duration::from_millis(1));
+        assert!(progress.to_string().starts_with("5/10 done, 14."));
+        tracker.record_many(5, Duration::ZERO);
+        assert!(tracker.is_finished());
+        assert_eq!(tracker.snapshot().eta, Some(Duration::ZERO));
+    }
+
+    /// Many threads record while another one reads snapshots,
+    /// which only ever go up.
+    #[test]
+    fn test_stress() {
+        let config = Config::from_env();
+        let tracker = ProgressTracker::new(config.threads * config.iterations);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut last = tracker.snapshot();
+                while !tracker.is_finished() {
+                    let progress = tracker.snapshot();
+                    assert!(progress.done >= last.done && progress.max >= last.max);
+                    last = progress;
+                    thread::yield_now();
+                }
+            });
+            stress::run(&config, |w| {
+                for _ in 0..w.iterations {
+                    let micros = w.rng.below(1000) as u64;
+                    tracker.record(Duration::from_micros(micros));
+                }
+            });
+        });
+        let progress = tracker.snapshot();
+        assert_eq!(progress.done, progress.total);
+        assert!(progress.max < Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,278 @@
+// Raw locks: only the locking and unlocking, without any data to protect.
+//
+// TwoStateMutex: from mutex.rs.
+// ThreeStateMutex: from mutex_3state_optimizing_further.rs. What `Mutex` uses.
+// RawRWLock: from rwlock3.rs. What `RWLock` uses.
+//
+// They implement the RawMutex and RawRwLock traits from lock_api.rs,
+// to put them in a lock_api::Mutex or lock_api::RwLock with data.
+//
+// Unlocking is unsafe: there's no guard to prove the lock is held.
+
+use crate::{DefaultWait, WaitStrategy};
+use core::marker::PhantomData;
+use core::sync::atomic::{
+    AtomicU32,
+    Ordering::{Acquire, Relaxed, Release},
+};
+
+// TwoStateMutex //
+
+pub struct TwoStateMutex<W: WaitStrategy = DefaultWait> {
+    /// 0: unlocked
+    /// 1: locked
+    state: AtomicU32,
+    _wait: PhantomData<fn() -> W>,
+}
+
+impl<W: WaitStrategy> TwoStateMutex<W> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(0), // unlocked state
+            _wait: PhantomData,
+        }
+    }
+
+    pub fn lock(&self) {
+        // Set the state to 1: locked.
+        while self.state.swap(1, Acquire) == 1 {
+            // If it was already locked...
+            // ... wait, unless the state is no longer 1.
+            W::wait(&self.state, 1);
+        }
+    }
+
+    pub fn try_lock(&self) -> bool {
+        self.state.compare_exchange(0, 1, Acquire, Relaxed).is_ok()
+    }
+
+    /// # Safety
+    ///
+    /// Only call this while locked, once.
+    pub unsafe fn unlock(&self) {
+        self.state.store(0, Release);
+        // We don't know if anyone is waiting, so always wake one up.
+        W::wake_one(&self.state);
+    }
+
+    /// Might be outdated by the time you look at it.
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Relaxed) != 0
+    }
+}
+
+impl<W: WaitStrategy> Default for TwoStateMutex<W> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ThreeStateMutex //
+
+pub struct ThreeStateMutex<W: WaitStrategy = DefaultWait> {
+    /// 0: unlocked
+    /// 1: locked, no other threads waiting
+    /// 2: locked, other threads waiting
+    state: AtomicU32,
+    _wait: PhantomData<fn() -> W>,
+}
+
+impl<W: WaitStrategy> ThreeStateMutex<W> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(0), // unlocked state
+            _wait: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn lock(&self) {
+        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
+            lock_contended::<W>(&self.state);
+        }
+    }
+
+    pub fn try_lock(&self) -> bool {
+        self.state.compare_exchange(0, 1, Acquire, Relaxed).is_ok()
+    }
+
+    /// # Safety
+    ///
+    /// Only call this while locked, once.
+    #[inline]
+    pub unsafe fn unlock(&self) {
+        if self.state.swap(0, Release) == 2 {
+            W::wake_one(&self.state);
+        }
+    }
+
+    /// Might be outdated by the time you look at it.
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Relaxed) != 0
+    }
+}
+
+impl<W: WaitStrategy> Default for ThreeStateMutex<W> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cold]
+fn lock_contended<W: WaitStrategy>(state: &AtomicU32) {
+    let mut spin_count = 0;
+
+    while state.load(Relaxed) == 1 && spin_count < 100 {
+        spin_count += 1;
+        core::hint::spin_loop();
+    }
+
+    if state.compare_exchange(0, 1, Acquire, Relaxed).is_ok() {
+        return;
+    }
+
+    while state.swap(2, Acquire) != 0 {
+        W::wait(state, 2);
+    }
+}
+
+// RawRWLock //
+
+pub struct RawRWLock<W: WaitStrategy = DefaultWait> {
+    /// The number of read lockes times two, plus on if there's a writer waiting.
+    /// u32::MAX if write locked.
+    ///
+    /// This means that readers may acquire the lock when
+    /// the state is even, but need to block when odd.
+    state: AtomicU32,
+    /// Incremented to wake up writers.
+    writer_wake_counter: AtomicU32,
+    _wait: PhantomData<fn() -> W>,
+}
+
+impl<W: WaitStrategy> RawRWLock<W> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            writer_wake_counter: AtomicU32::new(0),
+            _wait: PhantomData,
+        }
+    }
+
+    pub fn read(&self) {
+        let mut s = self.state.load(Relaxed);
+        loop {
+            if s.is_multiple_of(2) {
+                // Even
+                assert!(s < u32::MAX - 2, "too many readers");
+                match self.state.compare_exchange_weak(s, s + 2, Acquire, Relaxed) {
+                    Ok(_) => return,
+                    Err(new_s) => s = new_s,
+                }
+            }
+
+            if s % 2 == 1 {
+                // Odd.
+                W::wait(&self.state, s);
+                s = self.state.load(Relaxed);
+            }
+        }
+    }
+
+    /// Returns false if it's write locked, or if a writer is waiting.
+    pub fn try_read(&self) -> bool {
+        let mut s = self.state.load(Relaxed);
+        while s.is_multiple_of(2) {
+            assert!(s < u32::MAX - 2, "too many readers");
+            match self.state.compare_exchange_weak(s, s + 2, Acquire, Relaxed) {
+                Ok(_) => return true,
+                Err(new_s) => s = new_s,
+            }
+        }
+        false
+    }
+
+    /// # Safety
+    ///
+    /// Only call this while read locked, once per `read`.
+    pub unsafe fn read_unlock(&self) {
+        // Decrement the state by 2 to remove one read-lock.
+        if self.state.fetch_sub(2, Release) == 3 {
+            // If we decrement from 3 to 1, that means
+            // the RWLock is now unlocked _and_ there is
+            // a waiting writer, which we wake up.
+            self.writer_wake_counter.fetch_add(1, Release);
+            W::wake_one(&self.writer_wake_counter);
+        }
+    }
+
+    pub fn write(&self) {
+        let mut s = self.state.load(Relaxed);
+        loop {
+            // Try lock if unlocked.
+            if s <= 1 {
+                match self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
+                    Ok(_) => return,
+                    Err(new_s) => {
+                        s = new_s;
+                        continue;
+                    }
+                }
+            }
+
+            // Block new readers, by making sure the state is odd.
+            if s.is_multiple_of(2) {
+                match self.state.compare_exchange(s, s + 1, Acquire, Relaxed) {
+                    Ok(_) => {}
+                    Err(new_s) => {
+                        s = new_s;
+                        continue;
+                    }
+                }
+            }
+
+            // Wait, if it's still locked
+            let w = self.writer_wake_counter.load(Acquire);
+            s = self.state.load(Relaxed);
+            // If there are readers
+            if s >= 2 {
+                W::wait(&self.writer_wake_counter, w);
+                s = self.state.load(Relaxed);
+            }
+        }
+    }
+
+    pub fn try_write(&self) -> bool {
+        let mut s = self.state.load(Relaxed);
+        while s <= 1 {
+            match self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
+                Ok(_) => return true,
+                Err(new_s) => s = new_s,
+            }
+        }
+        false
+    }
+
+    /// # Safety
+    ///
+    /// Only call this while write locked, once.
+    pub unsafe fn write_unlock(&self) {
+        self.state.store(0, Release);
+        self.writer_wake_counter.fetch_add(1, Release);
+        // Wake up all waiting readers and writers.
+        W::wake_one(&self.writer_wake_counter);
+        W::wake_all(&self.state);
+    }
+
+    /// Read or write locked.
+    /// Might be outdated by the time you look at it.
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Relaxed) >= 2
+    }
+}
+
+impl<W: WaitStrategy> Default for RawRWLock<W> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
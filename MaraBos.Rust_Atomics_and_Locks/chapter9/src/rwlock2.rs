@@ -9,7 +9,7 @@ use core::sync::atomic::{
     AtomicU32,
     Ordering::{Acquire, Relaxed, Release},
 };
-use std::thread;
+use stress::{Config, Mix, Stamp};
 
 // RWLock //
 
@@ -32,7 +32,7 @@ impl<T> RWLock<T> {
         }
     }
 
-    pub fn read(&self) -> ReadGuard<T> {
+    pub fn read(&self) -> ReadGuard<'_, T> {
         let mut s = self.state.load(Relaxed);
         loop {
             if s < u32::MAX {
@@ -49,7 +49,7 @@ impl<T> RWLock<T> {
         }
     }
 
-    pub fn write(&self) -> WriteGuard<T> {
+    pub fn write(&self) -> WriteGuard<'_, T> {
         while self
             .state
             .compare_exchange(0, u32::MAX, Acquire, Relaxed)
@@ -119,33 +119,51 @@ impl<T> Drop for WriteGuard<'_, T> {
     }
 }
 
-// main //
+// stress //
 
-fn main() {
-    let rwlock = RWLock::new(0);
-    static mut SUM: usize = 0;
+#[derive(Clone, Copy)]
+enum Op {
+    Read,
+    Write,
+}
 
-    thread::scope(|s| {
-        s.spawn(|| {
-            for _ in 0..1000 {
-                unsafe { SUM += *rwlock.read() };
+/// Readers check that they never see a half-written `Stamp`,
+/// and the final value has to match the number of writes.
+fn stress_rwlock(config: &Config) -> u64 {
+    let rwlock = RWLock::new(Stamp::new(0));
+    let mix = Mix::new(&[(Op::Read, 4), (Op::Write, 1)]);
+
+    let writes: u64 = stress::run(config, |w| {
+        let mut writes = 0;
+        for _ in 0..w.iterations {
+            match mix.pick(&mut w.rng) {
+                Op::Read => assert!(rwlock.read().is_intact(), "torn read"),
+                Op::Write => {
+                    let mut guard = rwlock.write();
+                    *guard = guard.next();
+                    writes += 1;
+                }
             }
-        });
+        }
+        writes
+    })
+    .into_iter()
+    .sum();
 
-        s.spawn(|| {
-            for _ in 0..1000 {
-                unsafe { SUM += *rwlock.read() };
-            }
-        });
+    assert_eq!(rwlock.read().value(), writes);
+    writes
+}
 
-        s.spawn(|| {
-            for _ in 0..1000 {
-                *rwlock.write() += 1;
-            }
-        });
-    });
+#[test]
+fn test_stress() {
+    stress_rwlock(&Config::from_env());
+}
 
-    println!("The sum is: {}", unsafe { SUM });
+// main //
+
+fn main() {
+    let writes = stress_rwlock(&Config::from_env());
+    println!("{writes} writes, no torn reads");
 
     println!("done");
 }
@@ -9,7 +9,7 @@ use core::sync::atomic::{
     AtomicU32,
     Ordering::{Acquire, Relaxed, Release},
 };
-use std::thread;
+use stress::{Config, Mix, Stamp};
 
 // RWLock //
 
@@ -36,10 +36,10 @@ impl<T> RWLock<T> {
         }
     }
 
-    pub fn read(&self) -> ReadGuard<T> {
+    pub fn read(&self) -> ReadGuard<'_, T> {
         let mut s = self.state.load(Relaxed);
         loop {
-            if s % 2 == 0 {
+            if s.is_multiple_of(2) {
                 // Even
                 assert!(s < u32::MAX - 2, "too many readers");
                 match self.state.compare_exchange_weak(s, s + 2, Acquire, Relaxed) {
@@ -56,7 +56,7 @@ impl<T> RWLock<T> {
         }
     }
 
-    pub fn write(&self) -> WriteGuard<T> {
+    pub fn write(&self) -> WriteGuard<'_, T> {
         let mut s = self.state.load(Relaxed);
         loop {
             // Try lock if unlocked.
@@ -71,7 +71,7 @@ impl<T> RWLock<T> {
             }
 
             // Block new readers, by making sure the state is odd.
-            if s % 2 == 0 {
+            if s.is_multiple_of(2) {
                 match self.state.compare_exchange(s, s + 1, Acquire, Relaxed) {
                     Ok(_) => {}
                     Err(new_s) => {
@@ -150,33 +150,51 @@ impl<T> Drop for WriteGuard<'_, T> {
     }
 }
 
-// main //
+// stress //
 
-fn main() {
-    let rwlock = RWLock::new(0);
-    static mut SUM: usize = 0;
+#[derive(Clone, Copy)]
+enum Op {
+    Read,
+    Write,
+}
 
-    thread::scope(|s| {
-        s.spawn(|| {
-            for _ in 0..1000 {
-                unsafe { SUM += *rwlock.read() };
+/// Readers check that they never see a half-written `Stamp`,
+/// and the final value has to match the number of writes.
+fn stress_rwlock(config: &Config) -> u64 {
+    let rwlock = RWLock::new(Stamp::new(0));
+    let mix = Mix::new(&[(Op::Read, 4), (Op::Write, 1)]);
+
+    let writes: u64 = stress::run(config, |w| {
+        let mut writes = 0;
+        for _ in 0..w.iterations {
+            match mix.pick(&mut w.rng) {
+                Op::Read => assert!(rwlock.read().is_intact(), "torn read"),
+                Op::Write => {
+                    let mut guard = rwlock.write();
+                    *guard = guard.next();
+                    writes += 1;
+                }
             }
-        });
+        }
+        writes
+    })
+    .into_iter()
+    .sum();
 
-        s.spawn(|| {
-            for _ in 0..1000 {
-                unsafe { SUM += *rwlock.read() };
-            }
-        });
+    assert_eq!(rwlock.read().value(), writes);
+    writes
+}
 
-        s.spawn(|| {
-            for _ in 0..1000 {
-                *rwlock.write() += 1;
-            }
-        });
-    });
+#[test]
+fn test_stress() {
+    stress_rwlock(&Config::from_env());
+}
 
-    println!("The sum is: {}", unsafe { SUM });
+// main //
+
+fn main() {
+    let writes = stress_rwlock(&Config::from_env());
+    println!("{writes} writes, no torn reads");
 
     println!("done");
 }
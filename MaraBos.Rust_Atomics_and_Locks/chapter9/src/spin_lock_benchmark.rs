@@ -0,0 +1,102 @@
+// Throughput of the spin locks from chapter 4 under contention,
+// compared to the futex-based Mutex from this chapter.
+//
+// Every thread keeps locking the same lock to increment a counter,
+// which is about the worst case for a lock: there's nothing else to do.
+// The SpinLock should fall behind as the number of threads (on different
+// cores) grows, while the queue locks keep handing the lock over in order.
+// Once there are more threads than cores, the queue locks suffer instead:
+// if the next thread in line isn't running, nobody else can take the lock.
+//
+// Run with --release, and on a machine with many cores
+// for the numbers to mean anything.
+
+use chapter4::clh::ClhLock;
+use chapter4::mcs::McsLock;
+use chapter4::SpinLock;
+use chapter9::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const OPERATIONS: usize = 1_000_000;
+
+trait Lock: Sync {
+    fn increment(&self);
+    fn get(&self) -> usize;
+}
+
+impl Lock for SpinLock<usize> {
+    fn increment(&self) {
+        *self.lock() += 1;
+    }
+
+    fn get(&self) -> usize {
+        *self.lock()
+    }
+}
+
+impl Lock for McsLock<usize> {
+    fn increment(&self) {
+        self.with_lock(|n| *n += 1);
+    }
+
+    fn get(&self) -> usize {
+        self.with_lock(|n| *n)
+    }
+}
+
+impl Lock for ClhLock<usize> {
+    fn increment(&self) {
+        self.with_lock(|n| *n += 1);
+    }
+
+    fn get(&self) -> usize {
+        self.with_lock(|n| *n)
+    }
+}
+
+impl Lock for Mutex<usize> {
+    fn increment(&self) {
+        *self.lock() += 1;
+    }
+
+    fn get(&self) -> usize {
+        *self.lock()
+    }
+}
+
+/// `OPERATIONS` increments in total, spread over `threads` threads.
+fn run(lock: &impl Lock, threads: usize) -> Duration {
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..OPERATIONS / threads {
+                    lock.increment();
+                }
+            });
+        }
+    });
+    let duration = start.elapsed();
+    assert_eq!(lock.get(), OPERATIONS / threads * threads);
+    duration
+}
+
+fn report(name: &str, threads: usize, duration: Duration) {
+    let rate = OPERATIONS as f64 / duration.as_secs_f64() / 1e6;
+    println!("{name:>10} {threads:>2} threads: {duration:>12.3?} ({rate:.1} M ops/s)");
+}
+
+fn main() {
+    for threads in [1, 2, 4, 8, 16, 32, 64] {
+        let d = run(&SpinLock::new(0), threads);
+        report("SpinLock", threads, d);
+        let d = run(&McsLock::new(0), threads);
+        report("McsLock", threads, d);
+        let d = run(&ClhLock::new(0), threads);
+        report("ClhLock", threads, d);
+        let d = run(&Mutex::new(0), threads);
+        report("Mutex", threads, d);
+        println!();
+    }
+}
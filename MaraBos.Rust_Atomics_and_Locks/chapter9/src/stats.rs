@@ -0,0 +1,666 @@
+// Concurrent statistics
+//
+// chapter2/statistics.rs keeps the number of items, the total time and the
+// maximum time in three separate atomics, so a reader can see an item in
+// one of them but not yet in the others. ConcurrentStats keeps the count,
+// sum, minimum, maximum and a histogram together, such that a snapshot
+// always sees a recorded value in all of them, or in none.
+//
+// The counters are split into slots, one per thread that records, each on
+// its own cache line(s), so threads recording at the same time never touch
+// the same memory. Like the `Local`s in chapter6/epoch.rs, the slots are in
+// a list that only grows, and a thread that exits leaves its slot to the
+// next one. A snapshot merges all slots.
+//
+// Every slot works like a seqlock with a single writer: the writer
+// increments `started`, updates everything, and then increments `finished`.
+// A reader reads `finished`, then everything else, then `started`. If those
+// two are equal, the writer wasn't busy in the meantime, so what it read is
+// consistent. Otherwise, it tries again. Recording never blocks.
+//
+// A thread that records nonstop could keep a reader retrying forever,
+// though. So a slot has two sets of counters, and the writer records into
+// the `active` one. After PAUSE_AFTER failed attempts, the reader pauses
+// that set by making the other one active. The writer simply carries on
+// there, and the reader only has to wait for the one record that might
+// still be going on in the set it paused.
+//
+// The histogram has logarithmic buckets: eight per power of two, so a
+// percentile is at most 12.5% more than the real value, while covering
+// everything from a nanosecond to centuries in under 500 buckets.
+
+use crate::CachePadded;
+use std::cell::RefCell;
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{
+    fence, AtomicBool, AtomicPtr, AtomicU64, AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release},
+};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Bits of the value below its most significant one, that pick the bucket within a power of two.
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros();
+    let sub_bucket = (value >> (exponent - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    (exponent - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + sub_bucket
+}
+
+/// The smallest value that goes into the bucket.
+fn bucket_start(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let exponent = (index / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+    let sub_bucket = (index % SUB_BUCKETS) as u64;
+    (SUB_BUCKETS as u64 + sub_bucket) << (exponent - SUB_BUCKET_BITS)
+}
+
+/// The largest value that goes into the bucket.
+fn bucket_end(index: usize) -> u64 {
+    if index + 1 == BUCKETS {
+        u64::MAX
+    } else {
+        bucket_start(index + 1) - 1
+    }
+}
+
+/// Failed attempts to read a set of counters before pausing it.
+const PAUSE_AFTER: u32 = 64;
+
+/// All in nanoseconds.
+struct Counters {
+    started: AtomicU64,
+    /// Also the count.
+    finished: AtomicU64,
+    sum: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Counters {
+    fn new() -> Self {
+        Self {
+            started: AtomicU64::new(0),
+            finished: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+        }
+    }
+
+    fn record(&self, nanos: u64) {
+        self.started.fetch_add(1, Relaxed);
+        // Readers that see any of the following, also see `started`.
+        fence(Release);
+        self.sum.fetch_add(nanos, Relaxed);
+        self.min.fetch_min(nanos, Relaxed);
+        self.max.fetch_max(nanos, Relaxed);
+        self.buckets[bucket_index(nanos)].fetch_add(1, Relaxed);
+        self.finished.fetch_add(1, Release);
+    }
+
+    /// Adds these numbers to `snapshot`, unless the writer was busy.
+    fn try_read_into(&self, snapshot: &mut StatsSnapshot) -> bool {
+        let mut buckets = [0; BUCKETS];
+        let finished = self.finished.load(Acquire);
+        let sum = self.sum.load(Relaxed);
+        let min = self.min.load(Relaxed);
+        let max = self.max.load(Relaxed);
+        for (b, bucket) in buckets.iter_mut().zip(&self.buckets) {
+            *b = bucket.load(Relaxed);
+        }
+        fence(Acquire);
+        if self.started.load(Relaxed) != finished {
+            return false;
+        }
+        snapshot.count += finished;
+        snapshot.sum += sum;
+        snapshot.min = snapshot.min.min(min);
+        snapshot.max = snapshot.max.max(max);
+        for (total, b) in snapshot.buckets.iter_mut().zip(buckets) {
+            *total += b;
+        }
+        true
+    }
+}
+
+// Slots //
+
+/// A thread's counters, in a list that's only freed along with the
+/// `ConcurrentStats` (and the claims of threads still holding on to them).
+/// Slots of threads that exited are reused by new threads.
+struct Slot {
+    in_use: AtomicBool,
+    /// Which of the two `counters` the writer records into.
+    active: AtomicUsize,
+    counters: [Counters; 2],
+    /// Held by a reader that paused a set of counters, so no other reader
+    /// makes it active again before it's done.
+    pausing: Mutex<()>,
+    next: *const CachePadded<Slot>,
+}
+
+impl Slot {
+    fn new() -> Self {
+        Self {
+            in_use: AtomicBool::new(true),
+            active: AtomicUsize::new(0),
+            counters: [Counters::new(), Counters::new()],
+            pausing: Mutex::new(()),
+            next: ptr::null(),
+        }
+    }
+
+    /// Only by the thread that claimed it.
+    fn record(&self, nanos: u64) {
+        self.counters[self.active.load(Relaxed)].record(nanos);
+    }
+
+    /// Adds this slot's numbers to `snapshot`.
+    fn read_into(&self, snapshot: &mut StatsSnapshot) {
+        for (i, counters) in self.counters.iter().enumerate() {
+            let mut attempts = 0;
+            while !counters.try_read_into(snapshot) {
+                // The writer was busy. Let it finish.
+                attempts += 1;
+                if attempts == PAUSE_AFTER {
+                    // It keeps recording. Move it to the other counters.
+                    let _pausing = self.pausing.lock().unwrap_or_else(|e| e.into_inner());
+                    self.active.store(1 - i, Relaxed);
+                    while !counters.try_read_into(snapshot) {
+                        thread::yield_now();
+                    }
+                    break;
+                }
+                if attempts < 16 {
+                    std::hint::spin_loop();
+                } else {
+                    thread::yield_now();
+                }
+            }
+        }
+    }
+}
+
+// Safety: `next` is never changed once the slot is in the list,
+// and only the thread that claimed the slot records into it.
+unsafe impl Send for Slot {}
+unsafe impl Sync for Slot {}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A slot this thread claimed, released again when the thread exits.
+struct Claim {
+    /// The `ConcurrentStats::id`, which is never reused.
+    stats: u64,
+    slot: Arc<CachePadded<Slot>>,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.slot.in_use.store(false, Release);
+    }
+}
+
+thread_local! {
+    /// One for every `ConcurrentStats` this thread recorded into.
+    static CLAIMS: RefCell<Vec<Claim>> = const { RefCell::new(Vec::new()) };
+}
+
+// ConcurrentStats //
+
+pub struct ConcurrentStats {
+    id: u64,
+    /// Every slot in the list holds a reference from `Arc::into_raw`.
+    slots: AtomicPtr<CachePadded<Slot>>,
+}
+
+impl ConcurrentStats {
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Relaxed),
+            slots: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn slots(&self) -> impl Iterator<Item = &Slot> {
+        let mut p = self.slots.load(Acquire) as *const CachePadded<Slot>;
+        std::iter::from_fn(move || {
+            // Safety: slots are only freed when `self` is dropped.
+            let slot = unsafe { p.as_ref()? };
+            p = slot.next;
+            Some(&**slot)
+        })
+    }
+
+    /// A slot that no other thread is using, either a free one or a new one.
+    fn claim(&self) -> Arc<CachePadded<Slot>> {
+        let mut p = self.slots.load(Acquire) as *const CachePadded<Slot>;
+        // Safety: slots are only freed when `self` is dropped.
+        while let Some(slot) = unsafe { p.as_ref() } {
+            if !slot.in_use.load(Relaxed)
+                && slot
+                    .in_use
+                    .compare_exchange(false, true, Acquire, Relaxed)
+                    .is_ok()
+            {
+                // Safety: `p` is from `Arc::into_raw`, and the list's reference is still there.
+                unsafe {
+                    Arc::increment_strong_count(p);
+                    return Arc::from_raw(p);
+                }
+            }
+            p = slot.next;
+        }
+        let mut slot = Arc::new(CachePadded(Slot::new()));
+        let mut head = self.slots.load(Relaxed);
+        loop {
+            Arc::get_mut(&mut slot).unwrap().next = head;
+            let new = Arc::as_ptr(&slot) as *mut _;
+            match self
+                .slots
+                .compare_exchange_weak(head, new, Release, Relaxed)
+            {
+                Ok(_) => break,
+                Err(e) => head = e,
+            }
+        }
+        // The list's reference, given back in `drop`.
+        let _ = Arc::into_raw(Arc::clone(&slot));
+        slot
+    }
+
+    pub fn record(&self, time: Duration) {
+        let nanos = time.as_nanos().min(u64::MAX as u128) as u64;
+        let recorded = CLAIMS.try_with(|claims| {
+            let mut claims = claims.borrow_mut();
+            let i = match claims.iter().position(|c| c.stats == self.id) {
+                Some(i) => i,
+                None => {
+                    // Let go of slots of `ConcurrentStats` that are gone.
+                    claims.retain(|c| Arc::strong_count(&c.slot) > 1);
+                    claims.push(Claim {
+                        stats: self.id,
+                        slot: self.claim(),
+                    });
+                    claims.len() - 1
+                }
+            };
+            claims[i].slot.record(nanos);
+        });
+        if recorded.is_err() {
+            // The thread is exiting, and its claims are already gone.
+            // Use a slot for just this one value.
+            let claim = Claim {
+                stats: self.id,
+                slot: self.claim(),
+            };
+            claim.slot.record(nanos);
+        }
+    }
+
+    /// Runs `f`, and records how long it took.
+    pub fn time<R>(&self, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let result = f();
+        self.record(start.elapsed());
+        result
+    }
+
+    /// Every recorded value is either entirely in it, or not at all.
+    ///
+    /// It reads one set of counters at a time, though, so of two values
+    /// recorded while it runs, it might have the later one but not the
+    /// earlier one.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let mut snapshot = StatsSnapshot {
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+            buckets: vec![0; BUCKETS].into_boxed_slice(),
+        };
+        for slot in self.slots() {
+            slot.read_into(&mut snapshot);
+        }
+        snapshot
+    }
+}
+
+impl Default for ConcurrentStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ConcurrentStats {
+    fn drop(&mut self) {
+        let mut p = *self.slots.get_mut() as *const CachePadded<Slot>;
+        while !p.is_null() {
+            // Safety: the list's reference, from `claim`.
+            let slot = unsafe { Arc::from_raw(p) };
+            p = slot.next;
+        }
+    }
+}
+
+impl fmt::Debug for ConcurrentStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConcurrentStats")
+            .field("slots", &self.slots().count())
+            .finish_non_exhaustive()
+    }
+}
+
+// StatsSnapshot //
+
+/// From `ConcurrentStats::snapshot`.
+#[derive(Clone)]
+pub struct StatsSnapshot {
+    count: u64,
+    /// All in nanoseconds.
+    sum: u64,
+    min: u64,
+    max: u64,
+    buckets: Box<[u64]>,
+}
+
+impl StatsSnapshot {
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos(self.min))
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos(self.max))
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos(self.sum / self.count))
+    }
+
+    /// The value that `q` (0.0 to 1.0) of all values are at or below,
+    /// rounded up to the end of its histogram bucket.
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let value = bucket_end(index).clamp(self.min, self.max);
+                return Some(Duration::from_nanos(value));
+            }
+        }
+        unreachable!("the buckets add up to the count")
+    }
+
+    pub fn p50(&self) -> Option<Duration> {
+        self.percentile(0.5)
+    }
+
+    pub fn p99(&self) -> Option<Duration> {
+        self.percentile(0.99)
+    }
+
+    pub fn p999(&self) -> Option<Duration> {
+        self.percentile(0.999)
+    }
+}
+
+impl fmt::Debug for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StatsSnapshot")
+            .field("count", &self.count)
+            .field("min", &self.min())
+            .field("max", &self.max())
+            .field("mean", &self.mean())
+            .field("p50", &self.p50())
+            .field("p99", &self.p99())
+            .field("p999", &self.p999())
+            .finish()
+    }
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.min(), self.mean(), self.max()) {
+            (Some(min), Some(mean), Some(max)) => write!(
+                f,
+                "{} values, {:.3?} min, {:.3?} mean, {:.3?} p50, {:.3?} p99, {:.3?} p999, {:.3?} max",
+                self.count,
+                min,
+                mean,
+                self.p50().unwrap(),
+                self.p99().unwrap(),
+                self.p999().unwrap(),
+                max
+            ),
+            _ => write!(f, "no values"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use stress::Config;
+
+    #[test]
+    fn test_buckets() {
+        assert_eq!(bucket_index(u64::MAX), BUCKETS - 1);
+        assert_eq!(bucket_start(0), 0);
+        let mut values: Vec<u64> = (0..10_000).collect();
+        values.extend((0..64).flat_map(|i| [1 << i, (1 << i) - 1, (1 << i) + 1]));
+        values.push(u64::MAX);
+        for v in values {
+            let i = bucket_index(v);
+            assert!(
+                bucket_start(i) <= v && v <= bucket_end(i),
+                "{v} not in bucket {i}"
+            );
+            // At most 12.5% wide.
+            let width = bucket_end(i) - bucket_start(i);
+            assert!(
+                width <= bucket_start(i) / SUB_BUCKETS as u64,
+                "bucket {i} too wide"
+            );
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let stats = ConcurrentStats::new();
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            (snapshot.count(), snapshot.min(), snapshot.p50()),
+            (0, None, None)
+        );
+        assert_eq!(snapshot.to_string(), "no values");
+        thread::scope(|s| {
+            for t in 0..4 {
+                let stats = &stats;
+                s.spawn(move || {
+                    for i in (t * 250 + 1)..=(t * 250 + 250) {
+                        stats.record(Duration::from_micros(i));
+                    }
+                });
+            }
+        });
+        assert_eq!(stats.time(|| 5), 5);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.count(), 1001);
+        assert!(snapshot.min().unwrap() < Duration::from_micros(1));
+        assert_eq!(snapshot.max(), Some(Duration::from_micros(1000)));
+        let mean = snapshot.mean().unwrap();
+        assert!(mean.abs_diff(Duration::from_micros(500)) < Duration::from_micros(1));
+        for (p, expected) in [
+            (snapshot.p50(), 500),
+            (snapshot.p99(), 990),
+            (snapshot.p999(), 999),
+        ] {
+            let expected = Duration::from_micros(expected);
+            let p = p.unwrap();
+            assert!(
+                p >= expected && p <= expected.mul_f64(1.125),
+                "{p:?} vs {expected:?}"
+            );
+        }
+        assert_eq!(snapshot.percentile(1.0), snapshot.max());
+        assert!(snapshot.percentile(0.0) >= snapshot.min());
+    }
+
+    /// Every thread records values from a set that all fall into different
+    /// buckets, while another thread keeps taking snapshots, which must
+    /// always agree with themselves.
+    #[test]
+    fn test_stress() {
+        const VALUES: [u64; 4] = [3, 100, 5_000, 1_000_000];
+        let config = Config::from_env();
+        let stats = ConcurrentStats::new();
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut last = 0;
+                while !done.load(Relaxed) {
+                    let snapshot = stats.snapshot();
+                    let counts = VALUES.map(|v| snapshot.buckets[bucket_index(v)]);
+                    assert_eq!(counts.iter().sum::<u64>(), snapshot.count, "count");
+                    let sum: u64 = VALUES.iter().zip(counts).map(|(v, n)| v * n).sum();
+                    assert_eq!(sum, snapshot.sum, "sum");
+                    assert!(snapshot.count >= last);
+                    last = snapshot.count;
+                    thread::yield_now();
+                }
+            });
+            stress::run(&config, |w| {
+                for _ in 0..w.iterations {
+                    let value = VALUES[w.rng.below(VALUES.len())];
+                    stats.record(Duration::from_nanos(value));
+                }
+            });
+            done.store(true, Relaxed);
+        });
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot.count(),
+            (config.threads * config.iterations) as u64
+        );
+        assert_eq!(snapshot.min(), Some(Duration::from_nanos(3)));
+        assert_eq!(snapshot.max(), Some(Duration::from_millis(1)));
+    }
+
+    /// Writers recording nonstop, so their counters nearly always have
+    /// a record going on. Snapshots still finish, and are consistent.
+    #[test]
+    fn test_busy_writers() {
+        let config = Config::from_env();
+        let stats = ConcurrentStats::new();
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..config.threads.max(2) {
+                s.spawn(|| {
+                    while !done.load(Relaxed) {
+                        stats.record(Duration::from_nanos(100));
+                    }
+                });
+            }
+            let mut last = 0;
+            for _ in 0..1000 {
+                let snapshot = stats.snapshot();
+                assert_eq!(snapshot.sum, snapshot.count * 100);
+                assert!(snapshot.count >= last);
+                last = snapshot.count;
+            }
+            done.store(true, Relaxed);
+        });
+    }
+
+    /// A slot is reused once its thread exits, but never shared.
+    #[test]
+    fn test_reuse() {
+        let stats = Arc::new(ConcurrentStats::new());
+        for _ in 0..4 {
+            let stats = stats.clone();
+            thread::spawn(move || stats.record(Duration::from_nanos(1)))
+                .join()
+                .unwrap();
+        }
+        assert_eq!(stats.slots().count(), 1);
+        let barrier = Arc::new(Barrier::new(3));
+        let threads: Vec<_> = (0..3)
+            .map(|_| {
+                let stats = stats.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    stats.record(Duration::from_nanos(1));
+                    barrier.wait();
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(stats.slots().count(), 3);
+        assert!(stats.slots().all(|slot| !slot.in_use.load(Relaxed)));
+        assert_eq!(stats.snapshot().count(), 7);
+        // A thread that outlives the stats lets go of its slot without trouble.
+        let other = ConcurrentStats::new();
+        other.record(Duration::from_nanos(1));
+        drop(other);
+        stats.record(Duration::from_nanos(1));
+        CLAIMS.with(|claims| assert_eq!(claims.borrow().len(), 1));
+    }
+
+    /// A reader that sees a record going on for too long pauses those
+    /// counters, and parks until the record is done. The writer doesn't
+    /// wait for the reader, and carries on in its other counters.
+    #[test]
+    fn test_pause() {
+        let config = Config::from_env();
+        let stats = ConcurrentStats::new();
+        let claimed = Barrier::new(2);
+        thread::scope(|s| {
+            let writer = s.spawn(|| {
+                stats.record(Duration::from_nanos(100));
+                claimed.wait();
+                let slot = stats.slots().next().unwrap();
+                while slot.active.load(Relaxed) == 0 {
+                    thread::yield_now();
+                }
+                for _ in 0..config.iterations {
+                    stats.record(Duration::from_nanos(100));
+                }
+            });
+            claimed.wait();
+            // A record that seems to take forever.
+            let slot = stats.slots().next().unwrap();
+            slot.counters[0].started.fetch_add(1, Relaxed);
+            let reader = s.spawn(|| stats.snapshot());
+            writer.join().unwrap();
+            assert!(!reader.is_finished());
+            slot.counters[0].started.fetch_sub(1, Relaxed);
+            let snapshot = reader.join().unwrap();
+            assert_eq!(snapshot.count(), 1 + config.iterations as u64);
+            assert_eq!(snapshot.sum, snapshot.count * 100);
+        });
+    }
+}
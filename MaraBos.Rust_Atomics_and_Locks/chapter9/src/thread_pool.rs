@@ -0,0 +1,509 @@
+// Thread pool
+//
+// A fixed number of worker threads, each with its own Chase–Lev deque from
+// chapter6. Jobs spawned from a worker go onto its own deque, where it'll
+// pick them up again last-in-first-out, while jobs spawned from anywhere
+// else go onto one shared queue (the injector, a MsQueue from chapter6).
+// A worker that runs out of work takes from the injector, and otherwise
+// steals the oldest job from another worker's deque.
+//
+// Idle workers go to sleep on a Condvar. A worker only does so after
+// registering itself in `sleepers` and checking all the queues once more,
+// while anyone that pushes a job checks `sleepers` afterwards, with a SeqCst
+// fence on both sides: either the worker sees the job, or the pusher sees
+// the sleeper and wakes it up. That way, pushing a job is just a few atomic
+// operations whenever all workers are busy.
+//
+// `scope` (and `join`, on top of it) lets jobs borrow from the stack, by
+// waiting for all of them before returning. A worker that's waiting for a
+// scope runs other jobs in the meantime, so nested scopes don't deadlock.
+//
+// Dropping the pool (or `shutdown`) lets the workers finish all queued jobs,
+// and then joins them.
+//
+// A panic in a `spawn`ed job is caught (after the panic hook printed it), so
+// it doesn't take down a worker. A panic in a `scope` is resumed when the
+// scope ends.
+
+use crate::{futex, Condvar, Mutex};
+use chapter6::chase_lev::{Steal, Stealer, Worker};
+use chapter6::ms_queue::MsQueue;
+use std::any::Any;
+use std::cell::Cell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{
+    fence, AtomicBool, AtomicU32, AtomicUsize,
+    Ordering::{AcqRel, Acquire, Relaxed, Release, SeqCst},
+};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct Shared {
+    injector: MsQueue<Job>,
+    /// One for every worker's deque, in order.
+    stealers: Vec<Stealer<Job>>,
+    /// The number of workers that are going to sleep, or sleeping.
+    sleepers: AtomicUsize,
+    sleep: Mutex<()>,
+    wake: Condvar,
+    shutdown: AtomicBool,
+}
+
+impl Shared {
+    fn push(&self, job: Job) {
+        match WorkerThread::current(self) {
+            Some(worker) => worker.local.push(job),
+            None => self.injector.push(job),
+        }
+        // Pairs with the fence in `WorkerThread::run`.
+        fence(SeqCst);
+        if self.sleepers.load(Relaxed) > 0 {
+            let _guard = self.sleep.lock();
+            self.wake.notify_one();
+        }
+    }
+
+    fn has_work(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|s| !s.is_empty())
+    }
+}
+
+// WorkerThread //
+
+struct WorkerThread {
+    shared: Arc<Shared>,
+    index: usize,
+    local: Worker<Job>,
+}
+
+thread_local! {
+    static CURRENT: Cell<*const WorkerThread> = const { Cell::new(ptr::null()) };
+}
+
+impl WorkerThread {
+    /// The worker we're running on, if it's one of `shared`'s.
+    fn current(shared: &Shared) -> Option<&WorkerThread> {
+        let worker = CURRENT.with(Cell::get);
+        // Safety: It's only set while `run` is running, which is the whole
+        // time anything else runs on a worker thread.
+        let worker = unsafe { worker.as_ref()? };
+        ptr::eq(&*worker.shared, shared).then_some(worker)
+    }
+
+    fn run(self) {
+        CURRENT.with(|c| c.set(&self));
+        let shared = &*self.shared;
+        loop {
+            if let Some(job) = self.find_job() {
+                run_job(job);
+                continue;
+            }
+            let mut guard = shared.sleep.lock();
+            shared.sleepers.fetch_add(1, Relaxed);
+            // Pairs with the fence in `Shared::push`.
+            fence(SeqCst);
+            let stop = shared.shutdown.load(Relaxed);
+            if !stop && !shared.has_work() {
+                guard = shared.wake.wait(guard);
+            }
+            shared.sleepers.fetch_sub(1, Relaxed);
+            drop(guard);
+            // Only stop once everything that was queued is done.
+            if stop && !shared.has_work() {
+                break;
+            }
+        }
+        CURRENT.with(|c| c.set(ptr::null()));
+    }
+
+    fn find_job(&self) -> Option<Job> {
+        if let Some(job) = self.local.pop() {
+            return Some(job);
+        }
+        let stealers = &self.shared.stealers;
+        loop {
+            if let Some(job) = self.shared.injector.pop() {
+                return Some(job);
+            }
+            let mut retry = false;
+            // Start with our neighbour, so not everyone goes after the same worker.
+            for i in 1..stealers.len() {
+                match stealers[(self.index + i) % stealers.len()].steal() {
+                    Steal::Success(job) => return Some(job),
+                    Steal::Retry => retry = true,
+                    Steal::Empty => {}
+                }
+            }
+            if !retry {
+                return None;
+            }
+        }
+    }
+}
+
+fn run_job(job: Job) {
+    // The panic hook already reported it.
+    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+}
+
+// ThreadPool //
+
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    pub fn new(threads: usize) -> Self {
+        assert!(threads > 0, "a thread pool needs at least one thread");
+        let workers: Vec<Worker<Job>> = (0..threads).map(|_| Worker::new()).collect();
+        let shared = Arc::new(Shared {
+            injector: MsQueue::new(),
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            sleepers: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+        let threads = workers
+            .into_iter()
+            .enumerate()
+            .map(|(index, local)| {
+                let worker = WorkerThread {
+                    shared: shared.clone(),
+                    index,
+                    local,
+                };
+                thread::Builder::new()
+                    .name(format!("pool-worker-{index}"))
+                    .spawn(move || worker.run())
+                    .expect("failed to spawn worker thread")
+            })
+            .collect();
+        Self { shared, threads }
+    }
+
+    /// The number of worker threads.
+    pub fn threads(&self) -> usize {
+        self.threads.len()
+    }
+
+    pub fn spawn<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.push(Box::new(f));
+    }
+
+    /// Jobs spawned on the scope can borrow anything that outlives it,
+    /// since this only returns once they've all finished.
+    ///
+    /// `f` itself runs on the current thread.
+    pub fn scope<'scope, F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&Scope<'scope>) -> R,
+    {
+        let scope = Scope {
+            shared: self.shared.clone(),
+            state: Arc::new(ScopeState {
+                pending: AtomicUsize::new(1), // One for `f`.
+                done: AtomicU32::new(0),
+                panic: Mutex::new(None),
+            }),
+            _invariant: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.state.job_done();
+        scope.wait();
+        // The first panic wins, and `f` counts as first.
+        let result = result.unwrap_or_else(|p| panic::resume_unwind(p));
+        if let Some(p) = scope.state.panic.lock().take() {
+            panic::resume_unwind(p);
+        }
+        result
+    }
+
+    /// Runs `a` on the current thread while `b` may run on another,
+    /// and returns both results.
+    pub fn join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+    where
+        A: FnOnce() -> RA,
+        B: FnOnce() -> RB + Send,
+        RB: Send,
+    {
+        let mut result_b = None;
+        let result_a = self.scope(|s| {
+            s.spawn(|_| result_b = Some(b()));
+            a()
+        });
+        (result_a, result_b.unwrap())
+    }
+
+    /// Finishes all queued jobs and stops the workers.
+    /// The same as dropping it, but easier to spot.
+    pub fn shutdown(self) {}
+}
+
+impl Default for ThreadPool {
+    /// One thread per CPU.
+    fn default() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Relaxed);
+        // Lock, to make sure no worker is in between
+        // checking `shutdown` and going to sleep.
+        drop(self.shared.sleep.lock());
+        self.shared.wake.notify_all();
+        for t in self.threads.drain(..) {
+            // If a job dropped the pool, that worker can't wait for itself.
+            // It'll stop after that job.
+            if t.thread().id() != thread::current().id() {
+                let _ = t.join();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ThreadPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ThreadPool")
+            .field("threads", &self.threads.len())
+            .finish_non_exhaustive()
+    }
+}
+
+// Scope //
+
+/// Kept in an Arc, because the last job still wakes up the
+/// scope's thread after that might have returned already.
+struct ScopeState {
+    /// The number of unfinished jobs, plus one until the scope's own closure is done.
+    pending: AtomicUsize,
+    /// Set to one when `pending` reaches zero.
+    done: AtomicU32,
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl ScopeState {
+    fn job_done(&self) {
+        if self.pending.fetch_sub(1, AcqRel) == 1 {
+            self.done.store(1, Release);
+            atomic_wait::wake_all(&self.done);
+        }
+    }
+}
+
+pub struct Scope<'scope> {
+    shared: Arc<Shared>,
+    state: Arc<ScopeState>,
+    _invariant: PhantomData<fn(&'scope ()) -> &'scope ()>,
+}
+
+/// To send a `&Scope` to the jobs.
+struct ScopePtr<'scope>(*const Scope<'scope>);
+
+unsafe impl Send for ScopePtr<'_> {}
+
+impl<'scope> ScopePtr<'scope> {
+    fn get(&self) -> *const Scope<'scope> {
+        self.0
+    }
+}
+
+impl<'scope> Scope<'scope> {
+    pub fn spawn<F>(&self, f: F)
+    where
+        F: FnOnce(&Scope<'scope>) + Send + 'scope,
+    {
+        self.state.pending.fetch_add(1, Relaxed);
+        let scope = ScopePtr(self);
+        let state = self.state.clone();
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            // Safety: The scope waits for this job before it goes away.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f(unsafe { &*scope.get() })));
+            if let Err(p) = result {
+                state.panic.lock().get_or_insert(p);
+            }
+            state.job_done();
+        });
+        // Safety: Same here, so nothing it borrows goes away before it's done.
+        let job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        self.shared.push(job);
+    }
+
+    fn wait(&self) {
+        let done = &self.state.done;
+        match WorkerThread::current(&self.shared) {
+            // Help out, rather than blocking a worker.
+            Some(worker) => {
+                while done.load(Acquire) == 0 {
+                    match worker.find_job() {
+                        Some(job) => run_job(job),
+                        // Nothing to do, but new jobs don't wake us up,
+                        // so don't sleep for long.
+                        None => _ = futex::wait_timeout(done, 0, Duration::from_millis(1)),
+                    }
+                }
+            }
+            None => {
+                while done.load(Acquire) == 0 {
+                    atomic_wait::wait(done, 0);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Scope<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Scope").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use stress::Config;
+
+    #[test]
+    fn test_spawn_and_shutdown() {
+        let pool = ThreadPool::new(4);
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..1000 {
+            let counter = counter.clone();
+            pool.spawn(move || {
+                counter.fetch_add(1, Relaxed);
+            });
+        }
+        // All queued jobs still run.
+        pool.shutdown();
+        assert_eq!(counter.load(Relaxed), 1000);
+    }
+
+    #[test]
+    fn test_spawn_from_job() {
+        let pool = Arc::new(ThreadPool::new(2));
+        let (send, recv) = mpsc::channel();
+        let p = pool.clone();
+        pool.spawn(move || {
+            for i in 0..100 {
+                let send = send.clone();
+                p.spawn(move || send.send(i).unwrap());
+            }
+        });
+        let mut received: Vec<i32> = recv.iter().take(100).collect();
+        received.sort();
+        assert_eq!(received, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_scope() {
+        let pool = ThreadPool::new(3);
+        let mut numbers: Vec<u64> = (0..1000).collect();
+        let total = AtomicUsize::new(0);
+        pool.scope(|s| {
+            for chunk in numbers.chunks_mut(100) {
+                s.spawn(|s| {
+                    for n in chunk.iter_mut() {
+                        *n *= 2;
+                    }
+                    // Nested, on the same scope.
+                    s.spawn(|_| _ = total.fetch_add(1, Relaxed));
+                });
+            }
+        });
+        assert_eq!(total.into_inner(), 10);
+        assert_eq!(numbers.iter().sum::<u64>(), 999 * 1000);
+    }
+
+    #[test]
+    fn test_join() {
+        fn fib(pool: &ThreadPool, n: u64) -> u64 {
+            if n < 2 {
+                return n;
+            }
+            let (a, b) = pool.join(|| fib(pool, n - 1), || fib(pool, n - 2));
+            a + b
+        }
+        let pool = ThreadPool::new(4);
+        assert_eq!(fib(&pool, 20), 6765);
+        // Also from inside the pool, where it waits by running other jobs.
+        let (a, b) = pool.join(|| fib(&pool, 15), || fib(&pool, 16));
+        assert_eq!((a, b), (610, 987));
+    }
+
+    #[test]
+    fn test_panic() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|_| panic!("boom"));
+                for _ in 0..10 {
+                    s.spawn(|_| _ = finished.fetch_add(1, Relaxed));
+                }
+            })
+        }));
+        let message = *result.unwrap_err().downcast::<&str>().unwrap();
+        assert_eq!(message, "boom");
+        // The scope still waited for everything else.
+        assert_eq!(finished.load(Relaxed), 10);
+        // A panicking job doesn't take down its worker.
+        pool.spawn(|| panic!("boom"));
+        pool.spawn(|| panic!("boom"));
+        assert_eq!(pool.join(|| 1, || 2), (1, 2));
+    }
+
+    /// Many threads using one pool at the same time: spawning jobs,
+    /// and (nested) scopes and joins.
+    #[test]
+    fn test_stress() {
+        let config = Config::from_env();
+        let pool = ThreadPool::new(config.threads);
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let iterations = config.iterations / 10;
+        let expected: usize = stress::run(&config.iterations(iterations), |w| {
+            let mut expected = 0;
+            for _ in 0..w.iterations {
+                match w.rng.below(3) {
+                    0 => {
+                        let spawned = spawned.clone();
+                        pool.spawn(move || _ = spawned.fetch_add(1, Relaxed));
+                        expected += 1;
+                    }
+                    1 => {
+                        let n = w.rng.below(8);
+                        let count = AtomicUsize::new(0);
+                        pool.scope(|s| {
+                            for _ in 0..n {
+                                s.spawn(|_| {
+                                    let (a, b) = pool.join(|| 1, || 2);
+                                    count.fetch_add(a + b, Relaxed);
+                                });
+                            }
+                        });
+                        assert_eq!(count.into_inner(), 3 * n);
+                    }
+                    _ => assert_eq!(pool.join(|| 1, || 2), (1, 2)),
+                }
+            }
+            expected
+        })
+        .into_iter()
+        .sum();
+        drop(pool);
+        assert_eq!(spawned.load(Relaxed), expected);
+    }
+}
@@ -0,0 +1,82 @@
+// How the locks wait.
+//
+// The locks in this library only ever need three operations to block:
+// wait while an AtomicU32 has some value, and wake one or all of those
+// waiters. Normally, that's a futex (through `atomic_wait`), but there's
+// no futex on a microcontroller or in a kernel, so the locks are generic
+// over a `WaitStrategy`, which is one of the ones below, or your own.
+//
+// A strategy is only a type, never a value, so it takes no space in the locks.
+
+use core::sync::atomic::{AtomicU32, Ordering::Relaxed};
+
+/// Implement this to let the locks wait in some other way,
+/// e.g. through the scheduler of a real-time operating system,
+/// or with a `wfe`/`sev` pair of instructions on ARM.
+pub trait WaitStrategy {
+    /// If the value is `expected`, wait until woken up.
+    ///
+    /// Just like `atomic_wait::wait`, this may return spuriously,
+    /// since all callers check their condition again anyway.
+    /// It may even return right away, which is just spinning.
+    fn wait(atomic: &AtomicU32, expected: u32);
+
+    /// Wake up one thread waiting on `atomic`, if any.
+    fn wake_one(atomic: &AtomicU32);
+
+    /// Wake up all threads waiting on `atomic`.
+    fn wake_all(atomic: &AtomicU32);
+}
+
+/// Never sleeps: waiting threads spin (see chapter4's `Backoff`) until the
+/// value changes, so waking up is free.
+///
+/// Only a good idea if the locks are held for a very short time,
+/// or if there's nothing else to do anyway.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Spin;
+
+impl WaitStrategy for Spin {
+    fn wait(atomic: &AtomicU32, expected: u32) {
+        let backoff = chapter4::Backoff::DEFAULT;
+        let mut step = 0;
+        while atomic.load(Relaxed) == expected {
+            backoff.snooze(&mut step);
+        }
+    }
+
+    fn wake_one(_: &AtomicU32) {}
+
+    fn wake_all(_: &AtomicU32) {}
+}
+
+/// Futex wait and wake, through `atomic_wait`. The default, if available.
+#[cfg(feature = "futex")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Futex;
+
+#[cfg(feature = "futex")]
+impl WaitStrategy for Futex {
+    #[inline]
+    fn wait(atomic: &AtomicU32, expected: u32) {
+        atomic_wait::wait(atomic, expected);
+    }
+
+    #[inline]
+    fn wake_one(atomic: &AtomicU32) {
+        atomic_wait::wake_one(atomic);
+    }
+
+    #[inline]
+    fn wake_all(atomic: &AtomicU32) {
+        atomic_wait::wake_all(atomic);
+    }
+}
+
+/// What the locks use if you don't say otherwise.
+#[cfg(feature = "futex")]
+pub type DefaultWait = Futex;
+
+/// What the locks use if you don't say otherwise.
+#[cfg(not(feature = "futex"))]
+pub type DefaultWait = Spin;
@@ -0,0 +1,376 @@
+// Stress-test harness
+//
+// A small, dependency-free harness used by the chapter crates to hammer
+// their locks, channels and Arcs from many threads and check invariants
+// afterwards, instead of `static mut` counters in `main()`.
+//
+// Every thread gets its own deterministic random number generator, derived
+// from the configured seed and the thread index, so a failing run can be
+// replayed with the same sequence of operations per thread.
+// (The interleaving of the threads is of course still up to the OS.)
+//
+// Nothing here stands in the way of running it under Miri or ThreadSanitizer
+// (see README.md), but this is only tested natively.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+// Config //
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Number of threads spawned by `run`.
+    pub threads: usize,
+    /// Number of operations every thread performs.
+    pub iterations: usize,
+    /// Seed for the per-thread random number generators.
+    pub seed: u64,
+}
+
+impl Config {
+    /// Default configuration, overridable with the
+    /// `STRESS_THREADS`, `STRESS_ITERATIONS` and `STRESS_SEED` environment variables.
+    ///
+    /// Miri is a few orders of magnitude slower than native code,
+    /// so the default number of iterations is much lower there.
+    pub fn from_env() -> Self {
+        let iterations = if cfg!(miri) { 100 } else { 10_000 };
+        Self {
+            threads: env_or("STRESS_THREADS", 4),
+            iterations: env_or("STRESS_ITERATIONS", iterations),
+            seed: env_or("STRESS_SEED", 0x5eed),
+        }
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn iterations(mut self, iterations: usize) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::from_env()
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(v) => v
+            .parse()
+            .unwrap_or_else(|_| panic!("{name}: can't parse {v:?}")),
+        Err(_) => default,
+    }
+}
+
+// Rng //
+
+/// SplitMix64. Not cryptographic, but fast, tiny and good enough
+/// to pick operations.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub const fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        assert!(n > 0);
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+// Mix //
+
+/// A weighted mix of operations, e.g. 90% reads and 10% writes:
+///
+/// ```
+/// # #[derive(Clone, Copy)] enum Op { Read, Write }
+/// let mix = stress::Mix::new(&[(Op::Read, 9), (Op::Write, 1)]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Mix<Op> {
+    ops: Vec<(Op, u32)>,
+    total: u32,
+}
+
+impl<Op: Copy> Mix<Op> {
+    pub fn new(ops: &[(Op, u32)]) -> Self {
+        let total = ops.iter().map(|&(_, w)| w).sum();
+        assert!(total > 0, "empty mix");
+        Self {
+            ops: ops.to_vec(),
+            total,
+        }
+    }
+
+    pub fn pick(&self, rng: &mut Rng) -> Op {
+        let mut n = rng.below(self.total as usize) as u32;
+        for &(op, weight) in &self.ops {
+            if n < weight {
+                return op;
+            }
+            n -= weight;
+        }
+        unreachable!()
+    }
+}
+
+// run //
+
+/// The per-thread state handed to the closure given to `run`.
+pub struct Worker {
+    /// 0..config.threads
+    pub index: usize,
+    pub iterations: usize,
+    pub rng: Rng,
+}
+
+/// Runs `f` on `config.threads` threads at the same time,
+/// and returns what every thread returned, in thread order.
+///
+/// The threads return their own view of what they did
+/// (e.g. the number of increments), so the caller can
+/// check that against the final state.
+pub fn run<F, R>(config: &Config, f: F) -> Vec<R>
+where
+    F: Fn(&mut Worker) -> R + Sync,
+    R: Send,
+{
+    // Printed on stderr, which `cargo test` only shows if the test fails.
+    eprintln!("stress: {config:?}");
+
+    let mut seeder = Rng::new(config.seed);
+    let workers: Vec<Worker> = (0..config.threads)
+        .map(|index| Worker {
+            index,
+            iterations: config.iterations,
+            rng: Rng::new(seeder.next_u64()),
+        })
+        .collect();
+
+    let f = &f;
+    thread::scope(|s| {
+        let handles: Vec<_> = workers
+            .into_iter()
+            .map(|mut w| s.spawn(move || f(&mut w)))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
+
+// Stamp //
+
+/// A value that's bigger than any atomic, to detect torn reads.
+///
+/// Writers only ever store stamps with all words equal,
+/// so a reader seeing different words saw a half-written value.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Stamp([u64; 4]);
+
+impl Stamp {
+    pub const fn new(value: u64) -> Self {
+        Self([value; 4])
+    }
+
+    pub fn value(&self) -> u64 {
+        assert!(self.is_intact(), "torn read: {self:?}");
+        self.0[0]
+    }
+
+    pub fn next(&self) -> Self {
+        Self::new(self.value() + 1)
+    }
+
+    pub fn is_intact(&self) -> bool {
+        self.0.iter().all(|&w| w == self.0[0])
+    }
+}
+
+impl fmt::Debug for Stamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Stamp{:?}", self.0)
+    }
+}
+
+// DropCounter //
+
+/// Counts how many `Tracked` values are created and dropped,
+/// to check that nothing is leaked or dropped twice.
+pub struct DropCounter {
+    created: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl DropCounter {
+    pub const fn new() -> Self {
+        Self {
+            created: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn track(&self) -> Tracked<'_> {
+        self.created.fetch_add(1, Relaxed);
+        Tracked { counter: self }
+    }
+
+    pub fn created(&self) -> usize {
+        self.created.load(Relaxed)
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Relaxed)
+    }
+
+    /// Number of `Tracked` values that still exist.
+    pub fn alive(&self) -> usize {
+        self.created() - self.dropped()
+    }
+}
+
+impl Default for DropCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct Tracked<'a> {
+    counter: &'a DropCounter,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        let dropped = self.counter.dropped.fetch_add(1, Relaxed) + 1;
+        assert!(dropped <= self.counter.created(), "dropped twice");
+    }
+}
+
+impl fmt::Debug for DropCounter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DropCounter")
+            .field("created", &self.created())
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+// block_on //
+
+/// Runs a future to completion on the current thread,
+/// parking the thread whenever the future is pending.
+///
+/// The simplest possible executor, to test futures without an async runtime.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            // Spurious wake ups just result in an extra poll.
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deterministic() {
+        let config = Config::from_env().threads(3).iterations(100).seed(42);
+        let f = |w: &mut Worker| (0..w.iterations).fold(0, |x, _| x ^ w.rng.next_u64());
+        let run1 = run(&config, f);
+        let run2 = run(&config, f);
+        assert_eq!(run1, run2);
+        assert_ne!(run1[0], run1[1]);
+    }
+
+    #[test]
+    fn test_mix() {
+        let mix = Mix::new(&[(0, 3), (1, 0), (2, 1)]);
+        let mut rng = Rng::new(1);
+        let mut counts = [0; 3];
+        for _ in 0..4000 {
+            counts[mix.pick(&mut rng)] += 1;
+        }
+        assert_eq!(counts[1], 0);
+        assert!(counts[0] > 2 * counts[2]);
+    }
+
+    #[test]
+    fn test_stamp() {
+        let s = Stamp::new(7);
+        assert!(s.is_intact());
+        assert_eq!(s.next().value(), 8);
+        assert!(!Stamp([1, 1, 2, 1]).is_intact());
+    }
+
+    #[test]
+    fn test_drop_counter() {
+        let counter = DropCounter::new();
+        let a = counter.track();
+        let b = counter.track();
+        drop(a);
+        assert_eq!(counter.alive(), 1);
+        drop(b);
+        assert_eq!(counter.created(), 2);
+        assert_eq!(counter.dropped(), 2);
+    }
+
+    #[test]
+    fn test_block_on() {
+        assert_eq!(block_on(async { 1 + 2 }), 3);
+
+        // A future that's pending once, and wakes itself from another thread.
+        let mut woken = false;
+        let output = block_on(std::future::poll_fn(|cx| {
+            if woken {
+                return Poll::Ready("done");
+            }
+            woken = true;
+            let waker = cx.waker().clone();
+            thread::spawn(move || waker.wake());
+            Poll::Pending
+        }));
+        assert_eq!(output, "done");
+    }
+}